@@ -0,0 +1,602 @@
+use serde::{Deserialize, Serialize};
+
+use crate::common::*;
+
+use super::{sound_wave::Pulse, PULSE_TABLE};
+
+/// MMC5 clocks envelopes and length counters at a fixed 240Hz.
+const MMC5_FRAME_PERIOD: u32 = 7457;
+
+/**
+ * MMC5 sound: two pulse channels like the APU ones but without sweep, and an
+ * 8-bit PCM channel. The PCM level is written to $5011 or, in read mode,
+ * taken from CPU reads of $8000-$BFFF. A sample of 0 raises the PCM IRQ.
+ *
+ * Reference https://www.nesdev.org/wiki/MMC5_audio
+ */
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Mmc5Audio {
+  cycle: u32,
+  pulse1: Pulse,
+  pulse2: Pulse,
+  pcm_read_mode: bool,
+  #[serde(default)]
+  pcm_irq_enabled: bool,
+  #[serde(default)]
+  pcm_irq: bool,
+  pcm: Byte,
+}
+
+impl Mmc5Audio {
+  pub fn new() -> Self {
+    Self {
+      cycle: 0,
+      pulse1: Pulse::new(1),
+      pulse2: Pulse::new(2),
+      pcm_read_mode: false,
+      pcm_irq_enabled: false,
+      pcm_irq: false,
+      pcm: 0,
+    }
+  }
+
+  /// Clock once per CPU cycle.
+  pub fn step(&mut self) {
+    self.cycle += 1;
+    if self.cycle.is_multiple_of(2) {
+      self.pulse1.step_timer();
+      self.pulse2.step_timer();
+    }
+    if self.cycle >= MMC5_FRAME_PERIOD {
+      self.cycle = 0;
+      self.pulse1.step_envelope();
+      self.pulse2.step_envelope();
+      self.pulse1.step_length();
+      self.pulse2.step_length();
+    }
+  }
+
+  /// Level on the same scale as the APU mixer.
+  pub fn output(&self) -> f32 {
+    let pulse = PULSE_TABLE[(self.pulse1.output() + self.pulse2.output()) as usize];
+    pulse + self.pcm as f32 / 255.0 * 0.4
+  }
+
+  pub fn write(&mut self, addr: Address, value: Byte) {
+    match addr {
+      0x5000 => self.pulse1.write_control(value),
+      0x5002 => self.pulse1.write_timer_low(value),
+      0x5003 => self.pulse1.write_timer_high(value),
+      0x5004 => self.pulse2.write_control(value),
+      0x5006 => self.pulse2.write_timer_low(value),
+      0x5007 => self.pulse2.write_timer_high(value),
+      0x5010 => {
+        self.pcm_read_mode = bit_eq(value, 0x01);
+        self.pcm_irq_enabled = bit_eq(value, 0x80);
+      }
+      0x5011 if !self.pcm_read_mode => self.take_sample(value),
+      0x5015 => {
+        self.pulse1.set_enabled(bit_eq(value, 1));
+        self.pulse2.set_enabled(bit_eq(value, 2));
+      }
+      _ => {}
+    }
+  }
+
+  pub fn read_status(&self) -> Byte {
+    (self.pulse1.length_value() > 0) as Byte | ((self.pulse2.length_value() > 0) as Byte) << 1
+  }
+
+  /// $5010, reading acknowledges the PCM IRQ.
+  pub fn read_pcm_status(&mut self) -> Byte {
+    let status = ((self.pcm_irq && self.pcm_irq_enabled) as Byte) << 7;
+    self.pcm_irq = false;
+    status
+  }
+
+  /// CPU read of $8000-$BFFF, the value is the next sample in read mode.
+  pub fn read_sample(&mut self, value: Byte) {
+    if self.pcm_read_mode {
+      self.take_sample(value);
+    }
+  }
+
+  pub fn irq_pending(&self) -> bool {
+    self.pcm_irq && self.pcm_irq_enabled
+  }
+
+  // 0 is not played but raises the IRQ, games use it as "no sample"
+  fn take_sample(&mut self, value: Byte) {
+    if value == 0 {
+      self.pcm_irq = true;
+    } else {
+      self.pcm = value;
+    }
+  }
+}
+
+/**
+ * VRC6 pulse channel: 16 step duty cycle with 8 duty settings and a 4-bit
+ * volume, no envelope or length counter.
+ */
+#[derive(Serialize, Deserialize)]
+struct Vrc6Pulse {
+  enabled: bool,
+  ignore_duty: bool,
+  duty: Byte,
+  volume: Byte,
+  period: Address,
+  timer: Address,
+  step: Byte,
+}
+
+impl Vrc6Pulse {
+  fn new() -> Self {
+    Self {
+      enabled: false,
+      ignore_duty: false,
+      duty: 0,
+      volume: 0,
+      period: 0,
+      timer: 0,
+      step: 0,
+    }
+  }
+
+  fn write(&mut self, reg: Address, value: Byte) {
+    match reg {
+      0 => {
+        self.ignore_duty = bit_eq(value, 0x80);
+        self.duty = (value >> 4) & 0x07;
+        self.volume = value & 0x0F;
+      }
+      1 => self.period = (self.period & 0x0F00) | value as Address,
+      _ => {
+        self.period = (self.period & 0x00FF) | ((value as Address & 0x0F) << 8);
+        self.enabled = bit_eq(value, 0x80);
+        if !self.enabled {
+          self.step = 0;
+        }
+      }
+    }
+  }
+
+  fn step_timer(&mut self, shift: u8) {
+    if !self.enabled {
+      return;
+    }
+    if self.timer == 0 {
+      self.timer = self.period >> shift;
+      self.step = (self.step + 1) & 0x0F;
+    } else {
+      self.timer -= 1;
+    }
+  }
+
+  fn output(&self) -> Byte {
+    if self.enabled && (self.ignore_duty || self.step <= self.duty) {
+      self.volume
+    } else {
+      0
+    }
+  }
+}
+
+/// VRC6 sawtooth channel, adds the rate to an accumulator every other step
+/// and resets it after seven additions.
+#[derive(Serialize, Deserialize)]
+struct Vrc6Saw {
+  enabled: bool,
+  rate: Byte,
+  period: Address,
+  timer: Address,
+  step: Byte,
+  accumulator: Byte,
+}
+
+impl Vrc6Saw {
+  fn new() -> Self {
+    Self {
+      enabled: false,
+      rate: 0,
+      period: 0,
+      timer: 0,
+      step: 0,
+      accumulator: 0,
+    }
+  }
+
+  fn write(&mut self, reg: Address, value: Byte) {
+    match reg {
+      0 => self.rate = value & 0x3F,
+      1 => self.period = (self.period & 0x0F00) | value as Address,
+      _ => {
+        self.period = (self.period & 0x00FF) | ((value as Address & 0x0F) << 8);
+        self.enabled = bit_eq(value, 0x80);
+        if !self.enabled {
+          self.step = 0;
+          self.accumulator = 0;
+        }
+      }
+    }
+  }
+
+  fn step_timer(&mut self, shift: u8) {
+    if !self.enabled {
+      return;
+    }
+    if self.timer > 0 {
+      self.timer -= 1;
+      return;
+    }
+    self.timer = self.period >> shift;
+    self.step += 1;
+    if self.step == 14 {
+      self.step = 0;
+      self.accumulator = 0;
+    } else if self.step.is_multiple_of(2) {
+      self.accumulator = self.accumulator.wrapping_add(self.rate);
+    }
+  }
+
+  fn output(&self) -> Byte {
+    if self.enabled {
+      self.accumulator >> 3
+    } else {
+      0
+    }
+  }
+}
+
+/**
+ * Konami VRC6 sound: two pulse channels and a sawtooth.
+ *
+ * Reference https://www.nesdev.org/wiki/VRC6_audio
+ */
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Vrc6Audio {
+  pulse1: Vrc6Pulse,
+  pulse2: Vrc6Pulse,
+  saw: Vrc6Saw,
+  halt: bool,
+  // $9003 speeds all channels up by 16 or 256
+  shift: u8,
+}
+
+impl Vrc6Audio {
+  pub fn new() -> Self {
+    Self {
+      pulse1: Vrc6Pulse::new(),
+      pulse2: Vrc6Pulse::new(),
+      saw: Vrc6Saw::new(),
+      halt: false,
+      shift: 0,
+    }
+  }
+
+  /// Clock once per CPU cycle.
+  pub fn step(&mut self) {
+    if self.halt {
+      return;
+    }
+    self.pulse1.step_timer(self.shift);
+    self.pulse2.step_timer(self.shift);
+    self.saw.step_timer(self.shift);
+  }
+
+  /// Level on the same scale as the APU mixer, a pulse at full volume is as
+  /// loud as an APU pulse at full volume.
+  pub fn output(&self) -> f32 {
+    let sum = self.pulse1.output() + self.pulse2.output() + self.saw.output();
+    sum as f32 * PULSE_TABLE[15] / 15.0
+  }
+
+  /// Write a sound register, `addr` with the VRC6b address lines already
+  /// swapped back.
+  pub fn write(&mut self, addr: Address, value: Byte) {
+    let reg = addr & 0x03;
+    match addr & 0xF000 {
+      0x9000 if reg == 3 => {
+        self.halt = bit_eq(value, 0x01);
+        self.shift = if bit_eq(value, 0x04) {
+          8
+        } else if bit_eq(value, 0x02) {
+          4
+        } else {
+          0
+        };
+      }
+      0x9000 => self.pulse1.write(reg, value),
+      // $A003 is unused and $B003 is the banking mode of the mapper
+      _ if reg == 3 => {}
+      0xA000 => self.pulse2.write(reg, value),
+      0xB000 => self.saw.write(reg, value),
+      _ => {}
+    }
+  }
+}
+
+/// Namco 163 updates one channel every 15 CPU cycles.
+const NAMCO163_CHANNEL_PERIOD: u32 = 15;
+
+/**
+ * Namco 163 sound: up to 8 wavetable channels whose registers and 4-bit
+ * samples share 128 bytes of internal RAM, channel 7 at $78-$7F down to
+ * channel 0 at $40-$47.
+ *
+ * The chip updates and outputs one channel at a time, the mix is the average
+ * of the enabled channels, which is what the multiplexed output settles to.
+ *
+ * Reference https://www.nesdev.org/wiki/Namco_163_audio
+ */
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Namco163Audio {
+  #[serde(with = "serde_bytes")]
+  ram: Vec<Byte>,
+  address: Byte,
+  auto_increment: bool,
+  disabled: bool,
+  cycle: u32,
+  channel: usize,
+  outputs: [i16; 8],
+}
+
+impl Namco163Audio {
+  pub fn new() -> Self {
+    Self {
+      ram: vec![0; 0x80],
+      address: 0,
+      auto_increment: false,
+      disabled: false,
+      cycle: 0,
+      channel: 7,
+      outputs: [0; 8],
+    }
+  }
+
+  /// $F800, address of the data port, bit 7 increments it after each access.
+  pub fn write_address(&mut self, value: Byte) {
+    self.address = value & 0x7F;
+    self.auto_increment = bit_eq(value, 0x80);
+  }
+
+  /// $4800 write.
+  pub fn write_data(&mut self, value: Byte) {
+    self.ram[self.address as usize] = value;
+    self.next_address();
+  }
+
+  /// $4800 read.
+  pub fn read_data(&mut self) -> Byte {
+    let value = self.ram[self.address as usize];
+    self.next_address();
+    value
+  }
+
+  fn next_address(&mut self) {
+    if self.auto_increment {
+      self.address = (self.address + 1) & 0x7F;
+    }
+  }
+
+  /// Bit 6 of $E000 silences the chip.
+  pub fn set_disabled(&mut self, disabled: bool) {
+    self.disabled = disabled;
+  }
+
+  fn channel_count(&self) -> usize {
+    ((self.ram[0x7F] >> 4) & 0x07) as usize + 1
+  }
+
+  /// Clock once per CPU cycle.
+  pub fn step(&mut self) {
+    if self.disabled {
+      return;
+    }
+    self.cycle += 1;
+    if self.cycle < NAMCO163_CHANNEL_PERIOD {
+      return;
+    }
+    self.cycle = 0;
+    self.update_channel(self.channel);
+    self.channel = if self.channel <= 8 - self.channel_count() {
+      7
+    } else {
+      self.channel - 1
+    };
+  }
+
+  fn update_channel(&mut self, channel: usize) {
+    let base = 0x40 + channel * 8;
+    let reg = &mut self.ram[base..base + 8];
+    let frequency = reg[0] as u32 | (reg[2] as u32) << 8 | ((reg[4] & 0x03) as u32) << 16;
+    let length = (256 - (reg[4] & 0xFC) as u32) << 16;
+    let mut phase = reg[1] as u32 | (reg[3] as u32) << 8 | (reg[5] as u32) << 16;
+    phase = (phase + frequency) % length;
+    reg[1] = phase as Byte;
+    reg[3] = (phase >> 8) as Byte;
+    reg[5] = (phase >> 16) as Byte;
+
+    let sample_address = (reg[6] as u32 + (phase >> 16)) & 0xFF;
+    let volume = (reg[7] & 0x0F) as i16;
+    let sample = (self.ram[(sample_address >> 1) as usize] >> ((sample_address & 1) * 4)) & 0x0F;
+    self.outputs[channel] = (sample as i16 - 8) * volume;
+  }
+
+  /// Level on the same scale as the APU mixer, a single channel is about
+  /// twice as loud as an APU pulse like on the chip.
+  pub fn output(&self) -> f32 {
+    if self.disabled {
+      return 0.0;
+    }
+    let count = self.channel_count();
+    let sum: i16 = self.outputs[8 - count..].iter().sum();
+    sum as f32 / count as f32 * PULSE_TABLE[15] / 60.0
+  }
+}
+
+/// The Sunsoft 5B counts its tone, noise and envelope periods in units of
+/// 16 CPU cycles.
+const SUNSOFT5B_DIVIDER: u32 = 16;
+
+/**
+ * Sunsoft 5B sound, a YM2149F (AY-3-8910 family): three square channels, a
+ * shared noise generator and a 32 step envelope, with logarithmic volumes of
+ * 3dB per step.
+ *
+ * Reference https://www.nesdev.org/wiki/Sunsoft_5B_audio
+ */
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Sunsoft5bAudio {
+  address: Byte,
+  regs: [Byte; 16],
+  divider: u32,
+
+  tone_timers: [Address; 3],
+  tone_high: [bool; 3],
+
+  noise_timer: Byte,
+  // 17-bit LFSR
+  noise_shift: u32,
+
+  envelope_timer: Address,
+  envelope_step: Byte,
+  envelope_attack: bool,
+  envelope_holding: bool,
+}
+
+impl Sunsoft5bAudio {
+  pub fn new() -> Self {
+    Self {
+      address: 0,
+      regs: [0; 16],
+      divider: 0,
+      tone_timers: [0; 3],
+      tone_high: [false; 3],
+      noise_timer: 0,
+      noise_shift: 1,
+      envelope_timer: 0,
+      envelope_step: 0,
+      envelope_attack: false,
+      envelope_holding: false,
+    }
+  }
+
+  /// $C000, register of the next $E000 write.
+  pub fn write_address(&mut self, value: Byte) {
+    self.address = value & 0x0F;
+  }
+
+  /// $E000.
+  pub fn write_data(&mut self, value: Byte) {
+    self.regs[self.address as usize] = value;
+    if self.address == 0x0D {
+      // restart the envelope with the new shape
+      self.envelope_step = 0;
+      self.envelope_timer = 0;
+      self.envelope_attack = bit_eq(value, 0x04);
+      self.envelope_holding = false;
+    }
+  }
+
+  fn tone_period(&self, channel: usize) -> Address {
+    self.regs[channel * 2] as Address | ((self.regs[channel * 2 + 1] & 0x0F) as Address) << 8
+  }
+
+  /// Clock once per CPU cycle.
+  pub fn step(&mut self) {
+    self.divider += 1;
+    if self.divider < SUNSOFT5B_DIVIDER {
+      return;
+    }
+    self.divider = 0;
+
+    for channel in 0..3 {
+      self.tone_timers[channel] += 1;
+      if self.tone_timers[channel] >= self.tone_period(channel) {
+        self.tone_timers[channel] = 0;
+        self.tone_high[channel] = !self.tone_high[channel];
+      }
+    }
+
+    self.noise_timer += 1;
+    // the noise runs at half the rate of a tone of the same period
+    if self.noise_timer >= (self.regs[6] & 0x1F) * 2 {
+      self.noise_timer = 0;
+      let feedback = (self.noise_shift ^ (self.noise_shift >> 3)) & 1;
+      self.noise_shift = (self.noise_shift >> 1) | feedback << 16;
+    }
+
+    let envelope_period = self.regs[0x0B] as Address | (self.regs[0x0C] as Address) << 8;
+    self.envelope_timer += 1;
+    if self.envelope_timer >= envelope_period {
+      self.envelope_timer = 0;
+      self.step_envelope();
+    }
+  }
+
+  fn step_envelope(&mut self) {
+    if self.envelope_holding {
+      return;
+    }
+    if self.envelope_step < 31 {
+      self.envelope_step += 1;
+      return;
+    }
+    let shape = self.regs[0x0D];
+    let (continue_, alternate, hold) =
+      (bit_eq(shape, 0x08), bit_eq(shape, 0x02), bit_eq(shape, 0x01));
+    if !continue_ {
+      self.envelope_holding = true;
+      self.envelope_attack = false;
+    } else if hold {
+      self.envelope_holding = true;
+      if alternate {
+        self.envelope_attack = !self.envelope_attack;
+      }
+    } else {
+      if alternate {
+        self.envelope_attack = !self.envelope_attack;
+      }
+      self.envelope_step = 0;
+    }
+  }
+
+  /// 5-bit level of the envelope.
+  fn envelope_level(&self) -> Byte {
+    if self.envelope_attack {
+      self.envelope_step
+    } else {
+      31 - self.envelope_step
+    }
+  }
+
+  fn channel_output(&self, channel: usize) -> f32 {
+    let mixer = self.regs[7];
+    let tone = self.tone_high[channel] || bit_eq(mixer, 1 << channel);
+    let noise = self.noise_shift & 1 == 1 || bit_eq(mixer, 8 << channel);
+    if !tone || !noise {
+      return 0.0;
+    }
+    let volume = self.regs[8 + channel];
+    let level = if bit_eq(volume, 0x10) {
+      self.envelope_level()
+    } else if volume & 0x0F == 0 {
+      0
+    } else {
+      (volume & 0x0F) * 2 + 1
+    };
+    if level == 0 {
+      0.0
+    } else {
+      // 1.5dB per envelope step
+      10f32.powf((level as f32 - 31.0) * 1.5 / 20.0)
+    }
+  }
+
+  /// Level on the same scale as the APU mixer, a channel at full volume is as
+  /// loud as an APU pulse at full volume.
+  pub fn output(&self) -> f32 {
+    (0..3).map(|channel| self.channel_output(channel)).sum::<f32>() * PULSE_TABLE[15]
+  }
+}
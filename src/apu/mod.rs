@@ -1,3 +1,5 @@
+mod expansion;
+mod opll;
 mod sound_filter;
 mod sound_wave;
 
@@ -5,7 +7,9 @@ mod player;
 
 #[allow(unused_imports)]
 use std::{
+  cell::RefCell,
   fs::File,
+  rc::Rc,
   io::{BufWriter, Write},
   sync::mpsc,
 };
@@ -18,10 +22,14 @@ use crate::{
     main_bus::{IORegister, RegisterHandler, APU_ADDR, JOY2},
     message_bus::Message,
   },
-  common::*,
+  common::{region::Region, *},
+  mapper::Mapper,
   NesResult, cpu::InterruptType,
 };
 
+pub(crate) use expansion::{Mmc5Audio, Namco163Audio, Sunsoft5bAudio, Vrc6Audio};
+pub(crate) use opll::Opll;
+
 use self::{
   player::Player,
   sound_filter::{Filter, SoundFilter, SoundFilterChain},
@@ -56,7 +64,11 @@ pub struct Apu {
 
   #[serde(skip)]
   player: Box<dyn Player>,
+  #[serde(skip)]
+  muted: bool,
   sample_rate: f64,
+  #[serde(default)]
+  region: Region,
 
   pulse1: Pulse,
   pulse2: Pulse,
@@ -66,6 +78,9 @@ pub struct Apu {
 
   filter_chain: SoundFilterChain,
 
+  // cartridge with its own sound chip
+  #[serde(skip)]
+  expansion: Option<Rc<RefCell<dyn Mapper>>>,
   #[serde(skip)]
   message_sx: Option<mpsc::Sender<Message>>,
   #[cfg(feature = "debug_audio")]
@@ -73,14 +88,10 @@ pub struct Apu {
   file_writer: Option<BufWriter<File>>,
 }
 
-pub const CPU_FREQUENCY: u32 = 1789773;
-
-const FRAME_COUNTER_RATE: f64 = CPU_FREQUENCY as f64 / 240.0;
-
 type ReadCallback = Box<dyn FnMut(Address) -> Byte>;
 
 impl Apu {
-  pub fn new(message_sx: mpsc::Sender<Message>) -> Self {
+  pub fn new(message_sx: mpsc::Sender<Message>, region: Region) -> Self {
     let mut player = Box::<dyn Player>::default();
 
     let sample_rate = player.init().unwrap() as f32;
@@ -92,7 +103,9 @@ impl Apu {
       frame_irq: false,
 
       player,
-      sample_rate: CPU_FREQUENCY as f64 / sample_rate as f64,
+      muted: false,
+      sample_rate: region.cpu_frequency() as f64 / sample_rate as f64,
+      region,
       pulse1: Pulse::new(1),
       pulse2: Pulse::new(2),
       triangle: Triangle::new(),
@@ -103,6 +116,7 @@ impl Apu {
         SoundFilter::new_high_pass_filter(sample_rate, 440.),
         SoundFilter::new_low_pass_filter(sample_rate, 14000.),
       ],
+      expansion: None,
       message_sx: Some(message_sx),
       #[cfg(feature = "debug_audio")]
       file_writer: Some(BufWriter::new(File::create("test.pcm").unwrap())),
@@ -113,6 +127,21 @@ impl Apu {
     self.player.pull_samples(buf_size)
   }
 
+  pub fn take_samples(&mut self) -> NesResult<Vec<f32>> {
+    self.player.take_samples()
+  }
+
+  pub fn set_muted(&mut self, muted: bool) {
+    self.muted = muted;
+  }
+
+  /// Keep the audio output of another APU, e.g. the one replaced by a
+  /// savestate, so the audio device is not reopened.
+  pub fn take_output(&mut self, other: &mut Apu) {
+    std::mem::swap(&mut self.player, &mut other.player);
+    self.muted = other.muted;
+  }
+
   pub fn start(&mut self) {
     match self.player.start() {
       Ok(_) => {}
@@ -140,8 +169,12 @@ impl Apu {
     self.cycle += 1;
     let cycle2 = self.cycle as f64;
     self.step_timer();
-    let f1 = (cycle1 / FRAME_COUNTER_RATE) as u32;
-    let f2 = (cycle2 / FRAME_COUNTER_RATE) as u32;
+    if let Some(expansion) = &self.expansion {
+      expansion.borrow_mut().step_audio();
+    }
+    let frame_counter_rate = self.region.frame_counter_rate();
+    let f1 = (cycle1 / frame_counter_rate) as u32;
+    let f2 = (cycle2 / frame_counter_rate) as u32;
     if f1 != f2 {
       self.step_frame_counter();
     }
@@ -152,6 +185,15 @@ impl Apu {
     }
   }
 
+  /// Mix the sound chip of the cartridge into the output, if it has one.
+  pub fn set_expansion_audio(&mut self, mapper: Rc<RefCell<dyn Mapper>>) {
+    self.expansion = if mapper.borrow().has_expansion_audio() {
+      Some(mapper)
+    } else {
+      None
+    };
+  }
+
   pub fn set_message_bus(&mut self, message_sx: mpsc::Sender<Message>) {
     self.message_sx = Some(message_sx);
   }
@@ -162,8 +204,11 @@ impl Apu {
     let triangle = self.triangle.output();
     let noise = self.noise.output();
     let dmc = self.dmc.output();
-    let sample = PULSE_TABLE[(pulse1 + pulse2) as usize]
+    let mut sample = PULSE_TABLE[(pulse1 + pulse2) as usize]
       + TND_TABLE[(3 * triangle + 2 * noise + dmc) as usize];
+    if let Some(expansion) = &self.expansion {
+      sample += expansion.borrow().audio_output();
+    }
     let after_sample = self.filter_chain.step(sample);
 
     #[cfg(feature = "debug_audio")]
@@ -190,7 +235,9 @@ impl Apu {
       // info!("sample: {:?} {:?}", after_sample, sample);
     }
 
-    let _ = self.player.send_sample(after_sample);
+    let _ = self
+      .player
+      .send_sample(if self.muted { 0.0 } else { after_sample });
   }
 
   // mode 0:    mode 1:       function
@@ -315,9 +362,9 @@ impl Apu {
       0x400B => self.triangle.write_timer_high(value),
       0x400C => self.noise.write_control(value),
       0x400D => (),
-      0x400E => self.noise.write_period(value),
+      0x400E => self.noise.write_period(value, self.region),
       0x400F => self.noise.write_length(value),
-      0x4010 => self.dmc.write_control(value),
+      0x4010 => self.dmc.write_control(value, self.region),
       0x4011 => self.dmc.write_value(value),
       0x4012 => self.dmc.write_address(value as Address),
       0x4013 => self.dmc.write_length(value as Address),
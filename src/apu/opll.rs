@@ -0,0 +1,457 @@
+use std::f32::consts::PI;
+
+use serde::{Deserialize, Serialize};
+
+use crate::common::*;
+
+/// The OPLL makes one sample every 36 CPU cycles, 49.7KHz.
+const CYCLES_PER_SAMPLE: u32 = 36;
+const SAMPLE_RATE: f32 = 49716.0;
+const CHANNELS: usize = 6;
+
+/// Attenuation in dB at which an operator is silent.
+const MAX_ATTENUATION: f32 = 48.0;
+
+/// Built-in VRC7 instruments, patch 0 is the user defined one.
+///
+/// Reference https://www.nesdev.org/wiki/VRC7_audio
+#[rustfmt::skip]
+const PATCHES: [[Byte; 8]; 16] = [
+  [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+  [0x03, 0x21, 0x05, 0x06, 0xE8, 0x81, 0x42, 0x27], // Buzzy bell
+  [0x13, 0x41, 0x14, 0x0D, 0xD8, 0xF6, 0x23, 0x12], // Guitar
+  [0x11, 0x11, 0x08, 0x08, 0xFA, 0xB2, 0x20, 0x12], // Wurly
+  [0x31, 0x61, 0x0C, 0x07, 0xA8, 0x64, 0x61, 0x27], // Flute
+  [0x32, 0x21, 0x1E, 0x06, 0xE1, 0x76, 0x01, 0x28], // Clarinet
+  [0x02, 0x01, 0x06, 0x00, 0xA3, 0xE2, 0xF4, 0xF4], // Synth
+  [0x21, 0x61, 0x1D, 0x07, 0x82, 0x81, 0x11, 0x07], // Trumpet
+  [0x23, 0x21, 0x22, 0x17, 0xA2, 0x72, 0x01, 0x17], // Organ
+  [0x35, 0x11, 0x25, 0x00, 0x40, 0x73, 0x72, 0x01], // Bells
+  [0xB5, 0x01, 0x0F, 0x0F, 0xA8, 0xA5, 0x51, 0x02], // Vibes
+  [0x17, 0xC1, 0x24, 0x07, 0xF8, 0xF8, 0x22, 0x12], // Vibraphone
+  [0x71, 0x23, 0x11, 0x06, 0x65, 0x74, 0x18, 0x16], // Tutti
+  [0x01, 0x02, 0xD3, 0x05, 0xC9, 0x95, 0x03, 0x02], // Fretless
+  [0x61, 0x63, 0x0C, 0x00, 0x94, 0xC0, 0x33, 0xF6], // Synth bass
+  [0x21, 0x72, 0x0D, 0x00, 0xC1, 0xD5, 0x56, 0x06], // Sweep
+];
+
+const MULTIPLIERS: [f32; 16] = [
+  0.5, 1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0, 9.0, 10.0, 10.0, 12.0, 12.0, 15.0, 15.0,
+];
+
+/// Key scale attenuation in dB at block 7 by the upper 4 bits of F-number.
+const KEY_SCALE_LEVELS: [f32; 16] = [
+  0.0, 18.0, 24.0, 27.75, 30.0, 32.25, 33.75, 35.25, 36.0, 37.5, 38.25, 39.0, 39.75, 40.5, 41.25,
+  42.0,
+];
+
+/// dB per octave of the 4 key scale settings, relative to 6dB.
+const KEY_SCALE_FACTORS: [f32; 4] = [0.0, 0.25, 0.5, 1.0];
+
+/// Attack from silence at the slowest rate, and decay of 48dB, in ms.
+const ATTACK_TIME: f32 = 2826.0;
+const DECAY_TIME: f32 = 19640.0;
+
+/// Tremolo of 4.8dB at 3.7Hz and vibrato of about 14 cents at 6.4Hz.
+const TREMOLO_DEPTH: f32 = 4.8;
+const TREMOLO_RATE: f32 = 3.7;
+const VIBRATO_DEPTH: f32 = 0.008;
+const VIBRATO_RATE: f32 = 6.4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+enum EnvelopeState {
+  Attack,
+  Decay,
+  Sustain,
+  Release,
+  Off,
+}
+
+/// Parameters of one operator, unpacked from a patch.
+struct OperatorPatch {
+  tremolo: bool,
+  vibrato: bool,
+  sustained: bool,
+  key_scale_rate: bool,
+  multiplier: f32,
+  key_scale_level: usize,
+  rectified: bool,
+  attack: Byte,
+  decay: Byte,
+  sustain_level: f32,
+  release: Byte,
+}
+
+impl OperatorPatch {
+  fn new(patch: &[Byte; 8], carrier: bool) -> Self {
+    let i = carrier as usize;
+    Self {
+      tremolo: bit_eq(patch[i], 0x80),
+      vibrato: bit_eq(patch[i], 0x40),
+      sustained: bit_eq(patch[i], 0x20),
+      key_scale_rate: bit_eq(patch[i], 0x10),
+      multiplier: MULTIPLIERS[(patch[i] & 0x0F) as usize],
+      key_scale_level: (patch[2 + i] >> 6) as usize,
+      rectified: bit_eq(patch[3], if carrier { 0x10 } else { 0x08 }),
+      attack: patch[4 + i] >> 4,
+      decay: patch[4 + i] & 0x0F,
+      sustain_level: (patch[6 + i] >> 4) as f32 * 3.0,
+      release: patch[6 + i] & 0x0F,
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Operator {
+  // phase in cycles, 0..1
+  phase: f32,
+  state: EnvelopeState,
+  // envelope attenuation in dB
+  envelope: f32,
+  // last two outputs, for the modulator feedback
+  output: [f32; 2],
+}
+
+impl Operator {
+  fn new() -> Self {
+    Self {
+      phase: 0.0,
+      state: EnvelopeState::Off,
+      envelope: MAX_ATTENUATION,
+      output: [0.0; 2],
+    }
+  }
+
+  fn key_on(&mut self) {
+    self.phase = 0.0;
+    self.state = EnvelopeState::Attack;
+  }
+
+  fn key_off(&mut self) {
+    if self.state != EnvelopeState::Off {
+      self.state = EnvelopeState::Release;
+    }
+  }
+
+  /// Advance the envelope one sample, `rate` picks the effective rate of a
+  /// 0-15 setting.
+  fn step_envelope(&mut self, patch: &OperatorPatch, rate: impl Fn(Byte) -> u32, sustain: bool) {
+    match self.state {
+      EnvelopeState::Attack => {
+        let r = rate(patch.attack);
+        if patch.attack == 15 || r >= 60 {
+          self.envelope = 0.0;
+        } else if r > 0 {
+          // exponential approach, from 48dB to 0.05dB over the attack time
+          let samples = ATTACK_TIME / 2f32.powf((r as f32 - 4.0) / 4.0) * SAMPLE_RATE / 1000.0;
+          self.envelope *= 0.001f32.powf(1.0 / samples);
+        }
+        if self.envelope < 0.05 {
+          self.envelope = 0.0;
+          self.state = EnvelopeState::Decay;
+        }
+      }
+      EnvelopeState::Decay => {
+        self.envelope += decay_step(rate(patch.decay));
+        if self.envelope >= patch.sustain_level {
+          self.envelope = patch.sustain_level;
+          self.state = EnvelopeState::Sustain;
+        }
+      }
+      EnvelopeState::Sustain => {
+        // percussive sounds keep fading at the release rate
+        if !patch.sustained {
+          self.envelope += decay_step(rate(patch.release));
+        }
+      }
+      EnvelopeState::Release => {
+        let release = if sustain {
+          5
+        } else if patch.sustained {
+          patch.release
+        } else {
+          // percussive sounds already fade at the release rate
+          7.max(patch.release)
+        };
+        self.envelope += decay_step(rate(release));
+      }
+      EnvelopeState::Off => {}
+    }
+    if self.envelope >= MAX_ATTENUATION {
+      self.envelope = MAX_ATTENUATION;
+      if self.state != EnvelopeState::Attack {
+        self.state = EnvelopeState::Off;
+      }
+    }
+  }
+
+  fn wave(&self, patch: &OperatorPatch, phase: f32) -> f32 {
+    let value = (2.0 * PI * phase).sin();
+    if patch.rectified && value < 0.0 {
+      0.0
+    } else {
+      value
+    }
+  }
+}
+
+fn decay_step(rate: u32) -> f32 {
+  if rate < 4 {
+    return 0.0;
+  }
+  let samples = DECAY_TIME / 2f32.powf((rate as f32 - 4.0) / 4.0) * SAMPLE_RATE / 1000.0;
+  MAX_ATTENUATION / samples
+}
+
+#[derive(Serialize, Deserialize)]
+struct Channel {
+  f_number: Address,
+  block: Byte,
+  key_on: bool,
+  sustain: bool,
+  instrument: usize,
+  volume: Byte,
+  modulator: Operator,
+  carrier: Operator,
+}
+
+impl Channel {
+  fn new() -> Self {
+    Self {
+      f_number: 0,
+      block: 0,
+      key_on: false,
+      sustain: false,
+      instrument: 0,
+      volume: 0,
+      modulator: Operator::new(),
+      carrier: Operator::new(),
+    }
+  }
+
+  fn set_key_on(&mut self, key_on: bool) {
+    if key_on && !self.key_on {
+      self.modulator.key_on();
+      self.carrier.key_on();
+    } else if !key_on && self.key_on {
+      self.modulator.key_off();
+      self.carrier.key_off();
+    }
+    self.key_on = key_on;
+  }
+
+  fn sample(&mut self, patch: &[Byte; 8], tremolo: f32, vibrato: f32) -> f32 {
+    let modulator_patch = OperatorPatch::new(patch, false);
+    let carrier_patch = OperatorPatch::new(patch, true);
+
+    // effective envelope rate of a 0-15 setting, faster for higher notes
+    // with key scale rate
+    let key_code = ((self.block << 1) | (self.f_number >> 8) as Byte) as u32;
+    let rate = |key_scale_rate: bool| {
+      move |setting: Byte| {
+        let scale = if key_scale_rate {
+          key_code
+        } else {
+          key_code >> 2
+        };
+        if setting == 0 {
+          0
+        } else {
+          (setting as u32 * 4 + scale).min(63)
+        }
+      }
+    };
+    let (sustain, modulator, carrier) = (self.sustain, &mut self.modulator, &mut self.carrier);
+    modulator.step_envelope(
+      &modulator_patch,
+      rate(modulator_patch.key_scale_rate),
+      sustain,
+    );
+    carrier.step_envelope(&carrier_patch, rate(carrier_patch.key_scale_rate), sustain);
+
+    let base = self.f_number as f32 * 2f32.powi(self.block as i32) / 524288.0;
+    let step = |op: &mut Operator, patch: &OperatorPatch| {
+      let factor = if patch.vibrato { vibrato } else { 1.0 };
+      op.phase = (op.phase + base * patch.multiplier * factor).fract();
+    };
+    step(modulator, &modulator_patch);
+    step(carrier, &carrier_patch);
+
+    let key_scale = KEY_SCALE_LEVELS[(self.f_number >> 5) as usize & 0x0F];
+    let key_scale = (key_scale - 6.0 * (7 - self.block) as f32).max(0.0);
+    let level = |op: &Operator, patch: &OperatorPatch, total: f32| {
+      let am = if patch.tremolo { tremolo } else { 0.0 };
+      let ksl = key_scale * KEY_SCALE_FACTORS[patch.key_scale_level];
+      let attenuation = op.envelope + total + ksl + am;
+      if attenuation >= MAX_ATTENUATION {
+        0.0
+      } else {
+        10f32.powf(-attenuation / 20.0)
+      }
+    };
+
+    // modulator with feedback of up to 4 pi
+    let feedback = patch[3] & 0x07;
+    let feedback_phase = if feedback == 0 {
+      0.0
+    } else {
+      (modulator.output[0] + modulator.output[1]) / 2.0 * 2f32.powi(feedback as i32) / 64.0
+    };
+    let total_level = (patch[2] & 0x3F) as f32 * 0.75;
+    let modulator_out = modulator.wave(&modulator_patch, modulator.phase + feedback_phase)
+      * level(modulator, &modulator_patch, total_level);
+    modulator.output = [modulator.output[1], modulator_out];
+
+    // a full modulator output shifts the carrier by 4 pi
+    let volume = self.volume as f32 * 3.0;
+    let carrier_out = carrier.wave(&carrier_patch, carrier.phase + modulator_out * 2.0)
+      * level(carrier, &carrier_patch, volume);
+    carrier.output = [carrier.output[1], carrier_out];
+    carrier_out
+  }
+}
+
+/**
+ * The YM2413 (OPLL) derived FM synthesizer of the VRC7: 6 channels of a
+ * modulator and a carrier operator, 15 built-in instruments and one user
+ * defined.
+ *
+ * The operators are computed in floating point from the documented rates
+ * and levels, not from the log-sin tables of the chip.
+ *
+ * Reference https://www.nesdev.org/wiki/VRC7_audio
+ */
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Opll {
+  cycle: u32,
+  address: Byte,
+  custom_patch: [Byte; 8],
+  channels: Vec<Channel>,
+  lfo_time: f32,
+  output: f32,
+}
+
+impl Opll {
+  pub fn new() -> Self {
+    Self {
+      cycle: 0,
+      address: 0,
+      custom_patch: [0; 8],
+      channels: (0..CHANNELS).map(|_| Channel::new()).collect(),
+      lfo_time: 0.0,
+      output: 0.0,
+    }
+  }
+
+  /// Silence all channels, like bit 6 of VRC7 $E000.
+  pub fn reset(&mut self) {
+    *self = Self::new();
+  }
+
+  pub fn write_address(&mut self, value: Byte) {
+    self.address = value;
+  }
+
+  pub fn write_data(&mut self, value: Byte) {
+    let channel = (self.address & 0x0F) as usize;
+    match self.address {
+      0x00..=0x07 => self.custom_patch[self.address as usize] = value,
+      0x10..=0x15 => {
+        let ch = &mut self.channels[channel];
+        ch.f_number = (ch.f_number & 0x100) | value as Address;
+      }
+      0x20..=0x25 => {
+        let ch = &mut self.channels[channel];
+        ch.f_number = (ch.f_number & 0xFF) | ((value as Address & 0x01) << 8);
+        ch.block = (value >> 1) & 0x07;
+        ch.sustain = bit_eq(value, 0x20);
+        ch.set_key_on(bit_eq(value, 0x10));
+      }
+      0x30..=0x35 => {
+        let ch = &mut self.channels[channel];
+        ch.instrument = (value >> 4) as usize;
+        ch.volume = value & 0x0F;
+      }
+      _ => {}
+    }
+  }
+
+  /// Clock once per CPU cycle.
+  pub fn step(&mut self) {
+    self.cycle += 1;
+    if self.cycle < CYCLES_PER_SAMPLE {
+      return;
+    }
+    self.cycle = 0;
+
+    self.lfo_time += 1.0 / SAMPLE_RATE;
+    if self.lfo_time > 100.0 {
+      self.lfo_time -= 100.0;
+    }
+    let tremolo = TREMOLO_DEPTH * (1.0 - (2.0 * PI * TREMOLO_RATE * self.lfo_time).cos()) / 2.0;
+    let vibrato = 1.0 + VIBRATO_DEPTH * (2.0 * PI * VIBRATO_RATE * self.lfo_time).sin();
+
+    let custom_patch = self.custom_patch;
+    self.output = self
+      .channels
+      .iter_mut()
+      .map(|ch| {
+        let patch = if ch.instrument == 0 {
+          &custom_patch
+        } else {
+          &PATCHES[ch.instrument]
+        };
+        ch.sample(patch, tremolo, vibrato)
+      })
+      .sum();
+  }
+
+  /// Level on the same scale as the APU mixer.
+  pub fn output(&self) -> f32 {
+    self.output * 0.12
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn opll_test() {
+    let mut opll = Opll::new();
+    // a plain sine from a silent modulator, A4 at full volume
+    let patch = [0x21, 0x21, 0x3F, 0x00, 0xFF, 0xF0, 0x0F, 0x0F];
+    for (address, value) in patch.iter().enumerate() {
+      opll.write_address(address as Byte);
+      opll.write_data(*value);
+    }
+    for (address, value) in [(0x10, 0x20), (0x30, 0x00), (0x20, 0x19)] {
+      opll.write_address(address);
+      opll.write_data(value);
+    }
+    let mut peak: f32 = 0.0;
+    let mut crossings = 0;
+    let mut last = 0.0;
+    // 0.1 second
+    for _ in 0..(CYCLES_PER_SAMPLE * 4972) {
+      opll.step();
+      let out = opll.output();
+      if last <= 0.0 && out > 0.0 {
+        crossings += 1;
+      }
+      last = out;
+      peak = peak.max(out.abs());
+    }
+    assert!(peak > 0.01);
+    // 288 * 49716 * 2^4 / 2^19 = 437Hz
+    assert!((40..=48).contains(&crossings), "{} crossings", crossings);
+
+    // key off fades out
+    opll.write_address(0x20);
+    opll.write_data(0x08);
+    for _ in 0..(CYCLES_PER_SAMPLE * 49716) {
+      opll.step();
+    }
+    assert!(opll.output().abs() < 0.001);
+  }
+}
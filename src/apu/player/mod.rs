@@ -17,6 +17,9 @@ pub trait Player {
   fn send_sample(&mut self, sample: f32) -> NesResult<()>;
 
   fn pull_samples(&mut self, sample_size: usize) -> NesResult<Vec<f32>>;
+
+  /// Drain every sample produced since the last call.
+  fn take_samples(&mut self) -> NesResult<Vec<f32>>;
 }
 
 pub struct DummyPlayer {
@@ -74,6 +77,15 @@ impl Player for DummyPlayer {
       return Err(anyhow!("receiver is not initialized"));
     }
   }
+
+  fn take_samples(&mut self) -> NesResult<Vec<f32>> {
+    if let Some(ref receiver) = self.receiver {
+      self.buf.extend(receiver.try_iter());
+      Ok(std::mem::take(&mut self.buf))
+    } else {
+      Err(anyhow!("receiver is not initialized"))
+    }
+  }
 }
 
 impl Default for Box<dyn Player> {
@@ -1,6 +1,6 @@
 use serde::{Deserialize, Serialize};
 
-use crate::common::*;
+use crate::common::{region::Region, *};
 
 use super::ReadCallback;
 
@@ -30,6 +30,14 @@ const DMC_TABLE: [Byte; 16] = [
   214, 190, 170, 160, 143, 127, 113, 107, 95, 80, 71, 64, 53, 42, 36, 27,
 ];
 
+const NOISE_TABLE_PAL: [Address; 16] = [
+  4, 8, 14, 30, 60, 88, 118, 148, 188, 236, 354, 472, 708, 944, 1890, 3778,
+];
+
+const DMC_TABLE_PAL: [Byte; 16] = [
+  199, 177, 158, 149, 138, 118, 105, 99, 88, 74, 66, 59, 49, 39, 33, 25,
+];
+
 #[derive(Serialize, Deserialize)]
 struct Envelope {
   enabled: bool,
@@ -308,10 +316,14 @@ impl Noise {
     self.shift_register = ((b1 ^ b2) << 14) | (self.shift_register >> 1);
   }
 
-  pub(crate) fn write_period(&mut self, value: Byte) {
+  pub(crate) fn write_period(&mut self, value: Byte, region: Region) {
     // m--- iiii       mode, period index
     self.timer_mode = bit_eq(value, 0x80);
-    self.timer_period = NOISE_TABLE[value as usize & 0x0F];
+    let table = match region {
+      Region::Pal => &NOISE_TABLE_PAL,
+      Region::Ntsc | Region::Dendy => &NOISE_TABLE,
+    };
+    self.timer_period = table[value as usize & 0x0F];
   }
 }
 
@@ -533,9 +545,13 @@ impl DMC {
     self.value = value & 0x7F;
   }
 
-  pub(crate) fn write_control(&mut self, value: Byte) {
+  pub(crate) fn write_control(&mut self, value: Byte, region: Region) {
     self.irq = bit_eq(value, 0x80);
     self.loop_enable = bit_eq(value, 0x40);
-    self.tick_period = DMC_TABLE[value as usize & 0xF];
+    let table = match region {
+      Region::Pal => &DMC_TABLE_PAL,
+      Region::Ntsc | Region::Dendy => &DMC_TABLE,
+    };
+    self.tick_period = table[value as usize & 0xF];
   }
 }
@@ -1,23 +1,25 @@
 use ciborium::de::from_reader;
 use ciborium::ser::into_writer;
+use ciborium::value::Value;
 use log::{error, warn};
 use serde::{Deserialize, Serialize};
-use std::fs::File;
-use std::io::{BufReader, BufWriter};
-use std::sync::mpsc::Sender;
+use std::io::{Read, Write};
 use std::sync::{Arc, Mutex};
-use std::{cell::RefCell, rc::Rc};
+use std::{
+  cell::{Ref, RefCell},
+  rc::Rc,
+};
 
 use crate::apu::Apu;
+use crate::cartridge::Cartridge;
+use crate::cheat::CheatEffect;
 use crate::common::*;
 use crate::controller::key_binding_parser::KeyType;
 use crate::controller::Controller;
-use crate::cpu::InterruptType;
 use crate::mapper::factory::load_mapper;
-use crate::mapper::Mapper;
+use crate::mapper::{Mapper, MapperType};
 use crate::ppu::Ppu;
-
-use super::message_bus::Message;
+use crate::NesResult;
 
 pub type IORegister = u16;
 
@@ -48,6 +50,13 @@ pub struct MainBus {
   ext_ram: Vec<Byte>,
   has_ext_ram: bool,
   #[serde(skip)]
+  battery: bool,
+  // PRG-RAM is banked by the mapper
+  #[serde(skip)]
+  mapper_ram: bool,
+  #[serde(skip)]
+  watch_reads: bool,
+  #[serde(skip)]
   mapper: Option<Rc<RefCell<dyn Mapper>>>,
   #[serde(skip)]
   registers: Vec<Arc<Mutex<dyn RegisterHandler>>>,
@@ -55,6 +64,8 @@ pub struct MainBus {
   control1: Controller,
   #[serde(skip)]
   control2: Controller,
+  #[serde(skip)]
+  cheats: Vec<CheatEffect>,
 
   skip_dma_cycles: bool,
 }
@@ -65,67 +76,94 @@ impl MainBus {
       ram: vec![0; 0x800],
       ext_ram: vec![],
       has_ext_ram: false,
+      battery: false,
+      mapper_ram: false,
+      watch_reads: false,
       mapper: None,
       registers: vec![ppu, apu],
       control1: Controller::new(),
       control2: Controller::remote_controller(),
+      cheats: vec![],
 
       skip_dma_cycles: false,
     }
   }
 
-  pub fn save_binary<'a>(&'a self, mut writer: BufWriter<&'a mut File>) -> BufWriter<&mut File> {
-    into_writer(&self.ram, &mut writer).unwrap();
-    into_writer(&self.ext_ram, &mut writer).unwrap();
-    into_writer(&self.skip_dma_cycles, &mut writer).unwrap();
+  pub fn save_binary<W: Write>(&self, mut writer: W) -> NesResult<()> {
+    into_writer(&self.ram, &mut writer)?;
+    into_writer(&self.ext_ram, &mut writer)?;
+    into_writer(&self.skip_dma_cycles, &mut writer)?;
     let mapper = self.mapper.as_ref().unwrap().borrow();
 
-    into_writer(&mapper.mapper_type(), &mut writer).unwrap();
-    into_writer(&mapper.save(), &mut writer).unwrap();
-    writer
+    into_writer(&mapper.mapper_type(), &mut writer)?;
+    into_writer(&serde_bytes::Bytes::new(&mapper.save()), &mut writer)?;
+    Ok(())
   }
 
-  pub fn load_binary(
-    mut reader: BufReader<File>,
-    message_sx: Sender<Message>,
+  /// `rom` provides the PRG and CHR ROM, which are not part of the state.
+  pub fn load_binary<R: Read>(
+    mut reader: R,
+    rom: &Cartridge,
     ppu: Arc<Mutex<Ppu>>,
     apu: Arc<Mutex<Apu>>,
-  ) -> Self {
-    let ram: Vec<Byte> = from_reader(&mut reader).unwrap();
-    let ext_ram: Vec<Byte> = from_reader(&mut reader).unwrap();
-    let skip_dma_cycles: bool = from_reader(&mut reader).unwrap();
+  ) -> NesResult<Self> {
+    let ram: Vec<Byte> = from_reader(&mut reader)?;
+    let ext_ram: Vec<Byte> = from_reader(&mut reader)?;
+    let skip_dma_cycles: bool = from_reader(&mut reader)?;
+    if ram.len() != 0x800 || !(ext_ram.is_empty() || ext_ram.len() == 0x2000) {
+      anyhow::bail!("invalid ram size {} / {}", ram.len(), ext_ram.len());
+    }
 
-    let mapper_type: u8 = from_reader(&mut reader).unwrap();
-    let mapper_content: String = from_reader(&mut reader).unwrap();
+    let mapper_type: MapperType = from_reader(&mut reader)?;
+    // version 1 states stored the mapper as a JSON string
+    let mapper_state = match from_reader(&mut reader)? {
+      Value::Bytes(bytes) => bytes,
+      Value::Text(text) => text.into_bytes(),
+      _ => anyhow::bail!("invalid mapper state"),
+    };
     let ppu_clone = ppu.clone();
     let mapper = load_mapper(
-      mapper_type as Byte,
-      mapper_content.as_str(),
+      mapper_type,
+      &mapper_state,
+      rom,
       Box::new(move |val: Byte| {
-        let r = ppu_clone.try_lock();
-        if r.is_err() {
+        if let Ok(mut ppu) = ppu_clone.try_lock() {
+          ppu.update_mirroring(Some(val));
+        } else {
           warn!("ppu is locked");
-          return;
         }
-        r.unwrap().update_mirroring(Some(val));
       }),
-      Box::new(move || {
-        if let Err(e) = message_sx.send(Message::CpuInterrupt(InterruptType::IRQ)) {
-          log::error!("send interrupt error {:?}", e);
-        }
-      })
-    );
+    )?;
     ppu.lock().unwrap().set_mapper_for_bus(mapper.clone());
-    Self {
+    apu.lock().unwrap().set_expansion_audio(mapper.clone());
+    let battery = mapper.borrow().has_battery();
+    let mapper_ram = mapper.borrow().prg_ram().is_some();
+    let watch_reads = mapper.borrow().watch_cpu_reads();
+    Ok(Self {
+      has_ext_ram: !ext_ram.is_empty(),
       ram,
       ext_ram,
-      has_ext_ram: false,
+      battery,
+      mapper_ram,
+      watch_reads,
       mapper: Some(mapper),
       registers: vec![ppu, apu],
       control1: Controller::new(),
       control2: Controller::new(),
+      cheats: vec![],
       skip_dma_cycles,
-    }
+    })
+  }
+
+  /// Keep key bindings and button state of another bus, e.g. after loading a
+  /// savestate.
+  pub fn take_controllers(&mut self, other: &mut MainBus) {
+    std::mem::swap(&mut self.control1, &mut other.control1);
+    std::mem::swap(&mut self.control2, &mut other.control2);
+  }
+
+  pub fn cartridge(&self) -> Ref<'_, Cartridge> {
+    Ref::map(self.mapper.as_ref().unwrap().borrow(), |m| m.cartridge())
   }
 
   pub fn set_mapper(&mut self, mapper: Rc<RefCell<dyn Mapper>>) {
@@ -133,14 +171,137 @@ impl MainBus {
       self.has_ext_ram = true;
       self.ext_ram.resize(0x2000, 0);
     }
+    self.battery = mapper.borrow().has_battery();
+    self.mapper_ram = mapper.borrow().prg_ram().is_some();
+    self.watch_reads = mapper.borrow().watch_cpu_reads();
     self.mapper = Some(mapper);
   }
 
+  /// Copy a cartridge trainer to $7000-$71FF, into the PRG-RAM of the mapper
+  /// when it has its own.
+  pub fn load_trainer(&mut self, trainer: &[Byte]) {
+    if self.mapper_ram {
+      let mut mapper = self.mapper.as_ref().unwrap().borrow_mut();
+      let ram = mapper.prg_ram_mut().unwrap();
+      // RAM smaller than 8KB like the 1KB of MMC6 is mirrored
+      let offset = 0x1000 % ram.len();
+      ram[offset..offset + trainer.len()].copy_from_slice(trainer);
+      return;
+    }
+    self.has_ext_ram = true;
+    self.ext_ram.resize(0x2000, 0);
+    self.ext_ram[0x1000..0x1000 + trainer.len()].copy_from_slice(trainer);
+  }
+
+  /// Battery backed PRG-RAM, `None` if the cartridge has no battery.
+  pub fn battery_ram(&self) -> Option<Vec<Byte>> {
+    if !self.battery {
+      None
+    } else if self.mapper_ram {
+      let mapper = self.mapper.as_ref().unwrap().borrow();
+      mapper.prg_ram().map(|ram| ram.to_vec())
+    } else if !self.ext_ram.is_empty() {
+      Some(self.ext_ram.clone())
+    } else {
+      None
+    }
+  }
+
+  pub fn load_battery_ram(&mut self, data: &[Byte]) {
+    if !self.battery {
+      warn!("cartridge has no battery, ignore {} bytes", data.len());
+      return;
+    }
+    let mut mapper = self.mapper.as_ref().unwrap().borrow_mut();
+    let ram = match mapper.prg_ram_mut() {
+      Some(ram) if self.mapper_ram => ram,
+      _ => &mut self.ext_ram[..],
+    };
+    let len = std::cmp::min(data.len(), ram.len());
+    ram[..len].copy_from_slice(&data[..len]);
+  }
+
   pub fn set_controller_keys(&mut self, p1: Vec<KeyType>, p2: Vec<KeyType>) {
     self.control1.set_key_bindings(p1);
     self.control2.set_key_bindings(p2);
   }
 
+  pub fn set_buttons(&mut self, port: usize, buttons: Byte) {
+    match port {
+      0 => self.control1.set_buttons(buttons),
+      1 => self.control2.set_buttons(buttons),
+      _ => warn!("invalid controller port {}", port),
+    }
+  }
+
+  pub fn poll_buttons(&mut self) -> [Byte; 2] {
+    [self.control1.poll_buttons(), self.control2.poll_buttons()]
+  }
+
+  /// Drive both controllers from recorded input instead of the keyboard.
+  pub fn set_input_override(&mut self, buttons: Option<[Byte; 2]>) {
+    self.control1.set_input_override(buttons.map(|b| b[0]));
+    self.control2.set_input_override(buttons.map(|b| b[1]));
+  }
+
+  /// Replace the active cheats, Game Genie codes patch PRG reads right away
+  /// and freezes are applied by [`MainBus::apply_freezes`].
+  pub fn set_cheats(&mut self, cheats: Vec<CheatEffect>) {
+    self.cheats = cheats;
+  }
+
+  /// Rewrite frozen RAM values, called once per frame.
+  pub fn apply_freezes(&mut self) {
+    for cheat in &self.cheats {
+      if let CheatEffect::Freeze { address, value } = *cheat {
+        match address {
+          0x0000..=0x1fff => self.ram[(address & 0x07ff) as usize] = value,
+          // a CPU write, so the banking and write protection of the mapper apply
+          0x6000..=0x7fff if self.mapper_ram => {
+            self
+              .mapper
+              .as_ref()
+              .unwrap()
+              .borrow_mut()
+              .write_prg(address, value);
+          }
+          0x6000..=0x7fff if self.has_ext_ram => {
+            self.ext_ram[(address - 0x6000) as usize] = value
+          }
+          _ => {}
+        }
+      }
+    }
+  }
+
+  #[inline]
+  fn patch_prg(&self, addr: Address, value: Byte) -> Byte {
+    for cheat in &self.cheats {
+      match *cheat {
+        CheatEffect::Genie {
+          address,
+          value: patched,
+          compare,
+        } if address == addr && compare.is_none_or(|c| c == value) => return patched,
+        _ => {}
+      }
+    }
+    value
+  }
+
+  /// Let the mapper see the CPU cycles of the last instruction.
+  pub fn clock_mapper(&mut self, cycles: u32) {
+    let mut mapper = self.mapper.as_ref().unwrap().borrow_mut();
+    for _ in 0..cycles {
+      mapper.cpu_clock();
+    }
+  }
+
+  /// Level of the IRQ line of the cartridge.
+  pub fn mapper_irq(&self) -> bool {
+    self.mapper.as_ref().unwrap().borrow().irq_pending()
+  }
+
   pub fn check_and_reset_dma(&mut self) -> bool {
     let ret = self.skip_dma_cycles;
     self.skip_dma_cycles = false;
@@ -183,16 +344,32 @@ impl MainBus {
                 break;
               }
             }
+            if mapped_addr == PPU_CTRL {
+              self
+                .mapper
+                .as_ref()
+                .unwrap()
+                .borrow_mut()
+                .write_register(mapped_addr, value);
+            }
           }
         }
       }
       0x4020..=0x5fff => {
-        // self
-        //   .mapper
-        //   .as_ref()
-        //   .unwrap()
-        //   .borrow_mut()
-        //   .write_prg(addr, value);
+        self
+          .mapper
+          .as_ref()
+          .unwrap()
+          .borrow_mut()
+          .write_register(addr, value);
+      }
+      0x6000..=0x7fff if self.mapper_ram => {
+        self
+          .mapper
+          .as_ref()
+          .unwrap()
+          .borrow_mut()
+          .write_prg(addr, value);
       }
       0x6000..=0x7fff => {
         if self.has_ext_ram {
@@ -216,6 +393,7 @@ impl MainBus {
       0x0000..=0x1fff => self.ram[(addr & 0x07ff) as usize],
       0x2000..=0x401f => 0,
       0x4020..=0x5fff => self.mapper.as_ref().unwrap().borrow().read_prg(addr),
+      0x6000..=0x7fff if self.mapper_ram => self.mapper.as_ref().unwrap().borrow().read_prg(addr),
       0x6000..=0x7fff => {
         if self.has_ext_ram {
           self.ext_ram[(addr - 0x6000) as usize]
@@ -223,7 +401,14 @@ impl MainBus {
           0
         }
       }
-      _ => self.mapper.as_ref().unwrap().borrow().read_prg(addr),
+      _ => {
+        let value = self.mapper.as_ref().unwrap().borrow().read_prg(addr);
+        if self.cheats.is_empty() {
+          value
+        } else {
+          self.patch_prg(addr, value)
+        }
+      }
     }
   }
 
@@ -254,7 +439,18 @@ impl MainBus {
     if addr < 0x4020 && addr > 0x2000 {
       return self.read_extra(addr);
     }
-    self.save_read(addr)
+    if (0x4020..0x6000).contains(&addr) {
+      let value = self.mapper.as_ref().unwrap().borrow_mut().read_register(addr);
+      if let Some(value) = value {
+        return value;
+      }
+    }
+    let value = self.save_read(addr);
+    if self.watch_reads && addr >= 0x8000 {
+      let mut mapper = self.mapper.as_ref().unwrap().borrow_mut();
+      mapper.notify_cpu_read(addr, value);
+    }
+    value
   }
 
   #[inline]
@@ -273,7 +469,7 @@ impl MainBus {
     } else if addr < 0x6000 {
       error!("Not supported to access expansion ROM");
       None
-    } else if addr < 0x8000 {
+    } else if addr < 0x8000 && !self.ext_ram.is_empty() {
       let ptr = self.ext_ram.as_ptr();
       Some(ptr.add(addr - 0x6000))
     } else {
@@ -281,3 +477,44 @@ impl MainBus {
     }
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use crate::cheat::CheatEffect;
+  use crate::console::Console;
+
+  // Namco 163 has its own PRG-RAM at $6000-$7FFF, 32KB PRG and 8KB CHR
+  fn n163_rom(trainer: Option<&[u8]>) -> Vec<u8> {
+    let mut rom = b"NES\x1A\x02\x01\x30\x10\x00\x00\x00\x00\x00\x00\x00\x00".to_vec();
+    if let Some(trainer) = trainer {
+      rom[6] |= 0x04;
+      rom.extend_from_slice(trainer);
+    }
+    rom.resize(rom.len() + 0x8000 + 0x2000, 0);
+    rom
+  }
+
+  #[test]
+  fn trainer_test() {
+    let trainer: Vec<u8> = (0..512).map(|i| i as u8 ^ 0xA5).collect();
+    let console = Console::new(&n163_rom(Some(&trainer))).unwrap();
+    let cpu = console.instance().cpu.lock().unwrap();
+    assert_eq!(cpu.main_bus().save_read(0x7000), 0xA5);
+    assert_eq!(cpu.main_bus().save_read(0x71FF), 0x5A);
+  }
+
+  #[test]
+  fn mapper_ram_freeze_test() {
+    let console = Console::new(&n163_rom(None)).unwrap();
+    let mut cpu = console.instance().cpu.lock().unwrap();
+    let bus = cpu.main_bus_mut();
+    // enable PRG-RAM writes
+    bus.write(0xF800, 0x40);
+    bus.set_cheats(vec![CheatEffect::Freeze {
+      address: 0x6123,
+      value: 0x5A,
+    }]);
+    bus.apply_freezes();
+    assert_eq!(bus.save_read(0x6123), 0x5A);
+  }
+}
@@ -26,6 +26,12 @@ pub struct PictureBus {
   palette: Vec<Byte>,
   #[serde(skip)]
   mapper: Option<Rc<RefCell<dyn Mapper>>>, // TODO: move mapper to PPU to save lock time?
+  #[serde(skip)]
+  watch_reads: bool,
+  #[serde(skip)]
+  mapper_name_tables: bool,
+  #[serde(skip)]
+  watch_fetches: bool,
 }
 
 impl PictureBus {
@@ -38,10 +44,16 @@ impl PictureBus {
       name_table3: 0,
       palette: vec![0; 0x20],
       mapper: None,
+      watch_reads: false,
+      mapper_name_tables: false,
+      watch_fetches: false,
     }
   }
 
   pub fn set_mapper(&mut self, mapper: Rc<RefCell<dyn Mapper>>) {
+    self.watch_reads = mapper.borrow().watch_ppu_reads();
+    self.mapper_name_tables = mapper.borrow().maps_nametables();
+    self.watch_fetches = mapper.borrow().watch_ppu_fetches();
     self.mapper = Some(mapper);
     self.update_mirroring(None);
   }
@@ -58,33 +70,87 @@ impl PictureBus {
 
   #[inline]
   pub fn batch_read(&self, addr1: Address, addr2: Address, shift_time: u8) -> Byte {
+    self.batch_read_pattern(addr1, addr2, shift_time, false)
+  }
+
+  /// Same as `batch_read` for sprite patterns.
+  #[inline]
+  pub fn batch_read_sprite(&self, addr1: Address, addr2: Address, shift_time: u8) -> Byte {
+    self.batch_read_pattern(addr1, addr2, shift_time, true)
+  }
+
+  #[inline]
+  fn batch_read_pattern(
+    &self,
+    addr1: Address,
+    addr2: Address,
+    shift_time: u8,
+    sprite: bool,
+  ) -> Byte {
     let mapper = self.mapper.as_ref().unwrap().borrow();
-    let value1 = match addr1 {
-      0x0000..=0x1FFF => mapper.read_chr(addr1),
-      0x2000..=0x3EFF => self.ram[self.get_name_table(addr1) + (addr1 & 0x3FF) as usize],
-      0x3F00..=0x3FFF => self.palette[(addr1 & 0x1F) as usize],
-      _ => 0,
-    } >> shift_time;
-    let value2 = match addr2 {
-      0x0000..=0x1FFF => mapper.read_chr(addr2),
-      0x2000..=0x3EFF => self.ram[self.get_name_table(addr2) + (addr2 & 0x3FF) as usize],
-      0x3F00..=0x3FFF => self.palette[(addr2 & 0x1F) as usize],
-      _ => 0,
-    } >> shift_time;
+    let value1 = self.read_with(&*mapper, addr1, sprite) >> shift_time;
+    let value2 = self.read_with(&*mapper, addr2, sprite) >> shift_time;
+    drop(mapper);
+    if self.watch_reads {
+      self.notify_read(addr1);
+      self.notify_read(addr2);
+    }
     value1 & 1 | ((value2 & 1) << 1)
   }
 
+  #[inline]
+  fn read_with(&self, mapper: &dyn Mapper, addr: Address, sprite: bool) -> Byte {
+    match addr {
+      0x0000..=0x1FFF if sprite => mapper.read_sprite_chr(addr),
+      0x0000..=0x1FFF => mapper.read_chr(addr),
+      0x2000..=0x3EFF if self.mapper_name_tables => mapper.read_nametable(addr, &self.ram),
+      0x2000..=0x3EFF => self.ram[self.get_name_table(addr) + (addr & 0x3FF) as usize],
+      0x3F00..=0x3FFF => self.palette[(addr & 0x1F) as usize],
+      _ => 0,
+    }
+  }
+
+  #[inline]
+  fn notify_read(&self, addr: Address) {
+    if addr < 0x2000 {
+      self.mapper.as_ref().unwrap().borrow_mut().notify_ppu_read(addr);
+    }
+  }
+
   #[inline]
   pub fn read(&self, addr: Address) -> Byte {
     match addr {
       // TODO(xxrl) avoid borrow for each time reading will save performance.
-      0x0000..=0x1FFF => self.mapper.as_ref().unwrap().borrow().read_chr(addr),
+      0x0000..=0x1FFF => {
+        let value = self.mapper.as_ref().unwrap().borrow().read_chr(addr);
+        if self.watch_reads {
+          self.notify_read(addr);
+        }
+        value
+      }
+      0x2000..=0x3EFF if self.mapper_name_tables => self
+        .mapper
+        .as_ref()
+        .unwrap()
+        .borrow()
+        .read_nametable(addr, &self.ram),
       0x2000..=0x3EFF => self.ram[self.get_name_table(addr) + (addr & 0x3FF) as usize],
       0x3F00..=0x3FFF => self.palette[(addr & 0x1F) as usize],
       _ => 0,
     }
   }
 
+  /// Nametable or attribute fetch of the background renderer.
+  #[inline]
+  pub fn fetch(&self, addr: Address) -> Byte {
+    if self.mapper_name_tables {
+      let mapper = self.mapper.as_ref().unwrap().borrow();
+      mapper.fetch_nametable(addr, &self.ram)
+    } else {
+      self.read(addr)
+    }
+  }
+
   #[inline]
   pub fn read_palette(&self, palette_addr: Byte) -> Byte {
     self.palette[palette_addr as usize]
@@ -98,6 +164,13 @@ impl PictureBus {
         .unwrap()
         .borrow_mut()
         .write_chr(addr, value);
+    } else if addr < 0x3EFF && self.mapper_name_tables {
+      self
+        .mapper
+        .as_ref()
+        .unwrap()
+        .borrow_mut()
+        .write_nametable(addr, value, &mut self.ram);
     } else if addr < 0x3EFF {
       let idx = self.get_name_table(addr) + (addr & 0x3FF) as usize;
       self.ram[idx] = value;
@@ -162,7 +235,12 @@ impl PictureBus {
     }
   }
 
-  pub fn scanline_irq(&mut self) {
-    self.mapper.as_mut().unwrap().borrow_mut().scanline_irq()
+  #[inline]
+  pub fn watch_fetches(&self) -> bool {
+    self.watch_fetches
+  }
+
+  pub fn notify_fetch(&self, addr: Address, dot: u64) {
+    self.mapper.as_ref().unwrap().borrow_mut().notify_ppu_fetch(addr, dot);
   }
 }
@@ -1,4 +1,7 @@
 use crate::common::*;
+use crate::mapper::MapperType;
+use crate::NesResult;
+use anyhow::Context;
 use log::{error, info};
 use serde::Deserialize;
 use serde::Serialize;
@@ -10,19 +13,222 @@ use std::vec::Vec;
 
 pub static BANK_SIZE: usize = 0x4000;
 pub static VBANK_SIZE: usize = 0x2000;
+pub static TRAINER_SIZE: usize = 0x200;
+
+/**
+ * Why a ROM could not be turned into a running cartridge. Loading functions
+ * return it wrapped in `NesError`, frontends can `downcast_ref` it or just
+ * show the message.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LoadError {
+  BadMagic,
+  /// The image ends before the size the header declares.
+  Truncated {
+    section: &'static str,
+    expected: usize,
+    actual: usize,
+  },
+  UnsupportedMapper(MapperType),
+  UnsupportedFeature(String),
+}
+
+impl std::fmt::Display for LoadError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      LoadError::BadMagic => write!(f, "not an iNES image (bad magic number)"),
+      LoadError::Truncated {
+        section,
+        expected,
+        actual,
+      } => write!(
+        f,
+        "truncated ROM: {} is {} bytes, header declares {}",
+        section, actual, expected
+      ),
+      LoadError::UnsupportedMapper(mapper) => write!(f, "unsupported mapper {}", mapper),
+      LoadError::UnsupportedFeature(feature) => write!(f, "unsupported feature: {}", feature),
+    }
+  }
+}
+
+impl std::error::Error for LoadError {}
+
+/// Timing region declared by the header.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TimingMode {
+  #[default]
+  Ntsc,
+  Pal,
+  MultiRegion,
+  Dendy,
+}
+
+/// Console type from byte 7 (and byte 13 for extended types).
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ConsoleType {
+  #[default]
+  Nes,
+  VsSystem,
+  Playchoice10,
+  Extended(Byte),
+}
+
+/**
+ * Typed view of the 16 byte iNES / NES 2.0 header.
+ * Sizes are in bytes, iNES 1.0 images fill in the usual defaults.
+ */
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct NesHeader {
+  pub nes2: bool,
+  pub mapper: u16,
+  pub submapper: Byte,
+  pub name_table_mirroring: Byte,
+  pub battery: bool,
+  pub trainer: bool,
+
+  pub prg_rom_size: usize,
+  pub chr_rom_size: usize,
+  pub prg_ram_size: usize,
+  pub prg_nvram_size: usize,
+  pub chr_ram_size: usize,
+  pub chr_nvram_size: usize,
+
+  pub timing: TimingMode,
+  pub console_type: ConsoleType,
+  pub misc_roms: Byte,
+  pub expansion_device: Byte,
+}
+
+impl NesHeader {
+  pub fn parse(header: &[u8]) -> Result<Self, LoadError> {
+    if header.len() < 0x10 || &header[0..4] != b"NES\x1A" {
+      error!(
+        "Not a valid iNES image. Magic number: {:02x?} rather than NES1a",
+        &header[..std::cmp::min(4, header.len())]
+      );
+      return Err(LoadError::BadMagic);
+    }
+    let mut ret = Self {
+      nes2: (header[7] & 0x0C) == 0x08,
+      name_table_mirroring: header[6] & 0x9,
+      battery: bit_eq(header[6], 0x2),
+      trainer: bit_eq(header[6], 0x4),
+      ..Default::default()
+    };
+    ret.console_type = match header[7] & 0x3 {
+      0 => ConsoleType::Nes,
+      1 => ConsoleType::VsSystem,
+      2 => ConsoleType::Playchoice10,
+      _ => ConsoleType::Extended(header[13] & 0xF),
+    };
+
+    if ret.nes2 {
+      ret.mapper =
+        ((header[6] >> 4) as u16) | ((header[7] & 0xF0) as u16) | (((header[8] & 0xF) as u16) << 8);
+      ret.submapper = header[8] >> 4;
+      ret.prg_rom_size = rom_size("PRG-ROM", header[4], header[9] & 0xF, BANK_SIZE)?;
+      ret.chr_rom_size = rom_size("CHR-ROM", header[5], header[9] >> 4, VBANK_SIZE)?;
+      ret.prg_ram_size = ram_size(header[10] & 0xF);
+      ret.prg_nvram_size = ram_size(header[10] >> 4);
+      ret.chr_ram_size = ram_size(header[11] & 0xF);
+      ret.chr_nvram_size = ram_size(header[11] >> 4);
+      ret.timing = match header[12] & 0x3 {
+        0 => TimingMode::Ntsc,
+        1 => TimingMode::Pal,
+        2 => TimingMode::MultiRegion,
+        _ => TimingMode::Dendy,
+      };
+      ret.misc_roms = header[14] & 0x3;
+      ret.expansion_device = header[15] & 0x3F;
+    } else {
+      // Old dumps often carry garbage such as "DiskDude!" in bytes 7-15,
+      // only trust the upper mapper nibble when the tail is zeroed.
+      let dirty = header[12..16].iter().any(|b| *b != 0);
+      ret.mapper = (header[6] >> 4) as u16;
+      if !dirty {
+        ret.mapper |= (header[7] & 0xF0) as u16;
+      }
+      ret.prg_rom_size = BANK_SIZE * header[4] as usize;
+      ret.chr_rom_size = VBANK_SIZE * header[5] as usize;
+      // A value of 0 infers 8KB for compatibility.
+      ret.prg_ram_size = VBANK_SIZE * std::cmp::max(header[8], 1) as usize;
+      if ret.chr_rom_size == 0 {
+        ret.chr_ram_size = VBANK_SIZE;
+      }
+      if !dirty && bit_eq(header[9], 0x1) {
+        ret.timing = TimingMode::Pal;
+      }
+    }
+    Ok(ret)
+  }
+}
+
+// Reads exactly `size` bytes of one section of the image into `buf`. The size comes
+// from the header, so the buffer only grows with the data actually read.
+fn read_section<T: Read>(
+  reader: &mut BufReader<T>,
+  section: &'static str,
+  size: usize,
+  buf: &mut Vec<Byte>,
+) -> NesResult<()> {
+  reader.by_ref().take(size as u64).read_to_end(buf)?;
+  if buf.len() < size {
+    error!("{} has {} bytes rather than {}", section, buf.len(), size);
+    return Err(
+      LoadError::Truncated {
+        section,
+        expected: size,
+        actual: buf.len(),
+      }
+      .into(),
+    );
+  }
+  Ok(())
+}
+
+// Size field of NES 2.0, the MSB nibble 0xF switches to exponent-multiplier form.
+fn rom_size(section: &str, lsb: Byte, msb: Byte, unit: usize) -> Result<usize, LoadError> {
+  if msb == 0xF {
+    let exponent = (lsb >> 2) as u32;
+    let multiplier = (lsb & 0x3) as usize * 2 + 1;
+    1usize
+      .checked_shl(exponent)
+      .and_then(|size| size.checked_mul(multiplier))
+      .ok_or_else(|| {
+        let size = format!("{} of 2^{} * {} bytes", section, exponent, multiplier);
+        LoadError::UnsupportedFeature(size)
+      })
+  } else {
+    Ok((((msb as usize) << 8) | lsb as usize) * unit)
+  }
+}
+
+// Shift count field of NES 2.0, 0 means no RAM at all.
+fn ram_size(shift: Byte) -> usize {
+  if shift == 0 {
+    0
+  } else {
+    64 << shift
+  }
+}
 
 /**
  * This struct represents a iNES cartridge
  * use to load iNES ROM, 
  * and provide rom/ram data for mapper implementation
  */
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Cartridge {
+  // ROM contents are left out of savestates, see `attach_rom`.
+  #[serde(skip)]
   prg_rom: Vec<Byte>,
+  #[serde(skip)]
   chr_rom: Vec<Byte>,
-  name_table_mirroring: Byte,
-  mapper_number: Byte,
-  extended_ram: bool,
+  header: NesHeader,
+  // copied into $7000 at power on, never saved.
+  #[serde(skip)]
+  trainer: Option<Vec<Byte>>,
 }
 
 impl Cartridge {
@@ -30,112 +236,81 @@ impl Cartridge {
     Self {
       prg_rom: vec![],
       chr_rom: vec![],
-      name_table_mirroring: 0,
-      mapper_number: 0,
-      extended_ram: false,
+      header: NesHeader::default(),
+      trainer: None,
     }
   }
 
-  fn read_header(&mut self, header: &[u8]) -> Option<(Byte, Byte)> {
-    let header_str = std::str::from_utf8(header).expect("error when parse header to str");
-    if !header_str.starts_with("NES\x1A") {
-      error!(
-        "Not a valid iNES image. Magic number: {:#x}{:#x}{:#x}{:#x} rather than NES1a",
-        header[0], header[1], header[2], header[3]
-      );
-      return None;
-    }
-    let banks = header[4];
-    if banks == 0 {
+  fn read_header(&mut self, header: &[u8]) -> Result<(usize, usize), LoadError> {
+    let parsed = NesHeader::parse(header)?;
+    if parsed.prg_rom_size == 0 {
       error!("ROM has no PRG-ROM banks. Loading ROM failed.");
-      return None;
+      return Err(LoadError::UnsupportedFeature("ROM without PRG-ROM".to_string()));
     }
-    let vbanks = header[5];
-
-    self.name_table_mirroring = header[6] & 0xB;
-    self.mapper_number = ((header[6] >> 4) & 0xF) | (header[7] & 0xF0);
-    self.extended_ram = bit_eq(header[6], 0x2);
     info!(
-      "Load header finished. 16KB PRG-ROM Banks: {}, 8KB CHR-ROM Banks: {}",
-      banks, vbanks
+      "Load {} header finished. PRG-ROM: {}KB, CHR-ROM: {}KB",
+      if parsed.nes2 { "NES 2.0" } else { "iNES" },
+      parsed.prg_rom_size / 1024,
+      parsed.chr_rom_size / 1024
     );
     info!(
-      "Name Table Mirroring: {}, Mapper: {}, Extended (CPU) RAM: {}",
-      self.name_table_mirroring, self.mapper_number, self.extended_ram
+      "Name Table Mirroring: {}, Mapper: {}.{}, Battery: {}",
+      parsed.name_table_mirroring, parsed.mapper, parsed.submapper, parsed.battery
     );
-    if bit_eq(header[6], 0x4) {
-      error!("Trainer is not supported.");
-      return None;
-    }
-
-    if (header[0xA] & 0x3) != 0 {
-      error!("PAL ROM not supported.");
-      return None;
-    } else {
-      info!("ROM is NSTC compatible.");
+    if parsed.nes2 {
+      info!(
+        "PRG-RAM: {}B, PRG-NVRAM: {}B, CHR-RAM: {}B, CHR-NVRAM: {}B, Console: {:?}, Expansion: {:#x}",
+        parsed.prg_ram_size,
+        parsed.prg_nvram_size,
+        parsed.chr_ram_size,
+        parsed.chr_nvram_size,
+        parsed.console_type,
+        parsed.expansion_device
+      );
     }
+    info!("Timing: {:?}", parsed.timing);
 
-    return Some((banks, vbanks));
+    let sizes = (parsed.prg_rom_size, parsed.chr_rom_size);
+    self.header = parsed;
+    Ok(sizes)
   }
 
-  pub fn load_from_data(&mut self, data: &[u8]) -> bool {
+  pub fn load_from_data(&mut self, data: &[u8]) -> NesResult<()> {
     let reader = BufReader::new(data);
     self.load_from_buf(reader)
   }
 
-  pub fn load_from_file(&mut self, path_str: &str) -> bool {
+  pub fn load_from_file(&mut self, path_str: &str) -> NesResult<()> {
     info!("Reading ROM content from {}", path_str);
     let path = Path::new(path_str);
-    let rom_file_result = File::open(path);
-    if let Err(_) = rom_file_result {
-      error!("Can't the open ROM file {}", path_str);
-      return false;
-    }
-    let file_reader = BufReader::new(rom_file_result.unwrap());
-    self.load_from_buf(file_reader)
+    let rom_file = File::open(path).with_context(|| format!("can't open ROM file {}", path_str))?;
+    self.load_from_buf(BufReader::new(rom_file))
   }
 
-  fn load_from_buf<T>(&mut self, mut reader: BufReader<T>) -> bool
+  fn load_from_buf<T>(&mut self, mut reader: BufReader<T>) -> NesResult<()>
   where
     T: std::io::Read,
   {
     let mut header = Vec::with_capacity(0x10);
-    reader
-      .by_ref()
-      .take(0x10)
-      .read_to_end(&mut header)
-      .expect("Read ROM file failed");
-    let header_res =  self.read_header(&mut header);
-    if header_res.is_none() {
-      return false;
-    }
-    let (banks, vbanks) = header_res.unwrap();
-
-    // Read prg_rom
-    let prg_rom_size = BANK_SIZE * banks as usize;
-    self.prg_rom.reserve(prg_rom_size);
-    if let Err(e) = reader
-      .by_ref()
-      .take(prg_rom_size as u64)
-      .read_to_end(&mut self.prg_rom)
-    {
-      error!("Read ROM file failed {}", e);
-      return false;
+    reader.by_ref().take(0x10).read_to_end(&mut header)?;
+    let (prg_rom_size, chr_rom_size) = self.read_header(&header)?;
+
+    // Read trainer, it sits between the header and PRG-ROM.
+    if self.header.trainer {
+      let mut trainer = Vec::with_capacity(TRAINER_SIZE);
+      read_section(&mut reader, "trainer", TRAINER_SIZE, &mut trainer)?;
+      info!("Cartridge with {} bytes trainer", trainer.len());
+      self.trainer = Some(trainer);
     }
 
-    // Read chr_rom
-    if vbanks != 0 {
-      let chr_rom_size = VBANK_SIZE * vbanks as usize;
-      self.chr_rom.reserve(chr_rom_size as usize);
-      if let Err(e) = reader.take(chr_rom_size as u64).read_to_end(&mut self.chr_rom) {
-        error!("Read ROM file failed {}", e);
-        return false;
-      }
+    read_section(&mut reader, "PRG-ROM", prg_rom_size, &mut self.prg_rom)?;
+    if chr_rom_size != 0 {
+      read_section(&mut reader, "CHR-ROM", chr_rom_size, &mut self.chr_rom)?;
     } else {
       info!("Cartridge with CHR-RAM");
     }
-    info!("Mapper type : {:#x}", self.mapper_number);
-    true
+    info!("Mapper type : {:#x}", self.header.mapper);
+    Ok(())
   }
 
   pub fn get_rom(&self) -> &Vec<Byte> {
@@ -146,16 +321,66 @@ impl Cartridge {
     &self.chr_rom
   }
 
-  pub fn get_mapper(&self) -> Byte {
-    return self.mapper_number;
+  /// Copy the ROM contents of `rom` into a cartridge restored from a
+  /// savestate.
+  pub fn attach_rom(&mut self, rom: &Cartridge) {
+    self.prg_rom.clone_from(&rom.prg_rom);
+    self.chr_rom.clone_from(&rom.chr_rom);
+    self.trainer.clone_from(&rom.trainer);
+  }
+
+  pub fn header(&self) -> &NesHeader {
+    &self.header
+  }
+
+  /// Trainer to be copied to $7000-$71FF at power on.
+  pub fn trainer(&self) -> Option<&[Byte]> {
+    self.trainer.as_deref()
+  }
+
+  pub fn get_mapper(&self) -> u16 {
+    return self.header.mapper;
   }
 
   pub fn get_name_table_mirroring(&self) -> Byte {
-    return self.name_table_mirroring;
+    return self.header.name_table_mirroring;
   }
 
   pub fn has_extended_ram(&self) -> bool {
-    return self.extended_ram;
+    if self.header.nes2 {
+      return self.header.prg_ram_size + self.header.prg_nvram_size > 0;
+    }
+    return self.header.battery;
+  }
+
+  /// SHA-1 of PRG-ROM followed by CHR-ROM, identifies the game.
+  pub fn sha1(&self) -> [u8; 20] {
+    let mut hasher = sha1_smol::Sha1::new();
+    hasher.update(&self.prg_rom);
+    hasher.update(&self.chr_rom);
+    hasher.digest().bytes()
+  }
+
+  /// MD5 of PRG-ROM followed by CHR-ROM, the checksum FCEUX movies use.
+  pub fn md5(&self) -> [u8; 16] {
+    let mut context = md5::Context::new();
+    context.consume(&self.prg_rom);
+    context.consume(&self.chr_rom);
+    context.compute().0
+  }
+
+  pub fn has_battery(&self) -> bool {
+    self.header.battery
+  }
+
+  /// Size of CHR-RAM the board carries, at least 8KB when there is no CHR-ROM.
+  pub fn chr_ram_size(&self) -> usize {
+    let size = self.header.chr_ram_size + self.header.chr_nvram_size;
+    if self.chr_rom.is_empty() {
+      std::cmp::max(size, VBANK_SIZE)
+    } else {
+      size
+    }
   }
 }
 
@@ -168,12 +393,91 @@ mod tests {
       Ok(_) => {}
     };
     let mut cart = crate::cartridge::Cartridge::new();
-    assert!(cart.load_from_file("assets/mario.nes"));
+    cart.load_from_file("assets/mario.nes").unwrap();
     println!("{}", cart.has_extended_ram());
   }
   fn modify_vec(vec: &mut Vec<u8>) {
     vec.push(1);
   }
+  #[test]
+  fn nes2_header_test() {
+    use crate::cartridge::{ConsoleType, LoadError, NesHeader, TimingMode};
+    let mut header = *b"NES\x1A\x10\x00\x12\x48\x51\x00\x70\x07\x03\x00\x00\x01";
+    let parsed = NesHeader::parse(&header).unwrap();
+    assert!(parsed.nes2);
+    assert!(parsed.battery);
+    assert_eq!(parsed.mapper, 0x141);
+    assert_eq!(parsed.submapper, 5);
+    assert_eq!(parsed.prg_rom_size, 256 * 1024);
+    assert_eq!(parsed.chr_rom_size, 0);
+    assert_eq!(parsed.prg_ram_size, 0);
+    assert_eq!(parsed.prg_nvram_size, 8 * 1024);
+    assert_eq!(parsed.chr_ram_size, 8 * 1024);
+    assert_eq!(parsed.timing, TimingMode::Dendy);
+    assert_eq!(parsed.console_type, ConsoleType::Nes);
+    assert_eq!(parsed.expansion_device, 1);
+
+    // exponent-multiplier form: 2^4 * 3
+    header[9] = 0x0F;
+    header[4] = 0x11;
+    assert_eq!(NesHeader::parse(&header).unwrap().prg_rom_size, 48);
+
+    header[0] = b'X';
+    assert_eq!(NesHeader::parse(&header).unwrap_err(), LoadError::BadMagic);
+  }
+
+  #[test]
+  fn ines_header_test() {
+    use crate::cartridge::{NesHeader, TimingMode};
+    let header = *b"NES\x1A\x02\x01\x31\x40\x00\x00\x00DiskD";
+    let parsed = NesHeader::parse(&header[..16]).unwrap();
+    assert!(!parsed.nes2);
+    // upper nibble ignored for dirty headers
+    assert_eq!(parsed.mapper, 3);
+    assert_eq!(parsed.name_table_mirroring, 1);
+    assert_eq!(parsed.prg_rom_size, 32 * 1024);
+    assert_eq!(parsed.chr_rom_size, 8 * 1024);
+    assert_eq!(parsed.prg_ram_size, 8 * 1024);
+    assert_eq!(parsed.timing, TimingMode::Ntsc);
+  }
+
+  #[test]
+  fn truncated_test() {
+    use crate::cartridge::{Cartridge, LoadError};
+    // 32KB PRG and 8KB CHR declared, CHR cut short
+    let mut rom = b"NES\x1A\x02\x01\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00".to_vec();
+    rom.resize(16 + 0x8000 + 0x100, 0);
+    let err = Cartridge::new().load_from_data(&rom).unwrap_err();
+    assert_eq!(
+      err.downcast_ref::<LoadError>(),
+      Some(&LoadError::Truncated {
+        section: "CHR-ROM",
+        expected: 0x2000,
+        actual: 0x100
+      })
+    );
+    let err = Cartridge::new().load_from_data(b"NES").unwrap_err();
+    assert_eq!(err.downcast_ref::<LoadError>(), Some(&LoadError::BadMagic));
+    // NES 2.0 exponent form, 2^63 * 7 bytes of PRG-ROM
+    let rom = b"NES\x1A\xFF\x00\x00\x08\x00\x0F\x00\x00\x00\x00\x00\x00";
+    let err = Cartridge::new().load_from_data(rom).unwrap_err();
+    assert!(matches!(
+      err.downcast_ref::<LoadError>(),
+      Some(LoadError::UnsupportedFeature(_))
+    ));
+    // 2^62 bytes fits in usize but not in the image
+    let rom = b"NES\x1A\xF8\x00\x00\x08\x00\x0F\x00\x00\x00\x00\x00\x00";
+    let err = Cartridge::new().load_from_data(rom).unwrap_err();
+    assert_eq!(
+      err.downcast_ref::<LoadError>(),
+      Some(&LoadError::Truncated {
+        section: "PRG-ROM",
+        expected: 1 << 62,
+        actual: 0
+      })
+    );
+  }
+
   #[test]
   fn vec_test() {
     let mut cart = crate::cartridge::Cartridge::new();
@@ -0,0 +1,208 @@
+//! Game Genie codes and RAM freezes
+use std::path::Path;
+
+use anyhow::{anyhow, bail};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+  common::{Address, Byte},
+  NesResult,
+};
+
+const GENIE_LETTERS: &str = "APZLGITYEOXUKSVN";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CheatEffect {
+  /// Substitute a PRG read at $8000-$FFFF, only if the original value
+  /// matches `compare` when it is set.
+  Genie {
+    address: Address,
+    value: Byte,
+    compare: Option<Byte>,
+  },
+  /// Write a value into RAM every frame.
+  Freeze { address: Address, value: Byte },
+}
+
+impl CheatEffect {
+  /// Parse a Game Genie code (`SXIOPO`) or a Pro Action Replay style RAM
+  /// code (`075A:09` or `075A09`).
+  pub fn parse(code: &str) -> NesResult<Self> {
+    let code = code.trim().to_ascii_uppercase();
+    if (code.len() == 6 || code.len() == 8) && code.chars().all(|c| GENIE_LETTERS.contains(c)) {
+      return Ok(decode_genie(&code));
+    }
+    let hex: String = code.chars().filter(|c| *c != ':').collect();
+    if hex.len() != 6 {
+      bail!("invalid cheat code {}", code);
+    }
+    let address = Address::from_str_radix(&hex[..4], 16)
+      .map_err(|_| anyhow!("invalid cheat address in {}", code))?;
+    let value =
+      Byte::from_str_radix(&hex[4..], 16).map_err(|_| anyhow!("invalid cheat value in {}", code))?;
+    match address {
+      0x0000..=0x1FFF | 0x6000..=0x7FFF => Ok(CheatEffect::Freeze { address, value }),
+      _ => bail!("can only freeze RAM, {:#06x} is not RAM", address),
+    }
+  }
+}
+
+fn decode_genie(code: &str) -> CheatEffect {
+  let n: Vec<u16> = code
+    .chars()
+    .map(|c| GENIE_LETTERS.find(c).unwrap() as u16)
+    .collect();
+  let address = 0x8000
+    + (((n[3] & 7) << 12)
+      | ((n[5] & 7) << 8)
+      | ((n[4] & 8) << 8)
+      | ((n[2] & 7) << 4)
+      | ((n[1] & 8) << 4)
+      | (n[4] & 7)
+      | (n[3] & 8));
+  let (value, compare) = if n.len() == 6 {
+    let value = ((n[1] & 7) << 4) | ((n[0] & 8) << 4) | (n[0] & 7) | (n[5] & 8);
+    (value, None)
+  } else {
+    let value = ((n[1] & 7) << 4) | ((n[0] & 8) << 4) | (n[0] & 7) | (n[7] & 8);
+    let compare = ((n[7] & 7) << 4) | ((n[6] & 8) << 4) | (n[6] & 7) | (n[5] & 8);
+    (value, Some(compare as Byte))
+  };
+  CheatEffect::Genie {
+    address,
+    value: value as Byte,
+    compare,
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Cheat {
+  pub name: String,
+  pub code: String,
+  pub enabled: bool,
+}
+
+impl Cheat {
+  pub fn effect(&self) -> NesResult<CheatEffect> {
+    CheatEffect::parse(&self.code)
+  }
+}
+
+/// Cheats of one game, persisted as JSON.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct CheatList {
+  pub cheats: Vec<Cheat>,
+}
+
+impl CheatList {
+  pub fn load(path: &Path) -> NesResult<Self> {
+    let list: CheatList = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+    Ok(CheatList {
+      cheats: list
+        .cheats
+        .into_iter()
+        .filter(|cheat| match cheat.effect() {
+          Ok(_) => true,
+          Err(e) => {
+            log::warn!("drop cheat {}: {}", cheat.code, e);
+            false
+          }
+        })
+        .collect(),
+    })
+  }
+
+  pub fn save(&self, path: &Path) -> NesResult<()> {
+    if let Some(dir) = path.parent() {
+      std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(path, serde_json::to_string_pretty(self)?)?;
+    Ok(())
+  }
+
+  /// Add a cheat or enable it again if the code is already known.
+  pub fn add(&mut self, code: &str, name: &str) -> NesResult<usize> {
+    CheatEffect::parse(code)?;
+    let code = code.trim().to_ascii_uppercase();
+    if let Some(index) = self.cheats.iter().position(|c| c.code == code) {
+      self.cheats[index].enabled = true;
+      return Ok(index);
+    }
+    self.cheats.push(Cheat {
+      name: name.to_string(),
+      code,
+      enabled: true,
+    });
+    Ok(self.cheats.len() - 1)
+  }
+
+  pub fn enabled_effects(&self) -> Vec<CheatEffect> {
+    self
+      .cheats
+      .iter()
+      .filter(|cheat| cheat.enabled)
+      .filter_map(|cheat| cheat.effect().ok())
+      .collect()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::console::Console;
+
+  #[test]
+  fn genie_test() {
+    // Super Mario Bros. infinite lives
+    assert_eq!(
+      CheatEffect::parse("SXIOPO").unwrap(),
+      CheatEffect::Genie {
+        address: 0x91D9,
+        value: 0xAD,
+        compare: None
+      }
+    );
+    match CheatEffect::parse("yeuzugaa").unwrap() {
+      CheatEffect::Genie { compare, .. } => assert!(compare.is_some()),
+      _ => panic!("expect a game genie code"),
+    }
+    assert_eq!(
+      CheatEffect::parse("075A:09").unwrap(),
+      CheatEffect::Freeze {
+        address: 0x075A,
+        value: 0x09
+      }
+    );
+    assert!(CheatEffect::parse("8000:01").is_err());
+    assert!(CheatEffect::parse("hello").is_err());
+  }
+
+  #[test]
+  fn cheat_test() {
+    let rom = std::fs::read("assets/mario.nes").unwrap();
+    let mut console = Console::new(&rom).unwrap();
+    let nanos = std::time::UNIX_EPOCH.elapsed().unwrap().as_nanos();
+    let name = format!("cheat_test_{}_{}.json", std::process::id(), nanos);
+    let path = std::env::temp_dir().join(name);
+    let instance = console.instance_mut();
+    instance.set_cheat_path(&path).unwrap();
+    instance.add_cheat("0700:5A", "freeze").unwrap();
+    instance.add_cheat("SXIOPO", "lives").unwrap();
+    console.run_frame();
+    let read = |console: &Console, addr| {
+      console.instance().cpu.lock().unwrap().main_bus().save_read(addr)
+    };
+    assert_eq!(read(&console, 0x0700), 0x5A);
+    assert_eq!(read(&console, 0x91D9), 0xAD);
+
+    console.instance_mut().set_cheat_enabled(1, false).unwrap();
+    assert_ne!(read(&console, 0x91D9), 0xAD);
+    console.power_cycle().unwrap();
+    assert_eq!(console.instance().cheats().len(), 2);
+    console.run_frame();
+    assert_eq!(read(&console, 0x0700), 0x5A);
+    let saved = CheatList::load(&path).unwrap();
+    assert_eq!(saved.cheats, console.instance().cheats());
+    std::fs::remove_file(&path).unwrap();
+  }
+}
@@ -2,6 +2,7 @@ pub type Byte = u8;
 pub type Address = u16;
 
 pub mod instant;
+pub mod region;
 
 #[inline]
 pub fn bit_eq<T: std::ops::BitAndAssign + PartialEq + Copy>(a: T, b: T) -> bool {
@@ -0,0 +1,121 @@
+use std::str::FromStr;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::cartridge::TimingMode;
+
+/// TV system of the console, drives CPU/PPU/APU timing and frame pacing.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Region {
+  #[default]
+  Ntsc,
+  Pal,
+  Dendy,
+}
+
+impl Region {
+  pub fn cpu_frequency(&self) -> u32 {
+    match self {
+      Region::Ntsc => 1789773,
+      Region::Pal => 1662607,
+      Region::Dendy => 1773448,
+    }
+  }
+
+  pub fn frame_rate(&self) -> f64 {
+    match self {
+      Region::Ntsc => 60.0988,
+      Region::Pal | Region::Dendy => 50.0070,
+    }
+  }
+
+  pub fn frame_duration(&self) -> Duration {
+    Duration::from_secs_f64(1.0 / self.frame_rate())
+  }
+
+  pub fn cpu_cycle_duration(&self) -> Duration {
+    Duration::from_secs_f64(1.0 / self.cpu_frequency() as f64)
+  }
+
+  /// CPU cycles between two quarter frame clocks of the APU frame counter.
+  pub fn frame_counter_rate(&self) -> f64 {
+    match self {
+      // Dendy keeps the NTSC APU sequencer.
+      Region::Ntsc | Region::Dendy => 1789773.0 / 240.0,
+      Region::Pal => 8313.0,
+    }
+  }
+
+  /// Scanline of the pre-render line, 262 lines for NTSC and 312 otherwise.
+  pub fn frame_end_scanline(&self) -> usize {
+    match self {
+      Region::Ntsc => 261,
+      Region::Pal | Region::Dendy => 311,
+    }
+  }
+
+  /// Scanline where the vblank flag is set and NMI fires.
+  pub fn vblank_scanline(&self) -> usize {
+    match self {
+      Region::Ntsc | Region::Pal => 241,
+      // Dendy has 51 post-render lines before vblank.
+      Region::Dendy => 291,
+    }
+  }
+
+  /// Whether the pre-render line is one dot shorter on odd frames.
+  pub fn skip_odd_frame_dot(&self) -> bool {
+    *self == Region::Ntsc
+  }
+
+  /// Convert CPU cycles into PPU dots, PAL runs 3.2 dots per cycle so the
+  /// fractional part is kept in `carry` (in fifths of a dot).
+  pub fn ppu_dots(&self, cycles: u32, carry: &mut u32) -> u32 {
+    match self {
+      Region::Ntsc | Region::Dendy => cycles * 3,
+      Region::Pal => {
+        let fifths = cycles * 16 + *carry;
+        *carry = fifths % 5;
+        fifths / 5
+      }
+    }
+  }
+}
+
+impl From<TimingMode> for Region {
+  fn from(timing: TimingMode) -> Self {
+    match timing {
+      TimingMode::Pal => Region::Pal,
+      TimingMode::Dendy => Region::Dendy,
+      TimingMode::Ntsc | TimingMode::MultiRegion => Region::Ntsc,
+    }
+  }
+}
+
+impl FromStr for Region {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s.to_ascii_lowercase().as_str() {
+      "ntsc" => Ok(Region::Ntsc),
+      "pal" => Ok(Region::Pal),
+      "dendy" => Ok(Region::Dendy),
+      _ => Err(format!("unknown region {}, expect ntsc, pal or dendy", s)),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::Region;
+
+  #[test]
+  fn pal_dot_ratio_test() {
+    let mut carry = 0;
+    let dots: u32 = (0..5).map(|_| Region::Pal.ppu_dots(1, &mut carry)).sum();
+    assert_eq!(dots, 16);
+    assert_eq!(carry, 0);
+    assert_eq!(Region::Ntsc.ppu_dots(7, &mut carry), 21);
+  }
+}
@@ -0,0 +1,186 @@
+
+use crate::{
+  common::region::Region,
+  emulator::RuntimeConfig,
+  instance::{FrameBuffer, Instance},
+  ppu::{SCANLINE_VISIBLE_DOTS, VISIBLE_SCANLINES},
+  NesResult,
+};
+
+/**
+ * Frontend agnostic emulator core, needs no window, keyboard or audio device.
+ *
+ * Buttons of a controller are one byte, A, B, Select, Start, Up, Down, Left,
+ * Right from bit 0 to bit 7. The framebuffer is 256x240 RGBA.
+ */
+pub struct Console {
+  instance: Instance,
+  frame: FrameBuffer,
+}
+
+impl Console {
+  pub fn new(rom: &[u8]) -> NesResult<Self> {
+    Self::with_config(rom, RuntimeConfig::default())
+  }
+
+  pub fn with_region(rom: &[u8], region: Region) -> NesResult<Self> {
+    Self::with_config(
+      rom,
+      RuntimeConfig {
+        region: Some(region),
+        ..RuntimeConfig::default()
+      },
+    )
+  }
+
+  pub fn with_config(rom: &[u8], runtime_config: RuntimeConfig) -> NesResult<Self> {
+    let instance = Instance::init_rom_from_data(rom, &runtime_config)?;
+    Ok(Self {
+      instance,
+      frame: FrameBuffer::new(SCANLINE_VISIBLE_DOTS as u32, VISIBLE_SCANLINES as u32),
+    })
+  }
+
+  pub fn region(&self) -> Region {
+    self.instance.region()
+  }
+
+  /// Emulate until the next frame is rendered.
+  pub fn run_frame(&mut self) {
+    if let Some(frame) = self.instance.run_frame() {
+      self.frame = frame;
+    }
+    self.instance.tick_battery();
+  }
+
+  pub fn set_buttons(&mut self, port: usize, buttons: u8) {
+    self.instance.set_buttons(port, buttons);
+  }
+
+  /// RGBA pixels of the last rendered frame.
+  pub fn framebuffer(&self) -> &[u8] {
+    &self.frame
+  }
+
+  /// Audio samples produced since the last call, mono f32.
+  pub fn audio_samples(&mut self) -> Vec<f32> {
+    self.instance.take_samples()
+  }
+
+  pub fn reset(&mut self) {
+    self.instance.reset();
+  }
+
+  /// Switch the console off and on again, battery RAM survives like on the
+  /// hardware.
+  pub fn power_cycle(&mut self) -> NesResult<()> {
+    self.instance.power_cycle()?;
+    self.frame = FrameBuffer::new(SCANLINE_VISIBLE_DOTS as u32, VISIBLE_SCANLINES as u32);
+    Ok(())
+  }
+
+  pub fn instance(&self) -> &Instance {
+    &self.instance
+  }
+
+  pub fn instance_mut(&mut self) -> &mut Instance {
+    &mut self.instance
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::Console;
+  use crate::movie::Movie;
+
+  #[test]
+  fn headless_run_test() {
+    let rom = std::fs::read("assets/mario.nes").unwrap();
+    let mut console = Console::new(&rom).unwrap();
+    for _ in 0..60 {
+      console.run_frame();
+    }
+    assert_eq!(console.framebuffer().len(), 256 * 240 * 4);
+    assert!(console.framebuffer().chunks(4).any(|p| p[..3] != [0, 0, 0]));
+    assert!(!console.audio_samples().is_empty());
+
+    // press start
+    console.set_buttons(0, 0x08);
+    console.run_frame();
+    console.set_buttons(0, 0);
+    console.reset();
+    console.run_frame();
+    console.power_cycle().unwrap();
+    console.run_frame();
+  }
+
+  #[test]
+  fn rewind_test() {
+    let rom = std::fs::read("assets/mario.nes").unwrap();
+    let mut console = Console::new(&rom).unwrap();
+    console.instance_mut().set_rewind(1, 4);
+    for _ in 0..10 {
+      console.run_frame();
+    }
+    let instance = console.instance_mut();
+    instance.set_rewinding(true);
+    for _ in 0..4 {
+      assert!(instance.rewind_frame());
+    }
+    assert!(!instance.rewind_frame());
+    instance.set_rewinding(false);
+  }
+
+  #[test]
+  fn rewind_movie_test() {
+    let rom = std::fs::read("assets/mario.nes").unwrap();
+    let mut console = Console::new(&rom).unwrap();
+    console.instance_mut().set_rewind(1, 4);
+    console.instance_mut().start_recording(true).unwrap();
+    for _ in 0..10 {
+      console.run_frame();
+    }
+    let instance = console.instance_mut();
+    instance.set_rewinding(true);
+    assert!(!instance.rewind_frame());
+    instance.set_rewinding(false);
+    let err = instance.load(&"no_such_state".to_string()).unwrap_err();
+    assert!(err.to_string().contains("movie"));
+    // the latch of start_recording plus one per frame
+    assert_eq!(instance.stop_movie().unwrap().frames.len(), 11);
+  }
+
+  #[test]
+  fn movie_replay_test() {
+    let rom = std::fs::read("assets/mario.nes").unwrap();
+    let mut console = Console::new(&rom).unwrap();
+    console.instance_mut().set_rewind(1, 0);
+    // recording and replaying both start from power on
+    for _ in 0..30 {
+      console.run_frame();
+    }
+    console.instance_mut().start_recording(false).unwrap();
+    for i in 0..120 {
+      // tap start now and then
+      console.set_buttons(0, if i % 40 < 5 { 0x08 } else { 0 });
+      if i == 90 {
+        console.power_cycle().unwrap();
+      }
+      console.run_frame();
+    }
+    let expected = console.framebuffer().to_vec();
+    let movie = console.instance_mut().stop_movie().unwrap();
+    let movie = Movie::parse_fm2(&movie.to_fm2()).unwrap();
+
+    console.set_buttons(0, 0);
+    for _ in 0..30 {
+      console.run_frame();
+    }
+    console.instance_mut().set_rewind(1, 0);
+    console.instance_mut().play_movie(movie).unwrap();
+    for _ in 0..120 {
+      console.run_frame();
+    }
+    assert_eq!(console.framebuffer(), &expected[..]);
+  }
+}
@@ -0,0 +1,10 @@
+use super::{key_binding_parser::KeyType, Controller};
+
+/// No keyboard without a frontend, only buttons set by `Controller::set_buttons`.
+impl Controller {
+  pub(crate) fn update_keys(&mut self) {}
+
+  pub(crate) fn read_key(&self, _btn: &KeyType) -> bool {
+    false
+  }
+}
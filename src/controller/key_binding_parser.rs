@@ -9,12 +9,19 @@ pub type KeyType = sdl2::keyboard::Keycode;
 #[cfg(feature = "wasm")]
 pub type KeyType = usize;
 
+// headless build, keys are only indexes into `KEYBOARD_KEYS`
+#[cfg(not(any(feature = "use_gl", feature = "use_sdl2", feature = "wasm")))]
+pub type KeyType = usize;
+
 #[cfg(feature = "wasm")]
 pub const DEFAULT_KEY: KeyType = key_codes::A;
 
-#[cfg(not(feature = "wasm"))]
+#[cfg(any(feature = "use_gl", feature = "use_sdl2"))]
 pub const DEFAULT_KEY: KeyType = KeyType::A;
 
+#[cfg(not(any(feature = "use_gl", feature = "use_sdl2", feature = "wasm")))]
+pub const DEFAULT_KEY: KeyType = 0;
+
 pub const TOTAL_BUTTONS: usize = 8;
 const BUTTONS: &'static [&str] = &["a", "b", "select", "start", "up", "down", "left", "right"];
 const KEYBOARD_KEYS: &'static [&str] = &[
@@ -331,6 +338,17 @@ const KEYS: &'static [KeyType] = &[
   KeyType::Pause,
 ];
 
+#[cfg(not(any(feature = "use_gl", feature = "use_sdl2", feature = "wasm")))]
+const KEYS: &[KeyType] = &{
+  let mut keys = [0; KEYBOARD_KEYS.len()];
+  let mut i = 0;
+  while i < keys.len() {
+    keys[i] = i;
+    i += 1;
+  }
+  keys
+};
+
 #[cfg(feature = "wasm")]
 use wasm_rgame::key_codes;
 
@@ -441,7 +459,7 @@ const KEYS: &'static [KeyType] = &[
 
 fn parse_one_player(keys: &HashMap<String, Option<String>>) -> Vec<KeyType> {
   #[cfg(not(feature = "wasm"))]
-  let mut res = vec![KeyType::A; TOTAL_BUTTONS + 1];
+  let mut res = vec![DEFAULT_KEY; TOTAL_BUTTONS + 1];
   #[cfg(feature = "wasm")]
   let mut res = vec![0; TOTAL_BUTTONS + 1];
   for (k, v) in keys {
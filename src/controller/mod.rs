@@ -2,6 +2,8 @@ use crate::common::{bit_eq, Byte};
 
 pub mod key_binding_parser;
 
+#[cfg(not(feature = "wasm"))]
+use key_binding_parser::DEFAULT_KEY;
 use key_binding_parser::{KeyType, TOTAL_BUTTONS};
 #[cfg(feature = "wasm")]
 pub mod web_key;
@@ -15,6 +17,9 @@ pub mod gl_key;
 #[cfg(feature = "use_sdl2")]
 pub mod sdl2_key;
 
+#[cfg(not(any(feature = "use_gl", feature = "use_sdl2", feature = "wasm")))]
+mod headless_key;
+
 #[derive(Default)]
 pub struct Controller {
   enable_strobe: bool,
@@ -22,6 +27,10 @@ pub struct Controller {
   key_bindings: Vec<KeyType>,
   #[allow(dead_code)]
   enable_remote: bool,
+  // buttons pressed through the API, same bit order as `key_states`
+  buttons: u8,
+  // replaces keyboard and API buttons, used by movies
+  input_override: Option<u8>,
 }
 
 impl Controller {
@@ -30,10 +39,12 @@ impl Controller {
       enable_strobe: false,
       key_states: 0,
       #[cfg(not(feature = "wasm"))]
-      key_bindings: vec![KeyType::A; TOTAL_BUTTONS],
+      key_bindings: vec![DEFAULT_KEY; TOTAL_BUTTONS],
       #[cfg(feature = "wasm")]
       key_bindings: vec![0; TOTAL_BUTTONS],
       enable_remote: false,
+      buttons: 0,
+      input_override: None,
     }
   }
 
@@ -42,10 +53,12 @@ impl Controller {
       enable_strobe: false,
       key_states: 0,
       #[cfg(not(feature = "wasm"))]
-      key_bindings: vec![KeyType::A; TOTAL_BUTTONS],
+      key_bindings: vec![DEFAULT_KEY; TOTAL_BUTTONS],
       #[cfg(feature = "wasm")]
       key_bindings: vec![0; TOTAL_BUTTONS],
       enable_remote: true,
+      buttons: 0,
+      input_override: None,
     }
   }
 
@@ -53,17 +66,42 @@ impl Controller {
     self.key_bindings = keys;
   }
 
+  /// Set buttons from outside of the keyboard, bits are A, B, Select, Start,
+  /// Up, Down, Left, Right from bit 0 to bit 7.
+  pub fn set_buttons(&mut self, buttons: u8) {
+    self.buttons = buttons;
+  }
+
+  pub fn set_input_override(&mut self, buttons: Option<u8>) {
+    self.input_override = buttons;
+  }
+
+  /// Buttons currently held on the keyboard or set through the API.
+  pub fn poll_buttons(&mut self) -> u8 {
+    let key_states = self.key_states;
+    self.key_states = 0;
+    self.update_keys();
+    let buttons = self.key_states | self.buttons;
+    self.key_states = key_states;
+    buttons
+  }
+
   pub fn strobe(&mut self, b: Byte) {
     self.enable_strobe = bit_eq(b, 1);
     if !self.enable_strobe {
-      self.key_states = 0;
-      self.update_keys();
+      self.key_states = match self.input_override {
+        Some(buttons) => buttons,
+        None => self.poll_buttons(),
+      };
     }
   }
 
   pub fn read(&mut self) -> Byte {
     return if self.enable_strobe {
-      self.read_key(&self.key_bindings[0]) as u8 | 0x40
+      match self.input_override {
+        Some(buttons) => buttons & 1,
+        None => self.read_key(&self.key_bindings[0]) as u8 | self.buttons & 1,
+      }
     } else {
       let ret = self.key_states & 1;
       self.key_states >>= 1;
@@ -11,6 +11,7 @@ use super::{
 
 pub enum WebEvent {
   KeyDown(KeyType),
+  KeyUp(KeyType),
   Focus(bool),
 }
 
@@ -69,6 +70,10 @@ pub fn keyboard_listen() -> Result<(), JsValue> {
         .lock()
         .unwrap()
         .remove(&(event.key_code() as usize));
+      KEYBOARD_EVENTS
+        .lock()
+        .unwrap()
+        .push(WebEvent::KeyUp(event.key_code() as KeyType));
     }) as Box<dyn FnMut(_)>);
     window().add_event_listener_with_callback("keyup", closure.as_ref().unchecked_ref())?;
     closure.forget();
@@ -167,6 +167,14 @@ impl Cpu {
     self.interrupt = i_type;
   }
 
+  /// Poll a level triggered IRQ line, it keeps interrupting until the source
+  /// is acknowledged. A pending NMI or BRK takes precedence.
+  pub fn poll_irq(&mut self, line: bool) {
+    if line && self.interrupt == InterruptType::None {
+      self.trigger_interrupt(InterruptType::IRQ);
+    }
+  }
+
   pub fn interrupt(&mut self, i_type: InterruptType) {
     if i_type == InterruptType::BRK {
       self.r_pc += 1;
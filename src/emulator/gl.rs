@@ -1,11 +1,8 @@
-use std::thread;
-
-use log::{error, info};
+use std::{io, process::Command, thread};
 
 use super::{Emulator, RuntimeConfig};
 
 use crate::common::instant::Instant;
-use crate::emulator::FRAME_DURATION;
 use crate::instance::Instance;
 
 impl Emulator {
@@ -54,22 +51,31 @@ impl Emulator {
         }
       }
 
+      let frame_duration = instance.frame_duration();
       if instance.can_run() {
         instance.update_timer();
         let cost = self.one_frame(&mut instance);
-        if FRAME_DURATION > cost {
-          thread::sleep(FRAME_DURATION - cost);
+        if frame_duration > cost {
+          thread::sleep(frame_duration - cost);
         }
       } else {
-        thread::sleep(FRAME_DURATION);
+        thread::sleep(frame_duration);
       }
     }
     instance.stop();
   }
 
+  /// Shows `message` in a desktop dialog, usable before the window exists.
+  pub fn show_error(&self, title: &str, message: &str) {
+    log::error!("{}: {}", title, message);
+    if let Err(e) = error_dialog(title, message) {
+      log::error!("show error dialog failed: {}", e);
+    }
+  }
+
   fn handle_event(
     &mut self,
-    runtime_config: &RuntimeConfig,
+    _runtime_config: &RuntimeConfig,
     event: glfw::WindowEvent,
     instance: &mut Instance,
   ) -> bool {
@@ -86,17 +92,16 @@ impl Emulator {
           instance.stat.unfocus();
         }
       }
-      WindowEvent::Key(glfw::Key::Z, _, Action::Press, _) => {
-        instance.do_save(&runtime_config.save_path)
+      WindowEvent::Key(glfw::Key::Backspace, _, Action::Press, _) => instance.set_rewinding(true),
+      WindowEvent::Key(glfw::Key::Backspace, _, Action::Release, _) => {
+        instance.set_rewinding(false)
       }
-      WindowEvent::Key(glfw::Key::X, _, Action::Press, _) => {
-        match Instance::load(&runtime_config) {
-          Ok(instance_load) => {
-            *instance = instance_load;
-            info!("load success")
-          }
-          Err(e) => error!("load failed: {}", e),
-        }
+      WindowEvent::Key(glfw::Key::Z, _, Action::Press, _) => self.save_slot(instance),
+      WindowEvent::Key(glfw::Key::X, _, Action::Press, _) => self.load_slot(instance),
+      WindowEvent::Key(key, _, Action::Press, _)
+        if key as i32 >= glfw::Key::Num0 as i32 && key as i32 <= glfw::Key::Num9 as i32 =>
+      {
+        self.select_slot((key as i32 - glfw::Key::Num0 as i32) as u8)
       }
       WindowEvent::Key(glfw::Key::F2, _, Action::Press, _) => instance.toggle_pause(),
       WindowEvent::Key(glfw::Key::F3, _, Action::Press, _) => {
@@ -127,3 +132,38 @@ impl Emulator {
     true
   }
 }
+
+/**
+ * glfw has no message box, so ask the desktop for one: osascript on macOS,
+ * PowerShell on Windows and zenity elsewhere. Title and message are passed as
+ * arguments or environment, never spliced into a script.
+ */
+fn error_dialog(title: &str, message: &str) -> io::Result<()> {
+  let mut command;
+  if cfg!(target_os = "macos") {
+    command = Command::new("osascript");
+    command
+      .args(["-e", "on run argv"])
+      .args(["-e", "display alert (item 1 of argv) message (item 2 of argv) as critical"])
+      .args(["-e", "end run", title, message]);
+  } else if cfg!(target_os = "windows") {
+    command = Command::new("powershell");
+    command
+      .args(["-NoProfile", "-Command"])
+      .arg(
+        "Add-Type -AssemblyName PresentationFramework; \
+         [System.Windows.MessageBox]::Show($env:NES_ERROR_MESSAGE, $env:NES_ERROR_TITLE)",
+      )
+      .env("NES_ERROR_TITLE", title)
+      .env("NES_ERROR_MESSAGE", message);
+  } else {
+    command = Command::new("zenity");
+    command.args(["--error", "--no-markup", "--title", title, "--text", message]);
+  }
+  let status = command.status()?;
+  if status.success() {
+    Ok(())
+  } else {
+    Err(io::Error::other(format!("{:?} exited with {}", command, status)))
+  }
+}
@@ -1,5 +1,3 @@
-use core::time;
-
 #[cfg(feature = "use_sdl2")]
 mod sdl2;
 
@@ -9,25 +7,25 @@ mod gl;
 #[cfg(target_arch = "wasm32")]
 mod web;
 
+use std::path::{Path, PathBuf};
 use std::time::Duration;
 
 #[allow(unused_imports)]
 use log::{debug, info};
 
-use crate::apu::CPU_FREQUENCY;
 use crate::common::instant::Instant;
-use crate::controller::key_binding_parser::KeyType;
+use crate::common::region::Region;
+use crate::controller::key_binding_parser::{KeyType, DEFAULT_KEY, TOTAL_BUTTONS};
 use crate::instance::Instance;
+use crate::savestate::{self, SaveStatePreview};
 use crate::ppu::{SCANLINE_VISIBLE_DOTS, VISIBLE_SCANLINES};
+use crate::NesResult;
 
 const NES_VIDEO_WIDTH: u32 = (SCANLINE_VISIBLE_DOTS) as u32;
 const NES_VIDEO_HEIGHT: u32 = (VISIBLE_SCANLINES) as u32;
-const CPU_CYCLE_DURATION: Duration = Duration::from_nanos(559);
 
 pub const APP_NAME: &str = "NES-Simulator";
 
-const FRAME_DURATION: Duration = time::Duration::from_millis(16);
-
 #[derive(Clone)]
 pub struct RuntimeConfig {
   pub save_path: String,
@@ -35,9 +33,48 @@ pub struct RuntimeConfig {
 
   pub ctl1: Vec<KeyType>,
   pub ctl2: Vec<KeyType>,
+  /// Force a region instead of picking it from the ROM header.
+  pub region: Option<Region>,
 }
 
+impl Default for RuntimeConfig {
+  fn default() -> Self {
+    Self {
+      save_path: "save/saved.json".to_string(),
+      screen_scale: 2.0,
+      ctl1: vec![DEFAULT_KEY; TOTAL_BUTTONS + 1],
+      ctl2: vec![DEFAULT_KEY; TOTAL_BUTTONS + 1],
+      region: None,
+    }
+  }
+}
+
+/// Number of savestate slots, selected with the number keys.
+pub const SAVE_SLOTS: u8 = 10;
+
 impl RuntimeConfig {
+  /// Path of a savestate slot, `save/saved.json` becomes `save/saved.3.json`.
+  pub fn slot_path(&self, slot: u8) -> String {
+    let path = Path::new(&self.save_path);
+    let stem = path
+      .file_stem()
+      .map(|s| s.to_string_lossy().into_owned())
+      .unwrap_or_default();
+    let name = match path.extension() {
+      Some(ext) => format!("{}.{}.{}", stem, slot, ext.to_string_lossy()),
+      None => format!("{}.{}", stem, slot),
+    };
+    path.with_file_name(name).to_string_lossy().into_owned()
+  }
+
+  /// Cheat file of a ROM, `save/cheats/<sha1>.json` next to the savestates.
+  pub fn cheat_path(&self, rom_sha1: &[u8; 20]) -> PathBuf {
+    let hex: String = rom_sha1.iter().map(|b| format!("{:02x}", b)).collect();
+    Path::new(&self.save_path)
+      .with_file_name("cheats")
+      .join(format!("{}.json", hex))
+  }
+
   pub fn window_size(&self) -> (u32, u32) {
     let width = (NES_VIDEO_WIDTH as f32 * self.screen_scale) as u32;
     let height = (NES_VIDEO_HEIGHT as f32 * self.screen_scale) as u32;
@@ -47,6 +84,7 @@ impl RuntimeConfig {
 
 pub struct Emulator {
   runtime_config: RuntimeConfig,
+  slot: u8,
 }
 
 impl Emulator {
@@ -57,40 +95,96 @@ impl Emulator {
         screen_scale,
         ctl1,
         ctl2,
+        region: None,
       },
+      slot: 0,
+    }
+  }
+
+  pub fn set_region(&mut self, region: Option<Region>) {
+    self.runtime_config.region = region;
+  }
+
+  pub fn slot(&self) -> u8 {
+    self.slot
+  }
+
+  pub fn select_slot(&mut self, slot: u8) {
+    if slot >= SAVE_SLOTS {
+      log::warn!("invalid savestate slot {}", slot);
+      return;
     }
+    info!("select savestate slot {}", slot);
+    self.slot = slot;
+  }
+
+  pub fn save_slot(&self, instance: &Instance) {
+    instance.do_save(&self.runtime_config.slot_path(self.slot));
+  }
+
+  pub fn load_slot(&self, instance: &mut Instance) {
+    instance.do_load(&self.runtime_config.slot_path(self.slot));
+  }
+
+  /// Header and thumbnail of every slot, `None` for empty or broken slots.
+  pub fn slot_previews(&self) -> Vec<Option<SaveStatePreview>> {
+    (0..SAVE_SLOTS)
+      .map(|slot| {
+        let data = std::fs::read(self.runtime_config.slot_path(slot)).ok()?;
+        savestate::read_preview(&data).ok()
+      })
+      .collect()
   }
 
   fn one_frame(&mut self, instance: &mut Instance) -> Duration {
-    let mut iter_time = CPU_FREQUENCY;
+    if instance.is_rewinding() {
+      instance.update_timer();
+      instance.rewind_frame();
+      instance.elapsed_time = Duration::new(0, 0);
+      return Instant::now() - instance.cycle_timer;
+    }
+    let region = instance.region();
+    let cpu_frequency = region.cpu_frequency();
+    let frame_duration = region.frame_duration();
+    let cycle_duration = region.cpu_cycle_duration();
+    let mut iter_time = cpu_frequency;
 
     instance.update_timer();
-    // 1789773 / 1000 * 16
+    // cpu frequency / frame rate
+    let frame_cycles = (cpu_frequency as f64 / region.frame_rate()) as u32;
     let mut iters = 0;
     for i in 0.. {
       let cur_circle = instance.step();
       iters += cur_circle;
-      if i % 100 == 0 && Instant::now() - instance.cycle_timer > FRAME_DURATION {
+      if i % 100 == 0 && Instant::now() - instance.cycle_timer > frame_duration {
         iter_time = iters;
         break;
       }
-      let duration = CPU_CYCLE_DURATION * cur_circle;
-      if iters > CPU_FREQUENCY / 60 || instance.elapsed_time < duration {
+      let duration = cycle_duration * cur_circle;
+      if iters > frame_cycles || instance.elapsed_time < duration {
         iter_time = iters;
         break;
       }
     }
+    instance.tick_battery();
     let cost = Instant::now() - instance.cycle_timer;
     debug!("last frame toke {:?} for {} times.", cost, iter_time);
     cost
   }
 
-  pub fn create_instance(&self, rom_path: &str) -> Instance {
-    Instance::init_rom_from_path(rom_path, &self.runtime_config).expect("Failed to load rom.")
+  pub fn create_instance(&self, rom_path: &str) -> NesResult<Instance> {
+    Instance::init_rom_from_path(rom_path, &self.runtime_config)
+  }
+
+  pub fn create_instance_from_data(&self, rom_data: &[u8]) -> NesResult<Instance> {
+    Instance::init_rom_from_data(rom_data, &self.runtime_config)
   }
 
-  pub fn create_instance_from_data(&self, rom_data: &[u8]) -> Instance {
-    Instance::init_rom_from_data(rom_data, &self.runtime_config).expect("Failed to load rom.")
+  /// Tells the user about an error the emulator can't start with, desktop
+  /// frontends pop up a message box, others rely on the log.
+  #[cfg(not(any(feature = "use_sdl2", feature = "use_gl")))]
+  pub fn show_error(&self, title: &str, message: &str) {
+    log::error!("{}: {}", title, message);
   }
 
   #[cfg(not(any(feature = "use_sdl2", feature = "use_gl")))]
@@ -106,7 +200,7 @@ mod tests {
     path::Path,
   };
 
-  use crate::{controller, instance::Instance, logger};
+  use crate::{controller, instance::Instance, logger, savestate};
 
   use super::Emulator;
 
@@ -114,20 +208,31 @@ mod tests {
     let (p1_key, p2_key) =
       controller::key_binding_parser::parse_key_binding("assets/keybindings.ini");
     let emulator = Emulator::new(2.0, "tmp".to_string(), p1_key, p2_key);
-    let instance = emulator.create_instance("assets/mario.nes");
+    let instance = emulator.create_instance("assets/mario.nes").unwrap();
     (emulator, instance)
   }
 
+  #[test]
+  fn slot_path_test() {
+    let emulator = Emulator::new(2.0, "save/saved.json".to_string(), vec![], vec![]);
+    assert_eq!(emulator.runtime_config.slot_path(3), "save/saved.3.json");
+  }
+
   #[test]
   fn save_load_test() {
     match logger::init() {
       Err(_) => return,
       Ok(_) => {}
     };
-    let (emulator, instance) = create_dummy_emulator();
+    let (_emulator, mut instance) = create_dummy_emulator();
     instance.save(&"tmp".to_string()).unwrap();
 
-    Instance::load(&emulator.runtime_config).unwrap();
+    instance.load(&"tmp".to_string()).unwrap();
     fs::remove_file(Path::new("tmp")).unwrap();
+
+    let state = instance.save_state().unwrap();
+    assert!(savestate::read_preview(&state).unwrap().thumbnail.is_none());
+    assert!(instance.load_state(&state[..state.len() / 2]).is_err());
+    instance.load_state(&state).unwrap();
   }
 }
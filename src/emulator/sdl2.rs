@@ -1,13 +1,13 @@
 use std::collections::HashSet;
 use std::thread;
 
-use crate::emulator::{APP_NAME, FRAME_DURATION, NES_VIDEO_HEIGHT, NES_VIDEO_WIDTH};
+use crate::emulator::{APP_NAME, NES_VIDEO_HEIGHT, NES_VIDEO_WIDTH};
 use crate::instance::Instance;
 
 use super::{Emulator, RuntimeConfig};
 
 use image::{ImageBuffer, Rgba};
-use log::{error, info};
+use log::info;
 use sdl2::pixels::PixelFormatEnum;
 use sdl2::render::{BlendMode, Canvas, Texture};
 use sdl2::surface::Surface;
@@ -41,6 +41,14 @@ impl Emulator {
     }
   }
 
+  /// Shows `message` in a message box, usable before the window exists.
+  pub fn show_error(&self, title: &str, message: &str) {
+    use sdl2::messagebox::{show_simple_message_box, MessageBoxFlag};
+    if let Err(e) = show_simple_message_box(MessageBoxFlag::ERROR, title, message, None) {
+      log::error!("show message box failed: {}", e);
+    }
+  }
+
   fn create_window(&self) -> Result<(Sdl, Canvas<Window>), String> {
     let (width, height) = self.runtime_config.window_size();
     let sdl_context = sdl2::init()?;
@@ -85,13 +93,14 @@ impl Emulator {
       self.update_keys(&event_pump);
       self.update_display(&mut instance, &mut texture, &mut canvas);
 
+      let frame_duration = instance.frame_duration();
       if instance.can_run() {
         let cost = self.one_frame(&mut instance);
-        if FRAME_DURATION > cost {
-          thread::sleep(FRAME_DURATION - cost);
+        if frame_duration > cost {
+          thread::sleep(frame_duration - cost);
         }
       } else {
-        thread::sleep(FRAME_DURATION);
+        thread::sleep(frame_duration);
       }
     }
 
@@ -100,7 +109,7 @@ impl Emulator {
 
   fn handle_event(
     &mut self,
-    runtime_config: &RuntimeConfig,
+    _runtime_config: &RuntimeConfig,
     event: sdl2::event::Event,
     instance: &mut Instance,
   ) -> bool {
@@ -126,17 +135,26 @@ impl Emulator {
         info!("window lost focus");
         instance.stat.unfocus();
       }
+      Event::KeyUp {
+        keycode: Some(Keycode::Backspace),
+        ..
+      } => instance.set_rewinding(false),
       Event::KeyDown {
         keycode: Some(key), ..
       } => match key {
-        Keycode::Z => instance.do_save(&runtime_config.save_path),
-        Keycode::X => match Instance::load(&runtime_config) {
-          Ok(instance_load) => {
-            *instance = instance_load;
-            info!("load success")
-          }
-          Err(e) => error!("load failed: {}", e),
-        },
+        Keycode::Backspace => instance.set_rewinding(true),
+        Keycode::Z => self.save_slot(instance),
+        Keycode::X => self.load_slot(instance),
+        Keycode::Num0
+        | Keycode::Num1
+        | Keycode::Num2
+        | Keycode::Num3
+        | Keycode::Num4
+        | Keycode::Num5
+        | Keycode::Num6
+        | Keycode::Num7
+        | Keycode::Num8
+        | Keycode::Num9 => self.select_slot((key as i32 - Keycode::Num0 as i32) as u8),
         Keycode::F2 => instance.toggle_pause(),
         Keycode::F3 => {
           if instance.stat.is_pausing() {
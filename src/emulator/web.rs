@@ -1,4 +1,3 @@
-use log::{error, info};
 use wasm_rgame::key_codes;
 
 use super::{Emulator, RuntimeConfig};
@@ -21,21 +20,18 @@ impl Emulator {
 
   pub fn handle_event(
     &mut self,
-    runtime_config: &RuntimeConfig,
+    _runtime_config: &RuntimeConfig,
     key: WebEvent,
     instance: &mut Instance,
   ) -> bool {
     match key {
-      WebEvent::KeyDown(key_codes::Z) => {
-        instance.do_save(&runtime_config.save_path);
+      WebEvent::KeyDown(key_codes::BACKSPACE) => instance.set_rewinding(true),
+      WebEvent::KeyUp(key_codes::BACKSPACE) => instance.set_rewinding(false),
+      WebEvent::KeyDown(key_codes::Z) => self.save_slot(instance),
+      WebEvent::KeyDown(key_codes::X) => self.load_slot(instance),
+      WebEvent::KeyDown(key) if key >= key_codes::ALPHA_0 && key <= key_codes::ALPHA_9 => {
+        self.select_slot((key - key_codes::ALPHA_0) as u8)
       }
-      WebEvent::KeyDown(key_codes::X) => match Instance::load(&runtime_config) {
-        Ok(instance_load) => {
-          *instance = instance_load;
-          info!("load success")
-        }
-        Err(e) => error!("load failed: {}", e),
-      },
       WebEvent::Focus(true) => instance.stat.focus(),
       WebEvent::Focus(false) => instance.stat.unfocus(),
       WebEvent::KeyDown(key_codes::F2) => instance.toggle_pause(),
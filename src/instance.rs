@@ -1,11 +1,10 @@
 use std::{
-  fs::OpenOptions,
-  io::{BufReader, BufWriter, Write},
+  path::{Path, PathBuf},
   sync::{Arc, Mutex},
   time::Duration,
 };
 
-use ciborium::{de::from_reader, ser::into_writer};
+use anyhow::bail;
 use image::{ImageBuffer, Rgba, RgbaImage};
 use log::{error, info, warn};
 use std::sync::mpsc;
@@ -14,15 +13,23 @@ use crate::{
   apu::Apu,
   bus::{main_bus::MainBus, message_bus::Message},
   cartridge::Cartridge,
-  common::instant::Instant,
-  cpu::{Cpu, InterruptType},
+  cheat::{Cheat, CheatList},
+  common::{instant::Instant, region::Region},
+  cpu::Cpu,
   emulator::RuntimeConfig,
   mapper::factory,
   ppu::{Ppu, SCANLINE_VISIBLE_DOTS, VISIBLE_SCANLINES},
+  movie::{Movie, MovieFrame, COMMAND_POWER, COMMAND_RESET},
+  rewind::Rewind,
+  savestate::{self, SaveStateHeader, SaveStateReader, SaveStateWriter},
+  NesResult,
 };
 
 pub type FrameBuffer = ImageBuffer<Rgba<u8>, Vec<u8>>;
 
+/// How often dirty battery RAM is flushed to the `.sav` file while running.
+const BATTERY_FLUSH_INTERVAL: Duration = Duration::from_secs(10);
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
 pub enum RunningStatus {
@@ -68,6 +75,12 @@ impl RunningStatus {
     (*self as u8 & Self::LostFocus as u8) == 0
   }
 }
+pub(crate) enum MovieState {
+  Idle,
+  Recording(Movie),
+  Playing(Movie, usize),
+}
+
 pub struct Instance {
   pub(crate) apu: Arc<Mutex<Apu>>,
   pub(crate) cpu: Arc<Mutex<Cpu>>,
@@ -77,6 +90,25 @@ pub struct Instance {
   pub(crate) elapsed_time: Duration,
   pub(crate) message_rx: mpsc::Receiver<Message>,
   pub(crate) rgba: Option<FrameBuffer>,
+  // last frame handed to the frontend, kept for savestate thumbnails
+  last_frame: Option<FrameBuffer>,
+  pub(crate) region: Region,
+  ppu_dot_carry: u32,
+  // battery backed PRG-RAM persistence
+  pub(crate) battery_path: Option<PathBuf>,
+  battery_snapshot: Vec<u8>,
+  battery_timer: Instant,
+  rom_sha1: [u8; 20],
+  rewind: Rewind,
+  rewinding: bool,
+  rom_md5: [u8; 16],
+  pub(crate) rom_name: String,
+  movie: MovieState,
+  // commands like reset to record into the next movie frame
+  movie_commands: u8,
+  pub(crate) movie_path: Option<PathBuf>,
+  cheats: CheatList,
+  cheat_path: Option<PathBuf>,
 }
 
 impl Instance {
@@ -86,6 +118,7 @@ impl Instance {
     ppu: Arc<Mutex<Ppu>>,
     message_rx: mpsc::Receiver<Message>,
   ) -> Self {
+    let region = ppu.lock().unwrap().region();
     Self {
       apu,
       cpu,
@@ -95,9 +128,33 @@ impl Instance {
       cycle_timer: Instant::now(),
       elapsed_time: Duration::new(0, 0),
       rgba: None,
+      last_frame: None,
+      region,
+      ppu_dot_carry: 0,
+      battery_path: None,
+      battery_snapshot: vec![],
+      battery_timer: Instant::now(),
+      rom_sha1: [0; 20],
+      rewind: Rewind::default(),
+      rewinding: false,
+      rom_md5: [0; 16],
+      rom_name: "rom".to_string(),
+      movie: MovieState::Idle,
+      movie_commands: 0,
+      movie_path: None,
+      cheats: CheatList::default(),
+      cheat_path: None,
     }
   }
 
+  pub fn region(&self) -> Region {
+    self.region
+  }
+
+  pub fn frame_duration(&self) -> Duration {
+    self.region.frame_duration()
+  }
+
   pub(crate) fn consume_message(&mut self) {
     while let Ok(message) = self.message_rx.try_recv() {
       match message {
@@ -106,6 +163,15 @@ impl Instance {
         }
         Message::PpuRender(frame) => {
           self.rgba = Some(frame);
+          self.cpu.lock().unwrap().main_bus_mut().apply_freezes();
+          // frames rendered by `rewind_frame` are neither recorded nor
+          // part of a movie
+          if !self.rewinding {
+            if self.rewind.tick() {
+              self.record_rewind();
+            }
+            self.latch_movie_input();
+          }
         }
       };
     }
@@ -125,7 +191,11 @@ impl Instance {
   }
 
   pub(crate) fn take_rgba(&mut self) -> Option<FrameBuffer> {
-    self.rgba.take()
+    let rgba = self.rgba.take();
+    if rgba.is_some() {
+      self.last_frame = rgba.clone();
+    }
+    rgba
   }
 
   pub(crate) fn update_timer(&mut self) {
@@ -134,18 +204,58 @@ impl Instance {
     self.cycle_timer = now;
   }
 
+  /// Run until the PPU finishes a frame, gives up after two frames worth of
+  /// CPU cycles so a stuck PPU can't hang the caller.
+  pub(crate) fn run_frame(&mut self) -> Option<FrameBuffer> {
+    let limit = 2 * (self.region.cpu_frequency() as f64 / self.region.frame_rate()) as u32;
+    let mut cycles = 0;
+    while cycles < limit {
+      cycles += self.step();
+      if let Some(frame) = self.take_rgba() {
+        return Some(frame);
+      }
+    }
+    warn!("no frame rendered in {} cycles", cycles);
+    None
+  }
+
+  pub(crate) fn set_buttons(&mut self, port: usize, buttons: u8) {
+    self.cpu.lock().unwrap().main_bus_mut().set_buttons(port, buttons);
+  }
+
+  pub(crate) fn take_samples(&mut self) -> Vec<f32> {
+    match self.apu.lock().unwrap().take_samples() {
+      Ok(samples) => samples,
+      Err(e) => {
+        error!("take samples failed: {}", e);
+        vec![]
+      }
+    }
+  }
+
+  /// Soft reset, like pressing the reset button on the console.
+  pub(crate) fn reset(&mut self) {
+    if let MovieState::Recording(_) = self.movie {
+      self.movie_commands |= COMMAND_RESET;
+    }
+    let mut cpu = self.cpu.lock().unwrap();
+    // silence all APU channels
+    cpu.main_bus_mut().write(0x4015, 0);
+    cpu.reset();
+  }
+
   pub(crate) fn step(&mut self) -> u32 {
     let circle = {
       let mut cpu = self.cpu.lock().unwrap();
       let circle = cpu.step();
       cpu.reset_skip_cycles();
+      cpu.main_bus_mut().clock_mapper(circle);
       circle
     };
     {
+      let dots = self.region.ppu_dots(circle, &mut self.ppu_dot_carry);
       let mut ppu = self.ppu.lock().unwrap();
-      for _ in 0..circle {
-        ppu.step();
-        ppu.step();
+      for _ in 0..dots {
         ppu.step();
       }
     }
@@ -155,6 +265,12 @@ impl Instance {
         apu.step();
       }
     }
+    {
+      // mapper IRQs stay asserted until acknowledged, unlike APU messages
+      let mut cpu = self.cpu.lock().unwrap();
+      let irq = cpu.main_bus().mapper_irq();
+      cpu.poll_irq(irq);
+    }
     self.consume_message();
 
     circle
@@ -162,6 +278,294 @@ impl Instance {
 
   pub fn stop(&mut self) {
     self.apu.lock().unwrap().stop();
+    if let Some(path) = self.movie_path.clone() {
+      if let Some(movie) = self.stop_movie() {
+        match std::fs::write(&path, movie.to_fm2()) {
+          Ok(_) => info!("movie saved to {}", path.display()),
+          Err(e) => error!("save movie failed: {}", e),
+        }
+      }
+    }
+    if let Err(e) = self.save_battery() {
+      error!("save battery failed: {}", e);
+    }
+  }
+}
+
+/// Cheats
+impl Instance {
+  pub fn cheats(&self) -> &[Cheat] {
+    &self.cheats.cheats
+  }
+
+  /// Set the cheat file of this ROM and load cheats from it if it exists.
+  pub fn set_cheat_path<P: Into<PathBuf>>(&mut self, path: P) -> NesResult<()> {
+    let path = path.into();
+    self.cheats = if path.exists() {
+      info!("load cheats from {}", path.display());
+      CheatList::load(&path)?
+    } else {
+      CheatList::default()
+    };
+    self.cheat_path = Some(path);
+    self.apply_cheats();
+    Ok(())
+  }
+
+  /// Add and enable a Game Genie or `AAAA:VV` RAM code, returns its index.
+  pub fn add_cheat(&mut self, code: &str, name: &str) -> NesResult<usize> {
+    let index = self.cheats.add(code, name)?;
+    self.update_cheats()?;
+    Ok(index)
+  }
+
+  pub fn remove_cheat(&mut self, index: usize) -> NesResult<()> {
+    if index >= self.cheats.cheats.len() {
+      bail!("no cheat at {}", index);
+    }
+    self.cheats.cheats.remove(index);
+    self.update_cheats()
+  }
+
+  pub fn set_cheat_enabled(&mut self, index: usize, enabled: bool) -> NesResult<()> {
+    match self.cheats.cheats.get_mut(index) {
+      Some(cheat) => cheat.enabled = enabled,
+      None => bail!("no cheat at {}", index),
+    }
+    self.update_cheats()
+  }
+
+  fn update_cheats(&mut self) -> NesResult<()> {
+    self.apply_cheats();
+    match &self.cheat_path {
+      Some(path) => self.cheats.save(path),
+      None => Ok(()),
+    }
+  }
+
+  fn apply_cheats(&mut self) {
+    let effects = self.cheats.enabled_effects();
+    self.cpu.lock().unwrap().main_bus_mut().set_cheats(effects);
+  }
+}
+
+/// Battery backed RAM
+impl Instance {
+  pub fn battery_path(&self) -> Option<&Path> {
+    self.battery_path.as_deref()
+  }
+
+  /// Set the `.sav` file and load battery RAM from it if it exists.
+  pub fn set_battery_path<P: Into<PathBuf>>(&mut self, path: P) -> Result<(), std::io::Error> {
+    self.battery_path = Some(path.into());
+    self.load_battery()
+  }
+
+  pub fn battery_ram(&self) -> Option<Vec<u8>> {
+    self.cpu.lock().unwrap().main_bus().battery_ram()
+  }
+
+  pub fn load_battery_ram(&mut self, data: &[u8]) {
+    self.cpu.lock().unwrap().main_bus_mut().load_battery_ram(data);
+    self.battery_snapshot = data.to_vec();
+  }
+
+  pub fn load_battery(&mut self) -> Result<(), std::io::Error> {
+    let path = match &self.battery_path {
+      Some(path) if path.exists() => path.clone(),
+      _ => return Ok(()),
+    };
+    let data = std::fs::read(&path)?;
+    info!("load battery ram from {}", path.display());
+    self.load_battery_ram(&data);
+    Ok(())
+  }
+
+  /// Write battery RAM to the `.sav` file if it changed since the last flush.
+  pub fn save_battery(&mut self) -> Result<(), std::io::Error> {
+    let path = match &self.battery_path {
+      Some(path) => path.clone(),
+      None => return Ok(()),
+    };
+    let ram = match self.battery_ram() {
+      Some(ram) => ram,
+      None => return Ok(()),
+    };
+    if ram == self.battery_snapshot {
+      return Ok(());
+    }
+    log::debug!("flush battery ram to {}", path.display());
+    std::fs::write(&path, &ram)?;
+    self.battery_snapshot = ram;
+    Ok(())
+  }
+
+  pub(crate) fn tick_battery(&mut self) {
+    if Instant::now() - self.battery_timer < BATTERY_FLUSH_INTERVAL {
+      return;
+    }
+    self.battery_timer = Instant::now();
+    if let Err(e) = self.save_battery() {
+      error!("save battery failed: {}", e);
+    }
+  }
+}
+
+/// Input movies
+impl Instance {
+  /// Start recording both controllers. With `from_savestate` the movie embeds
+  /// the current state, otherwise the console is power cycled first.
+  pub fn start_recording(&mut self, from_savestate: bool) -> NesResult<()> {
+    self.stop_movie();
+    let mut movie = Movie::new(&self.rom_name, self.rom_md5, self.region == Region::Pal);
+    if from_savestate {
+      movie.savestate = Some(self.write_state(false)?);
+    } else {
+      self.power_cycle()?;
+    }
+    info!("start recording movie");
+    self.movie = MovieState::Recording(movie);
+    self.movie_commands = 0;
+    self.latch_movie_input();
+    Ok(())
+  }
+
+  /// Record from now on and write the movie to `path` when stopping.
+  pub fn record_movie_to<P: Into<PathBuf>>(&mut self, path: P, from_savestate: bool) -> NesResult<()> {
+    self.movie_path = Some(path.into());
+    self.start_recording(from_savestate)
+  }
+
+  /// Replay a movie from its savestate or from power on, controllers ignore
+  /// the keyboard until it ends.
+  pub fn play_movie(&mut self, movie: Movie) -> NesResult<()> {
+    if let Some(checksum) = movie.rom_checksum {
+      if checksum != self.rom_md5 {
+        warn!("movie was recorded with another ROM, it may desync");
+      }
+    }
+    self.stop_movie();
+    match &movie.savestate {
+      Some(savestate) => self.load_state(savestate)?,
+      None => self.power_cycle()?,
+    }
+    info!("play movie with {} frames", movie.frames.len());
+    self.movie = MovieState::Playing(movie, 0);
+    self.latch_movie_input();
+    Ok(())
+  }
+
+  pub fn is_playing_movie(&self) -> bool {
+    matches!(self.movie, MovieState::Playing(..))
+  }
+
+  pub fn is_recording_movie(&self) -> bool {
+    matches!(self.movie, MovieState::Recording(_))
+  }
+
+  fn is_movie_active(&self) -> bool {
+    !matches!(self.movie, MovieState::Idle)
+  }
+
+  /// Stop recording or playback, returns the movie.
+  pub fn stop_movie(&mut self) -> Option<Movie> {
+    self.cpu.lock().unwrap().main_bus_mut().set_input_override(None);
+    match std::mem::replace(&mut self.movie, MovieState::Idle) {
+      MovieState::Idle => None,
+      MovieState::Recording(movie) | MovieState::Playing(movie, _) => Some(movie),
+    }
+  }
+
+  /// Fix the controller input for the next frame.
+  fn latch_movie_input(&mut self) {
+    let frame = match &mut self.movie {
+      MovieState::Idle => return,
+      MovieState::Recording(movie) => {
+        let ports = self.cpu.lock().unwrap().main_bus_mut().poll_buttons();
+        let frame = MovieFrame {
+          commands: std::mem::take(&mut self.movie_commands),
+          ports,
+        };
+        movie.frames.push(frame);
+        // the reset or power cycle already happened while recording
+        MovieFrame { commands: 0, ..frame }
+      }
+      MovieState::Playing(movie, index) => match movie.frames.get(*index) {
+        Some(frame) => {
+          *index += 1;
+          *frame
+        }
+        None => {
+          info!("movie finished");
+          self.stop_movie();
+          return;
+        }
+      },
+    };
+    if frame.commands & COMMAND_POWER != 0 {
+      if let Err(e) = self.power_cycle() {
+        error!("movie power cycle failed: {}", e);
+      }
+    }
+    if frame.commands & COMMAND_RESET != 0 {
+      self.reset();
+    }
+    self
+      .cpu
+      .lock()
+      .unwrap()
+      .main_bus_mut()
+      .set_input_override(Some(frame.ports));
+  }
+}
+
+/// Rewind
+impl Instance {
+  /// Snapshot every `interval` frames and keep `capacity` of them, a zero
+  /// capacity disables rewinding.
+  pub fn set_rewind(&mut self, interval: u32, capacity: usize) {
+    self.rewind = Rewind::new(interval, capacity);
+  }
+
+  pub fn is_rewinding(&self) -> bool {
+    self.rewinding
+  }
+
+  /// Start or stop rewinding, audio is muted meanwhile.
+  pub fn set_rewinding(&mut self, rewinding: bool) {
+    self.rewinding = rewinding;
+    self.apu.lock().unwrap().set_muted(rewinding);
+  }
+
+  fn record_rewind(&mut self) {
+    match self.write_state(false) {
+      Ok(state) => self.rewind.push(state),
+      Err(e) => error!("rewind snapshot failed: {}", e),
+    }
+  }
+
+  /// Step one snapshot backwards and render it, false when the buffer is
+  /// exhausted or a movie is recording or playing.
+  pub fn rewind_frame(&mut self) -> bool {
+    if self.is_movie_active() {
+      warn!("can't rewind during a movie");
+      return false;
+    }
+    let state = match self.rewind.pop() {
+      Some(state) => state,
+      None => return false,
+    };
+    if let Err(e) = self.load_state(&state) {
+      error!("rewind failed: {}", e);
+      return false;
+    }
+    // the restored PPU has no picture yet, render one without recording it
+    let rewinding = std::mem::replace(&mut self.rewinding, true);
+    if let Some(frame) = self.run_frame() {
+      self.rgba = Some(frame);
+    }
+    self.rewinding = rewinding;
+    true
   }
 }
 
@@ -174,98 +578,164 @@ impl Instance {
     }
   }
 
-  pub fn save(&self, file: &String) -> Result<(), std::io::Error> {
+  pub(crate) fn do_load(&mut self, file: &String) {
+    match self.load(file) {
+      Ok(_) => info!("load success"),
+      Err(e) => error!("load failed: {}", e),
+    }
+  }
+
+  pub fn save(&self, file: &String) -> NesResult<()> {
     let path = std::path::Path::new(file);
     log::info!("save to {}", path.display());
     {
       let dir = path.parent().expect("invalid path");
       if !dir.exists() && dir != std::path::Path::new("") {
         log::debug!("create dir {:?}", dir);
-        std::fs::create_dir(dir)?;
+        std::fs::create_dir_all(dir)?;
       }
     }
-    let mut file = std::fs::File::create(path)?;
-    let mut writer = BufWriter::new(&mut file);
-    into_writer(&*self.cpu.lock().unwrap(), &mut writer).unwrap();
-    into_writer(&*self.apu.lock().unwrap(), &mut writer).unwrap();
-    into_writer(&*self.ppu.lock().unwrap(), &mut writer).unwrap();
-    self
-      .cpu
-      .as_ref()
-      .lock()
-      .unwrap()
-      .main_bus()
-      .save_binary(writer)
-      .flush()?;
-    // writer.flush()
+    std::fs::write(path, self.save_state()?)?;
     Ok(())
   }
 
-  pub fn load(runtime_config: &RuntimeConfig) -> Result<Self, std::io::Error> {
-    let file = OpenOptions::new()
-      .read(true)
-      .open(&runtime_config.save_path)?;
-    let mut reader = BufReader::new(file);
+  /// Load a savestate file, refused during a movie as it would desync.
+  pub fn load(&mut self, file: &String) -> NesResult<()> {
+    if self.is_movie_active() {
+      bail!("can't load a savestate during a movie");
+    }
+    log::info!("load from {}", file);
+    let data = std::fs::read(file)?;
+    self.load_state(&data)
+  }
+
+  pub fn rom_sha1(&self) -> [u8; 20] {
+    self.rom_sha1
+  }
+
+  /// Serialize the whole machine into a savestate container.
+  pub fn save_state(&self) -> NesResult<Vec<u8>> {
+    self.write_state(true)
+  }
+
+  fn write_state(&self, thumbnail: bool) -> NesResult<Vec<u8>> {
+    let header = SaveStateHeader::new(self.rom_sha1, self.region);
+    let mut writer = SaveStateWriter::new(&header)?;
+    let cpu = self.cpu.lock().unwrap();
+    writer.cbor_section(savestate::CPU, &*cpu)?;
+    writer.cbor_section(savestate::APU, &*self.apu.lock().unwrap())?;
+    writer.cbor_section(savestate::PPU, &*self.ppu.lock().unwrap())?;
+    let mut bus = vec![];
+    cpu.main_bus().save_binary(&mut bus)?;
+    writer.section(savestate::BUS, &bus)?;
+    let frame = self.last_frame.as_ref().or(self.rgba.as_ref());
+    if let Some(frame) = frame.filter(|_| thumbnail) {
+      writer.section(savestate::THUMBNAIL, &savestate::encode_thumbnail(frame)?)?;
+    }
+    Ok(writer.finish())
+  }
+
+  /// Restore a savestate made by `save_state`, the running machine is left
+  /// untouched if the data is corrupt or belongs to another ROM.
+  pub fn load_state(&mut self, data: &[u8]) -> NesResult<()> {
+    let reader = SaveStateReader::parse(data)?;
+    if reader.header.rom_sha1 != self.rom_sha1 {
+      bail!("savestate belongs to another ROM");
+    }
     let (message_sx, message_rx) = mpsc::channel::<Message>();
 
-    let mut cpu: Cpu = from_reader(&mut reader).unwrap();
-    let apu = from_reader(&mut reader)
-      .map(|mut apu: Apu| {
-        apu.set_message_bus(message_sx.clone());
-        apu.start();
-
-        Arc::new(Mutex::new(apu))
-      })
-      .unwrap();
-    let ppu = from_reader(&mut reader)
-      .map(|mut ppu: Ppu| {
-        ppu.set_message_bus(message_sx.clone());
-        ppu.image = RgbaImage::new(SCANLINE_VISIBLE_DOTS as u32, VISIBLE_SCANLINES as u32);
-
-        Arc::new(Mutex::new(ppu))
-      })
-      .unwrap();
-    let mut main_bus = MainBus::load_binary(reader, message_sx, ppu.clone(), apu.clone());
-    main_bus.set_controller_keys(runtime_config.ctl1.clone(), runtime_config.ctl2.clone());
+    let mut cpu: Cpu = reader.cbor_section(savestate::CPU)?;
+    let mut apu: Apu = reader.cbor_section(savestate::APU)?;
+    apu.set_message_bus(message_sx.clone());
+    let apu = Arc::new(Mutex::new(apu));
+    let mut ppu: Ppu = reader.cbor_section(savestate::PPU)?;
+    ppu.set_message_bus(message_sx);
+    ppu.image = RgbaImage::new(SCANLINE_VISIBLE_DOTS as u32, VISIBLE_SCANLINES as u32);
+    let ppu = Arc::new(Mutex::new(ppu));
+    let mut main_bus = {
+      let current = self.cpu.lock().unwrap();
+      let rom = current.main_bus().cartridge();
+      MainBus::load_binary(
+        reader.section(savestate::BUS)?,
+        &rom,
+        ppu.clone(),
+        apu.clone(),
+      )?
+    };
+
+    main_bus.take_controllers(self.cpu.lock().unwrap().main_bus_mut());
     cpu.set_main_bus(main_bus);
     let cpu = Arc::new(Mutex::new(cpu));
-    let cpu_clone = cpu.clone();
-    apu.lock().unwrap().set_read_cb(Box::new(move |addr| {
-      let mut inner_cpu = cpu_clone.lock().unwrap();
-      inner_cpu.skip_dmc_cycles();
-      inner_cpu.main_bus_mut().read(addr)
-    }));
+    {
+      let cpu_clone = cpu.clone();
+      let mut apu = apu.lock().unwrap();
+      apu.set_read_cb(Box::new(move |addr| {
+        let mut inner_cpu = cpu_clone.lock().unwrap();
+        inner_cpu.skip_dmc_cycles();
+        inner_cpu.main_bus_mut().read(addr)
+      }));
+      apu.take_output(&mut self.apu.lock().unwrap());
+    }
 
-    Ok(Self::new(apu, cpu, ppu, message_rx))
+    self.region = ppu.lock().unwrap().region();
+    self.ppu_dot_carry = 0;
+    self.apu = apu;
+    self.cpu = cpu;
+    self.ppu = ppu;
+    self.message_rx = message_rx;
+    self.rgba = None;
+    self.apply_cheats();
+    Ok(())
+  }
+
+  fn init_rom(cartridge: Cartridge, runtime_config: &RuntimeConfig) -> NesResult<Self> {
+    let region = runtime_config
+      .region
+      .unwrap_or_else(|| Region::from(cartridge.header().timing));
+    info!("Region: {:?}", region);
+    let mut instance = Self::power_on(cartridge, region)?;
+    instance
+      .cpu
+      .lock()
+      .unwrap()
+      .main_bus_mut()
+      .set_controller_keys(runtime_config.ctl1.clone(), runtime_config.ctl2.clone());
+    let cheat_path = runtime_config.cheat_path(&instance.rom_sha1);
+    if let Err(e) = instance.set_cheat_path(cheat_path) {
+      error!("load cheats failed: {}", e);
+    }
+
+    Ok(instance)
   }
 
-  fn init_rom(cartridge: Cartridge, runtime_config: &RuntimeConfig) -> Option<Self> {
+  /// A console just switched on with `cartridge` inserted.
+  fn power_on(cartridge: Cartridge, region: Region) -> NesResult<Self> {
     let (message_sx, message_rx) = mpsc::channel::<Message>();
-    let ppu = Arc::new(Mutex::new(Ppu::new(message_sx.clone())));
+    let ppu = Arc::new(Mutex::new(Ppu::new(message_sx.clone(), region)));
 
-    let apu = Arc::new(Mutex::new(Apu::new(message_sx.clone())));
-    let mut main_bus = MainBus::new(apu.clone(), ppu.clone());
-    main_bus.set_controller_keys(runtime_config.ctl1.clone(), runtime_config.ctl2.clone());
+    let apu = Arc::new(Mutex::new(Apu::new(message_sx, region)));
+    let main_bus = MainBus::new(apu.clone(), ppu.clone());
 
     let mut cpu = Cpu::new(main_bus);
+    let trainer = cartridge.trainer().map(|trainer| trainer.to_vec());
+    let rom_sha1 = cartridge.sha1();
+    let rom_md5 = cartridge.md5();
     let ppu_clone = ppu.clone();
     let mapper = factory::create_mapper(
       cartridge,
       Box::new(move |val: u8| {
-        let r = ppu_clone.try_lock();
-        if r.is_ok() {
-          r.unwrap().update_mirroring(Some(val));
+        if let Ok(mut ppu) = ppu_clone.try_lock() {
+          ppu.update_mirroring(Some(val));
         } else {
           warn!("ppu is locked");
         }
       }),
-      Box::new(move || {
-        if let Err(e) = message_sx.send(Message::CpuInterrupt(InterruptType::IRQ)) {
-          log::error!("send interrupt error {:?}", e);
-        }
-      })
-    );
+    )?;
     cpu.main_bus_mut().set_mapper(mapper.clone());
+    apu.lock().unwrap().set_expansion_audio(mapper.clone());
+    if let Some(trainer) = trainer {
+      cpu.main_bus_mut().load_trainer(&trainer);
+    }
     cpu.reset();
     let cpu = Arc::new(Mutex::new(cpu));
     ppu.lock().unwrap().set_mapper_for_bus(mapper);
@@ -282,28 +752,67 @@ impl Instance {
     }
 
     // ppu.borrow_mut().reset();
-    let instance = Self::new(apu, cpu.clone(), ppu, message_rx);
+    let mut instance = Self::new(apu, cpu.clone(), ppu, message_rx);
+    instance.rom_sha1 = rom_sha1;
+    instance.rom_md5 = rom_md5;
+    Ok(instance)
+  }
 
-    Some(instance)
+  /// Switch the console off and on again. Battery RAM, cheats and controllers
+  /// survive like on the hardware.
+  pub fn power_cycle(&mut self) -> NesResult<()> {
+    if let MovieState::Recording(_) = self.movie {
+      self.movie_commands |= COMMAND_POWER;
+    }
+    let cartridge = self.cpu.lock().unwrap().main_bus().cartridge().clone();
+    let battery_ram = self.battery_ram();
+    self.apu.lock().unwrap().stop();
+
+    let fresh = Self::power_on(cartridge, self.region)?;
+    {
+      let mut cpu = fresh.cpu.lock().unwrap();
+      cpu.main_bus_mut().take_controllers(self.cpu.lock().unwrap().main_bus_mut());
+      if let Some(ram) = battery_ram {
+        cpu.main_bus_mut().load_battery_ram(&ram);
+      }
+    }
+    fresh.apu.lock().unwrap().set_muted(self.rewinding);
+    self.apu = fresh.apu;
+    self.cpu = fresh.cpu;
+    self.ppu = fresh.ppu;
+    self.message_rx = fresh.message_rx;
+    self.ppu_dot_carry = 0;
+    self.apply_cheats();
+    Ok(())
   }
 
   pub(crate) fn init_rom_from_data(
     rom_data: &[u8],
     runtime_config: &RuntimeConfig,
-  ) -> Option<Self> {
+  ) -> NesResult<Self> {
     let mut cartridge = Cartridge::new();
-    if !cartridge.load_from_data(rom_data) {
-      return None;
-    }
+    cartridge.load_from_data(rom_data)?;
 
     Self::init_rom(cartridge, runtime_config)
   }
 
-  pub(crate) fn init_rom_from_path(rom_path: &str, runtime_config: &RuntimeConfig) -> Option<Self> {
+  pub(crate) fn init_rom_from_path(
+    rom_path: &str,
+    runtime_config: &RuntimeConfig,
+  ) -> NesResult<Self> {
     let mut cartridge = Cartridge::new();
-    if !cartridge.load_from_file(rom_path) {
-      return None;
+    cartridge.load_from_file(rom_path)?;
+    let has_battery = cartridge.has_battery();
+    let mut instance = Self::init_rom(cartridge, runtime_config)?;
+    if let Some(stem) = Path::new(rom_path).file_stem() {
+      instance.rom_name = stem.to_string_lossy().into_owned();
     }
-    Self::init_rom(cartridge, runtime_config)
+    if has_battery {
+      let sav_path = Path::new(rom_path).with_extension("sav");
+      if let Err(e) = instance.set_battery_path(sav_path) {
+        error!("load battery failed: {}", e);
+      }
+    }
+    Ok(instance)
   }
 }
@@ -1,15 +1,24 @@
 mod apu;
 mod bus;
 mod cartridge;
+pub mod cheat;
 mod common;
+pub mod console;
 pub mod controller;
 mod cpu;
 pub mod emulator;
 mod instance;
 pub mod logger;
 mod mapper;
+pub mod movie;
 mod ppu;
 mod render;
+pub mod rewind;
+pub mod savestate;
+
+pub use common::region::Region;
+pub use console::Console;
+pub use instance::Instance;
 
 pub type NesError = anyhow::Error;
 pub type NesResult<T> = anyhow::Result<T, NesError>;
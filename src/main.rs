@@ -1,4 +1,8 @@
 use clap::Parser;
+use rust_nes::Region;
+
+#[cfg(any(feature = "use_gl", feature = "use_sdl2"))]
+use rust_nes::movie::Movie;
 
 #[cfg(any(feature = "use_gl", feature = "use_sdl2"))]
 use rust_nes::{controller, emulator, logger};
@@ -17,6 +21,22 @@ struct Args {
 
   #[clap(long, default_value = "save/saved.json")]
   save_path: String,
+
+  /// Override the region detected from the ROM header: ntsc, pal or dendy.
+  #[clap(long)]
+  region: Option<Region>,
+
+  /// Play an FCEUX .fm2 movie from power-on.
+  #[clap(long)]
+  play_movie: Option<String>,
+
+  /// Record input from power-on into an .fm2 movie, written on exit.
+  #[clap(long)]
+  record_movie: Option<String>,
+
+  /// Enable a Game Genie or AAAA:VV RAM cheat, remembered for this ROM.
+  #[clap(long, multiple_occurrences = true)]
+  cheat: Vec<String>,
 }
 
 #[cfg(any(feature = "use_gl", feature = "use_sdl2"))]
@@ -28,7 +48,34 @@ fn main() {
   let args = Args::parse();
   let (p1_key, p2_key) = controller::key_binding_parser::parse_key_binding(&args.key_binding_path);
   let mut emulator = emulator::Emulator::new(args.scale, args.save_path, p1_key, p2_key);
-  let instance = emulator.create_instance(&args.rom_path);
+  emulator.set_region(args.region);
+  let mut instance = match emulator.create_instance(&args.rom_path) {
+    Ok(instance) => instance,
+    Err(e) => {
+      let message = format!("Failed to load {}: {:#}", args.rom_path, e);
+      eprintln!("{}", message);
+      emulator.show_error("Failed to load ROM", &message);
+      std::process::exit(1);
+    }
+  };
+  for code in &args.cheat {
+    if let Err(e) = instance.add_cheat(code, code) {
+      log::error!("add cheat {} failed: {}", code, e);
+    }
+  }
+  if let Some(path) = args.play_movie {
+    let movie = std::fs::read_to_string(&path)
+      .map_err(|e| e.into())
+      .and_then(|text| Movie::parse_fm2(&text));
+    match movie.and_then(|movie| instance.play_movie(movie)) {
+      Ok(_) => {}
+      Err(e) => log::error!("play movie {} failed: {}", path, e),
+    }
+  } else if let Some(path) = args.record_movie {
+    if let Err(e) = instance.record_movie_to(path, false) {
+      log::error!("record movie failed: {}", e);
+    }
+  }
   emulator.run(instance);
 }
 
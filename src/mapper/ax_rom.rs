@@ -0,0 +1,142 @@
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::cartridge::Cartridge;
+use crate::common::*;
+
+use super::{
+  factory::{MirrorCallback, NameTableMirroring},
+  save, Mapper, MapperType, AXROM,
+};
+
+const PRG_BANK_SIZE: usize = 0x8000;
+
+/**
+ * AxROM: one 32KB switchable PRG bank, 8KB CHR-RAM and single screen
+ * mirroring selected by bit 4 of the bank register.
+ */
+#[derive(Serialize, Deserialize)]
+pub struct AxRom {
+  #[serde(with = "serde_bytes")]
+  character_ram: Option<Vec<Byte>>,
+  cart: Cartridge,
+  #[serde(skip)]
+  mirror_cb: Option<MirrorCallback>,
+  mirroring: NameTableMirroring,
+  prg_bank: usize, // offset of rom
+}
+
+impl AxRom {
+  pub fn new(cart: Cartridge, mirror_cb: MirrorCallback) -> Self {
+    let ram = if cart.get_vrom().is_empty() {
+      Some(vec![0; cart.chr_ram_size()])
+    } else {
+      None
+    };
+    Self {
+      character_ram: ram,
+      cart,
+      mirror_cb: Some(mirror_cb),
+      mirroring: NameTableMirroring::OneScreenLower,
+      prg_bank: 0,
+    }
+  }
+
+  pub fn set_mirror_cb(&mut self, mirror_cb: MirrorCallback) {
+    self.mirror_cb = Some(mirror_cb);
+  }
+}
+
+impl Mapper for AxRom {
+  fn write_prg(&mut self, _: Address, value: Byte) {
+    let banks = std::cmp::max(self.cart.get_rom().len() / PRG_BANK_SIZE, 1);
+    self.prg_bank = ((value & 0x07) as usize % banks) * PRG_BANK_SIZE;
+
+    let mirroring = if bit_eq(value, 0x10) {
+      NameTableMirroring::OneScreenHigher
+    } else {
+      NameTableMirroring::OneScreenLower
+    };
+    // games switch banks a lot, only tell the PPU when it changes
+    if mirroring != self.mirroring {
+      self.mirroring = mirroring;
+      if let Some(cb) = self.mirror_cb.as_mut() {
+        cb(mirroring.into());
+      }
+    }
+  }
+
+  fn read_prg(&self, addr: Address) -> Byte {
+    let rom = self.cart.get_rom();
+    rom[(self.prg_bank + (addr & 0x7FFF) as usize) % rom.len()]
+  }
+
+  fn write_chr(&mut self, addr: Address, value: Byte) {
+    match &mut self.character_ram {
+      Some(ram) => ram[addr as usize] = value,
+      None => warn!("Attempting to write read-only CHR memory on {:#x}", addr),
+    }
+  }
+
+  fn read_chr(&self, addr: Address) -> Byte {
+    match &self.character_ram {
+      Some(ram) => ram[addr as usize],
+      None => self.cart.get_vrom()[addr as usize],
+    }
+  }
+
+  fn has_extended_ram(&self) -> bool {
+    self.cart.has_extended_ram()
+  }
+
+  fn has_battery(&self) -> bool {
+    self.cart.has_battery()
+  }
+
+  fn get_name_table_mirroring(&self) -> u8 {
+    self.mirroring.into()
+  }
+
+  fn save(&self) -> Vec<u8> {
+    save(self)
+  }
+
+  fn cartridge(&self) -> &Cartridge {
+    &self.cart
+  }
+
+  fn cartridge_mut(&mut self) -> &mut Cartridge {
+    &mut self.cart
+  }
+
+  fn mapper_type(&self) -> MapperType {
+    AXROM
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{cell::Cell, rc::Rc};
+
+  use super::*;
+  use crate::mapper::test::test_cart;
+
+  #[test]
+  fn bank_switch_test() {
+    let cart = test_cart(7, 128, 0, PRG_BANK_SIZE, 0);
+
+    let mirroring = Rc::new(Cell::new(None));
+    let mirroring_clone = mirroring.clone();
+    let mut mapper = AxRom::new(cart, Box::new(move |m| mirroring_clone.set(Some(m))));
+    assert_eq!(mapper.read_prg(0x8000), 0);
+    mapper.write_prg(0x8000, 0x03);
+    assert_eq!(mapper.read_prg(0xFFFF), 3);
+    assert_eq!(mirroring.get(), None);
+    mapper.write_prg(0x8000, 0x12);
+    assert_eq!(mapper.read_prg(0x8000), 2);
+    assert_eq!(mirroring.get(), Some(NameTableMirroring::OneScreenHigher.into()));
+
+    mapper.write_chr(0x1234, 0x56);
+    assert_eq!(mapper.read_chr(0x1234), 0x56);
+  }
+}
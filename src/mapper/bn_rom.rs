@@ -0,0 +1,197 @@
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::cartridge::Cartridge;
+use crate::common::*;
+
+use super::{save, Mapper, MapperType, BNROM};
+
+const PRG_BANK_SIZE: usize = 0x8000;
+const PRG_RAM_SIZE: usize = 0x2000;
+const CHR_BANK_SIZE: usize = 0x1000;
+
+/**
+ * Mapper 34, two unrelated boards sharing a number:
+ *
+ * - BNROM: a 32KB PRG bank written to $8000-$FFFF, 8KB CHR-RAM
+ * - NINA-001: 8KB PRG-RAM with the registers at its end, a 32KB PRG bank at
+ *   $7FFD and two 4KB CHR banks at $7FFE and $7FFF
+ *
+ * Submapper 1 is NINA-001 and 2 BNROM, otherwise a board with CHR-ROM is
+ * taken as NINA-001.
+ */
+#[derive(Serialize, Deserialize)]
+pub struct BnRom {
+  #[serde(with = "serde_bytes")]
+  character_ram: Option<Vec<Byte>>,
+  #[serde(with = "serde_bytes")]
+  prg_ram: Option<Vec<Byte>>,
+  cart: Cartridge,
+  prg_bank: usize,       // offset of rom
+  chr_banks: [usize; 2], // offsets of vrom
+}
+
+impl BnRom {
+  pub fn new(cart: Cartridge) -> Self {
+    let nina001 = match cart.header().submapper {
+      1 => true,
+      2 => false,
+      _ => !cart.get_vrom().is_empty(),
+    };
+    let ram = if cart.get_vrom().is_empty() {
+      Some(vec![0; cart.chr_ram_size()])
+    } else {
+      None
+    };
+    Self {
+      character_ram: ram,
+      prg_ram: if nina001 {
+        Some(vec![0; PRG_RAM_SIZE])
+      } else {
+        None
+      },
+      cart,
+      prg_bank: 0,
+      chr_banks: [0, CHR_BANK_SIZE],
+    }
+  }
+
+  fn set_prg_bank(&mut self, value: Byte) {
+    let banks = std::cmp::max(self.cart.get_rom().len() / PRG_BANK_SIZE, 1);
+    self.prg_bank = (value as usize % banks) * PRG_BANK_SIZE;
+  }
+}
+
+impl Mapper for BnRom {
+  fn write_prg(&mut self, addr: Address, value: Byte) {
+    let ram = match &mut self.prg_ram {
+      Some(ram) if addr < 0x8000 => ram,
+      // NINA-001 ignores ROM writes
+      Some(_) => return,
+      None => {
+        if addr >= 0x8000 {
+          self.set_prg_bank(value);
+        }
+        return;
+      }
+    };
+    ram[(addr & 0x1FFF) as usize] = value;
+    match addr {
+      0x7FFD => self.set_prg_bank(value & 0x01),
+      0x7FFE | 0x7FFF => {
+        let banks = std::cmp::max(self.cart.get_vrom().len() / CHR_BANK_SIZE, 1);
+        let bank = ((value & 0x0F) as usize % banks) * CHR_BANK_SIZE;
+        self.chr_banks[(addr - 0x7FFE) as usize] = bank;
+      }
+      _ => {}
+    }
+  }
+
+  fn read_prg(&self, addr: Address) -> Byte {
+    match &self.prg_ram {
+      Some(ram) if addr < 0x8000 => ram[(addr & 0x1FFF) as usize],
+      _ if addr < 0x8000 => 0,
+      _ => self.cart.get_rom()[self.prg_bank + (addr & 0x7FFF) as usize],
+    }
+  }
+
+  fn write_chr(&mut self, addr: Address, value: Byte) {
+    match &mut self.character_ram {
+      Some(ram) => ram[addr as usize] = value,
+      None => warn!("Attempting to write read-only CHR memory on {:#x}", addr),
+    }
+  }
+
+  fn read_chr(&self, addr: Address) -> Byte {
+    match &self.character_ram {
+      Some(ram) => ram[addr as usize],
+      None => {
+        let bank = self.chr_banks[(addr >> 12) as usize & 1];
+        self.cart.get_vrom()[bank + (addr as usize & (CHR_BANK_SIZE - 1))]
+      }
+    }
+  }
+
+  fn prg_ram(&self) -> Option<&[Byte]> {
+    self.prg_ram.as_deref()
+  }
+
+  fn prg_ram_mut(&mut self) -> Option<&mut [Byte]> {
+    self.prg_ram.as_deref_mut()
+  }
+
+  fn has_extended_ram(&self) -> bool {
+    self.prg_ram.is_some() || self.cart.has_extended_ram()
+  }
+
+  fn has_battery(&self) -> bool {
+    self.cart.has_battery()
+  }
+
+  fn get_name_table_mirroring(&self) -> u8 {
+    self.cart.get_name_table_mirroring()
+  }
+
+  fn save(&self) -> Vec<u8> {
+    save(self)
+  }
+
+  fn cartridge(&self) -> &Cartridge {
+    &self.cart
+  }
+
+  fn cartridge_mut(&mut self) -> &mut Cartridge {
+    &mut self.cart
+  }
+
+  fn mapper_type(&self) -> MapperType {
+    BNROM
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::mapper::{restore, test::test_cart};
+
+  fn rom(chr_kb: usize) -> Cartridge {
+    test_cart(34, 128, chr_kb, PRG_BANK_SIZE, CHR_BANK_SIZE)
+  }
+
+  #[test]
+  fn bnrom_test() {
+    let mut mapper = BnRom::new(rom(0));
+    assert!(mapper.prg_ram().is_none());
+    mapper.write_prg(0x8000, 3);
+    assert_eq!(mapper.read_prg(0x8000), 3);
+    mapper.write_chr(0x1000, 0x42);
+    assert_eq!(mapper.read_chr(0x1000), 0x42);
+
+    // the 128KB of PRG-ROM is not part of the state
+    let state = mapper.save();
+    assert!(state.len() < PRG_BANK_SIZE);
+    let loaded: BnRom = restore(&state, mapper.cartridge()).unwrap();
+    assert_eq!(loaded.read_prg(0xFFFF), 3);
+  }
+
+  #[test]
+  fn nina001_test() {
+    let mut mapper = BnRom::new(rom(64));
+    assert!(mapper.prg_ram().is_some());
+    // ROM writes do nothing
+    mapper.write_prg(0x8000, 1);
+    assert_eq!(mapper.read_prg(0x8000), 0);
+    mapper.write_prg(0x7FFD, 1);
+    mapper.write_prg(0x7FFE, 5);
+    mapper.write_prg(0x7FFF, 9);
+    assert_eq!(mapper.read_prg(0x8000), 1);
+    assert_eq!(mapper.read_chr(0x0000), 5);
+    assert_eq!(mapper.read_chr(0x1000), 9);
+    // the registers are also RAM
+    assert_eq!(mapper.read_prg(0x7FFE), 5);
+
+    let loaded: BnRom = restore(&mapper.save(), mapper.cartridge()).unwrap();
+    assert_eq!(loaded.read_prg(0x8000), 1);
+    assert_eq!(loaded.read_chr(0x1000), 9);
+  }
+}
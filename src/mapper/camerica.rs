@@ -0,0 +1,155 @@
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::cartridge::Cartridge;
+use crate::common::*;
+
+use super::{
+  factory::{MirrorCallback, NameTableMirroring},
+  save, Mapper, MapperType, CAMERICA,
+};
+
+const PRG_BANK_SIZE: usize = 0x4000;
+
+/**
+ * Camerica/Codemasters (mapper 71): UxROM like, a 16KB PRG bank at $8000
+ * written to $C000-$FFFF and the last 16KB fixed. Fire Hawk selects one
+ * screen mirroring with bit 4 of writes to $9000-$9FFF.
+ */
+#[derive(Serialize, Deserialize)]
+pub struct Camerica {
+  #[serde(with = "serde_bytes")]
+  character_ram: Option<Vec<Byte>>,
+  cart: Cartridge,
+  #[serde(skip)]
+  mirror_cb: Option<MirrorCallback>,
+  mirroring: NameTableMirroring,
+  prg_bank: usize, // offset of rom
+}
+
+impl Camerica {
+  pub fn new(cart: Cartridge, mirror_cb: MirrorCallback) -> Self {
+    let ram = if cart.get_vrom().is_empty() {
+      Some(vec![0; cart.chr_ram_size()])
+    } else {
+      None
+    };
+    Self {
+      character_ram: ram,
+      mirroring: NameTableMirroring::from(cart.get_name_table_mirroring()),
+      cart,
+      mirror_cb: Some(mirror_cb),
+      prg_bank: 0,
+    }
+  }
+
+  pub fn set_mirror_cb(&mut self, mirror_cb: MirrorCallback) {
+    self.mirror_cb = Some(mirror_cb);
+  }
+}
+
+impl Mapper for Camerica {
+  fn write_prg(&mut self, addr: Address, value: Byte) {
+    match addr {
+      0x9000..=0x9FFF => {
+        let mirroring = if bit_eq(value, 0x10) {
+          NameTableMirroring::OneScreenHigher
+        } else {
+          NameTableMirroring::OneScreenLower
+        };
+        if mirroring != self.mirroring {
+          self.mirroring = mirroring;
+          if let Some(cb) = self.mirror_cb.as_mut() {
+            cb(mirroring.into());
+          }
+        }
+      }
+      0xC000..=0xFFFF => {
+        let banks = std::cmp::max(self.cart.get_rom().len() / PRG_BANK_SIZE, 1);
+        self.prg_bank = ((value & 0x0F) as usize % banks) * PRG_BANK_SIZE;
+      }
+      _ => {}
+    }
+  }
+
+  fn read_prg(&self, addr: Address) -> Byte {
+    let rom = self.cart.get_rom();
+    if addr < 0xC000 {
+      rom[self.prg_bank + (addr & 0x3FFF) as usize]
+    } else {
+      rom[rom.len() - PRG_BANK_SIZE + (addr & 0x3FFF) as usize]
+    }
+  }
+
+  fn write_chr(&mut self, addr: Address, value: Byte) {
+    match &mut self.character_ram {
+      Some(ram) => ram[addr as usize] = value,
+      None => warn!("Attempting to write read-only CHR memory on {:#x}", addr),
+    }
+  }
+
+  fn read_chr(&self, addr: Address) -> Byte {
+    match &self.character_ram {
+      Some(ram) => ram[addr as usize],
+      None => self.cart.get_vrom()[addr as usize],
+    }
+  }
+
+  fn has_extended_ram(&self) -> bool {
+    self.cart.has_extended_ram()
+  }
+
+  fn has_battery(&self) -> bool {
+    self.cart.has_battery()
+  }
+
+  fn get_name_table_mirroring(&self) -> u8 {
+    self.mirroring.into()
+  }
+
+  fn save(&self) -> Vec<u8> {
+    save(self)
+  }
+
+  fn cartridge(&self) -> &Cartridge {
+    &self.cart
+  }
+
+  fn cartridge_mut(&mut self) -> &mut Cartridge {
+    &mut self.cart
+  }
+
+  fn mapper_type(&self) -> MapperType {
+    CAMERICA
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::mapper::{restore, test::test_cart};
+
+  #[test]
+  fn bank_switch_test() {
+    let cart = test_cart(71, 128, 0, PRG_BANK_SIZE, 0);
+    let mut mapper = Camerica::new(cart, Box::new(|_| {}));
+    assert_eq!(mapper.read_prg(0xC000), 7);
+    // writes below $C000 do not switch banks
+    mapper.write_prg(0x8000, 3);
+    assert_eq!(mapper.read_prg(0x8000), 0);
+    mapper.write_prg(0xC000, 3);
+    assert_eq!(mapper.read_prg(0x8000), 3);
+    mapper.write_prg(0x9000, 0x10);
+    assert_eq!(
+      mapper.get_name_table_mirroring(),
+      NameTableMirroring::OneScreenHigher as u8
+    );
+
+    let loaded: Camerica = restore(&mapper.save(), mapper.cartridge()).unwrap();
+    assert_eq!(loaded.read_prg(0xBFFF), 3);
+    assert_eq!(
+      loaded.get_name_table_mirroring(),
+      NameTableMirroring::OneScreenHigher as u8
+    );
+  }
+}
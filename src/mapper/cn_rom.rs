@@ -5,7 +5,7 @@ use crate::cartridge::{Cartridge, BANK_SIZE};
 use crate::common::*;
 use crate::mapper::Mapper;
 
-use super::{CNROM, save};
+use super::{save, MapperType, CNROM};
 
 #[derive(Serialize, Deserialize)]
 pub struct CnRom {
@@ -50,15 +50,27 @@ impl Mapper for CnRom {
     self.cart.has_extended_ram()
   }
 
+  fn has_battery(&self) -> bool {
+    self.cart.has_battery()
+  }
+
   fn get_name_table_mirroring(&self) -> u8 {
     self.cart.get_name_table_mirroring()
   }
 
-  fn save(&self) -> String {
+  fn save(&self) -> Vec<u8> {
     save(self)
   }
 
-  fn mapper_type(&self) -> u8 {
+  fn cartridge(&self) -> &Cartridge {
+    &self.cart
+  }
+
+  fn cartridge_mut(&mut self) -> &mut Cartridge {
+    &mut self.cart
+  }
+
+  fn mapper_type(&self) -> MapperType {
     CNROM
   }
 }
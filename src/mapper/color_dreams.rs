@@ -0,0 +1,99 @@
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::cartridge::Cartridge;
+use crate::common::*;
+
+use super::{save, Mapper, MapperType, COLOR_DREAMS};
+
+const PRG_BANK_SIZE: usize = 0x8000;
+const CHR_BANK_SIZE: usize = 0x2000;
+
+/**
+ * Color Dreams (mapper 11): a 32KB PRG bank in bits 0-1 and an 8KB CHR bank
+ * in bits 4-7 of any write to $8000-$FFFF.
+ */
+#[derive(Serialize, Deserialize)]
+pub struct ColorDreams {
+  cart: Cartridge,
+  prg_bank: usize, // offset of rom
+  chr_bank: usize, // offset of vrom
+}
+
+impl ColorDreams {
+  pub fn new(cart: Cartridge) -> Self {
+    Self {
+      cart,
+      prg_bank: 0,
+      chr_bank: 0,
+    }
+  }
+}
+
+impl Mapper for ColorDreams {
+  fn write_prg(&mut self, _: Address, value: Byte) {
+    self.prg_bank = (value & 0x03) as usize * PRG_BANK_SIZE;
+    self.chr_bank = (value >> 4) as usize * CHR_BANK_SIZE;
+  }
+
+  fn read_prg(&self, addr: Address) -> Byte {
+    let rom = self.cart.get_rom();
+    rom[(self.prg_bank + (addr & 0x7FFF) as usize) % rom.len()]
+  }
+
+  fn write_chr(&mut self, addr: Address, _: Byte) {
+    warn!("Attempting to write read-only CHR memory on {:#x}", addr);
+  }
+
+  fn read_chr(&self, addr: Address) -> Byte {
+    let vrom = self.cart.get_vrom();
+    vrom[(self.chr_bank + addr as usize) % vrom.len()]
+  }
+
+  fn has_extended_ram(&self) -> bool {
+    self.cart.has_extended_ram()
+  }
+
+  fn has_battery(&self) -> bool {
+    self.cart.has_battery()
+  }
+
+  fn get_name_table_mirroring(&self) -> u8 {
+    self.cart.get_name_table_mirroring()
+  }
+
+  fn save(&self) -> Vec<u8> {
+    save(self)
+  }
+
+  fn cartridge(&self) -> &Cartridge {
+    &self.cart
+  }
+
+  fn cartridge_mut(&mut self) -> &mut Cartridge {
+    &mut self.cart
+  }
+
+  fn mapper_type(&self) -> MapperType {
+    COLOR_DREAMS
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::mapper::{restore, test::test_cart};
+
+  #[test]
+  fn bank_switch_test() {
+    let cart = test_cart(11, 128, 128, PRG_BANK_SIZE, CHR_BANK_SIZE);
+    let mut mapper = ColorDreams::new(cart);
+    mapper.write_prg(0x8000, 0xA3);
+    assert_eq!(mapper.read_prg(0x8000), 3);
+    assert_eq!(mapper.read_chr(0x0000), 10);
+
+    let loaded: ColorDreams = restore(&mapper.save(), mapper.cartridge()).unwrap();
+    assert_eq!(loaded.read_prg(0xFFFF), 3);
+    assert_eq!(loaded.read_chr(0x1FFF), 10);
+  }
+}
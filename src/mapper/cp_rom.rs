@@ -0,0 +1,111 @@
+use serde::{Deserialize, Serialize};
+
+use crate::cartridge::Cartridge;
+use crate::common::*;
+
+use super::{save, Mapper, MapperType, CPROM};
+
+const CHR_BANK_SIZE: usize = 0x1000;
+const CHR_RAM_SIZE: usize = 0x4000;
+
+/**
+ * CPROM (mapper 13): 32KB of fixed PRG and 16KB of CHR-RAM, the first 4KB at
+ * $0000 and a switchable 4KB at $1000 selected by bits 0-1 of any write to
+ * $8000-$FFFF.
+ */
+#[derive(Serialize, Deserialize)]
+pub struct CpRom {
+  #[serde(with = "serde_bytes")]
+  character_ram: Vec<Byte>,
+  cart: Cartridge,
+  chr_bank: usize, // offset of character_ram
+}
+
+impl CpRom {
+  pub fn new(cart: Cartridge) -> Self {
+    Self {
+      character_ram: vec![0; std::cmp::max(cart.chr_ram_size(), CHR_RAM_SIZE)],
+      cart,
+      chr_bank: 0,
+    }
+  }
+
+  fn chr_offset(&self, addr: Address) -> usize {
+    if addr < 0x1000 {
+      addr as usize
+    } else {
+      self.chr_bank + (addr as usize & (CHR_BANK_SIZE - 1))
+    }
+  }
+}
+
+impl Mapper for CpRom {
+  fn write_prg(&mut self, _: Address, value: Byte) {
+    self.chr_bank = (value & 0x03) as usize * CHR_BANK_SIZE;
+  }
+
+  fn read_prg(&self, addr: Address) -> Byte {
+    let rom = self.cart.get_rom();
+    rom[(addr & 0x7FFF) as usize % rom.len()]
+  }
+
+  fn write_chr(&mut self, addr: Address, value: Byte) {
+    let offset = self.chr_offset(addr);
+    self.character_ram[offset] = value;
+  }
+
+  fn read_chr(&self, addr: Address) -> Byte {
+    self.character_ram[self.chr_offset(addr)]
+  }
+
+  fn has_extended_ram(&self) -> bool {
+    self.cart.has_extended_ram()
+  }
+
+  fn has_battery(&self) -> bool {
+    self.cart.has_battery()
+  }
+
+  fn get_name_table_mirroring(&self) -> u8 {
+    self.cart.get_name_table_mirroring()
+  }
+
+  fn save(&self) -> Vec<u8> {
+    save(self)
+  }
+
+  fn cartridge(&self) -> &Cartridge {
+    &self.cart
+  }
+
+  fn cartridge_mut(&mut self) -> &mut Cartridge {
+    &mut self.cart
+  }
+
+  fn mapper_type(&self) -> MapperType {
+    CPROM
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::mapper::{restore, test::test_cart};
+
+  #[test]
+  fn bank_switch_test() {
+    let mut mapper = CpRom::new(test_cart(13, 32, 0, 0x8000, 0));
+    mapper.write_chr(0x0000, 0x10);
+    mapper.write_prg(0x8000, 2);
+    mapper.write_chr(0x1000, 0x12);
+    mapper.write_prg(0x8000, 1);
+    mapper.write_chr(0x1000, 0x11);
+    assert_eq!(mapper.read_chr(0x1000), 0x11);
+    assert_eq!(mapper.read_chr(0x0000), 0x10);
+
+    let mut loaded: CpRom = restore(&mapper.save(), mapper.cartridge()).unwrap();
+    assert_eq!(loaded.read_chr(0x1000), 0x11);
+    loaded.write_prg(0x8000, 2);
+    assert_eq!(loaded.read_chr(0x1000), 0x12);
+  }
+}
@@ -0,0 +1,597 @@
+use std::cell::Cell;
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::apu::Mmc5Audio;
+use crate::bus::main_bus::PPU_CTRL;
+use crate::cartridge::Cartridge;
+use crate::common::*;
+
+use super::{save, Mapper, MapperType, EXROM};
+
+const PRG_BANK_SIZE: usize = 0x2000;
+const CHR_BANK_SIZE: usize = 0x400;
+const EX_RAM_SIZE: usize = 0x400;
+// The PPU reads at least every 4 dots while rendering, a 3 CPU cycle gap
+// means it stopped.
+const IDLE_CYCLES: Byte = 3;
+const IDLE_DOTS: u64 = 9;
+
+// ExRAM modes: 0 is an extra nametable, 1 holds a CHR bank and palette for
+// every background tile, 2 and 3 are CPU RAM.
+const EX_RAM_ATTRIBUTE: Byte = 1;
+const EX_RAM_READ_WRITE: Byte = 2;
+const EX_RAM_READ_ONLY: Byte = 3;
+
+/**
+ * MMC5 (ExROM, mapper 5)
+ *
+ * Banks PRG-ROM and PRG-RAM in 8KB to 32KB units, CHR in 1KB to 8KB units
+ * with separate sprite banks for 8x16 sprites, maps each nametable to VRAM,
+ * ExRAM or a fill tile, and has a scanline IRQ, a multiplier and two extra
+ * pulse channels plus a PCM channel, see `Mmc5Audio`.
+ *
+ * Like the chip, the scanline counter watches the PPU reads reported to
+ * `notify_ppu_fetch`: three identical nametable fetches in a row start a
+ * scanline, and the PPU not reading at all means it left the frame.
+ *
+ * Reference https://www.nesdev.org/wiki/MMC5
+ */
+#[derive(Serialize, Deserialize)]
+pub struct ExRom {
+  cart: Cartridge,
+  #[serde(with = "serde_bytes")]
+  character_ram: Option<Vec<Byte>>,
+  #[serde(with = "serde_bytes")]
+  prg_ram: Vec<Byte>,
+  #[serde(with = "serde_bytes")]
+  ex_ram: Vec<Byte>,
+
+  prg_mode: Byte,
+  chr_mode: Byte,
+  ram_protect: [Byte; 2],
+  ex_ram_mode: Byte,
+  name_table_mapping: Byte,
+  fill_tile: Byte,
+  fill_color: Byte,
+  // $5113-$5117
+  prg_regs: [Byte; 5],
+  // $5120-$5127 for sprites, $5128-$512B for the background
+  chr_regs: [Address; 12],
+  chr_upper: Byte,
+  // with 8x8 sprites the CHR set written last is used for everything
+  last_chr_set_b: bool,
+  sprite_8x16: bool,
+
+  irq_target: Byte,
+  irq_enabled: bool,
+  irq_pending: bool,
+  in_frame: bool,
+  scanline: Byte,
+  last_fetch: Address,
+  fetch_matches: Byte,
+  last_fetch_dot: u64,
+  // CPU cycles since the last PPU read
+  idle_cycles: Byte,
+
+  multiplicand: Byte,
+  multiplier: Byte,
+
+  audio: Mmc5Audio,
+
+  // ExRAM index of the last background tile the renderer fetched, for
+  // extended attributes
+  #[serde(skip)]
+  last_tile: Cell<usize>,
+}
+
+impl ExRom {
+  pub fn new(cart: Cartridge) -> Self {
+    let character_ram = if cart.get_vrom().is_empty() {
+      Some(vec![0; cart.chr_ram_size()])
+    } else {
+      None
+    };
+    let header = cart.header();
+    // iNES 1.0 can't tell, give the largest board
+    let prg_ram_size = if header.nes2 {
+      std::cmp::max(header.prg_ram_size + header.prg_nvram_size, PRG_BANK_SIZE)
+    } else {
+      0x10000
+    };
+    Self {
+      cart,
+      character_ram,
+      prg_ram: vec![0; prg_ram_size],
+      ex_ram: vec![0; EX_RAM_SIZE],
+      prg_mode: 3,
+      chr_mode: 0,
+      ram_protect: [0; 2],
+      ex_ram_mode: 0,
+      name_table_mapping: 0,
+      fill_tile: 0,
+      fill_color: 0,
+      prg_regs: [0, 0xFF, 0xFF, 0xFF, 0xFF],
+      chr_regs: [0; 12],
+      chr_upper: 0,
+      last_chr_set_b: false,
+      sprite_8x16: false,
+      irq_target: 0,
+      irq_enabled: false,
+      irq_pending: false,
+      in_frame: false,
+      scanline: 0,
+      last_fetch: 0,
+      fetch_matches: 0,
+      last_fetch_dot: 0,
+      idle_cycles: IDLE_CYCLES,
+      multiplicand: 0xFF,
+      multiplier: 0xFF,
+      audio: Mmc5Audio::new(),
+      last_tile: Cell::new(0),
+    }
+  }
+
+  // the first scanline after leaving the frame only restarts the count
+  fn clock_scanline(&mut self) {
+    if !self.in_frame {
+      self.in_frame = true;
+      self.scanline = 0;
+      return;
+    }
+    self.scanline = self.scanline.wrapping_add(1);
+    if self.irq_target != 0 && self.scanline == self.irq_target {
+      self.irq_pending = true;
+    }
+  }
+
+  /// Where an address of $6000-$FFFF points to, ROM or RAM and the offset.
+  fn prg_offset(&self, addr: Address) -> (bool, usize) {
+    if addr < 0x8000 {
+      let bank = (self.prg_regs[0] & 0x7F) as usize;
+      return (false, bank * PRG_BANK_SIZE + (addr & 0x1FFF) as usize);
+    }
+    let slot = ((addr - 0x8000) >> 13) as usize;
+    // register index and bank size in 8KB units
+    let (index, size) = match self.prg_mode {
+      0 => (4, 4),
+      1 => (if slot < 2 { 2 } else { 4 }, 2),
+      2 => match slot {
+        0 | 1 => (2, 2),
+        2 => (3, 1),
+        _ => (4, 1),
+      },
+      _ => (slot + 1, 1),
+    };
+    let reg = self.prg_regs[index];
+    // $5117 always maps ROM
+    let rom = index == 4 || bit_eq(reg, 0x80);
+    let bank = ((reg & 0x7F) as usize & !(size - 1)) | (slot % size);
+    (rom, bank * PRG_BANK_SIZE + (addr & 0x1FFF) as usize)
+  }
+
+  fn prg_ram_writable(&self) -> bool {
+    self.ram_protect == [2, 1]
+  }
+
+  fn chr_offset(&self, addr: Address, sprite: bool) -> usize {
+    // extended attributes bank every background tile on their own
+    if !sprite && self.ex_ram_mode == EX_RAM_ATTRIBUTE {
+      let tile = self.ex_ram[self.last_tile.get()];
+      let bank = (tile & 0x3F) as usize | (self.chr_upper as usize) << 6;
+      return bank * 0x1000 + (addr & 0xFFF) as usize;
+    }
+    let use_b = if self.sprite_8x16 {
+      !sprite
+    } else {
+      self.last_chr_set_b
+    };
+    let slot = (addr >> 10) as usize & 7;
+    // bank size in 1KB units
+    let size = 8 >> self.chr_mode;
+    let reg = if use_b {
+      // the background set repeats for both pattern tables
+      self.chr_regs[8 + ((slot & 3) | std::cmp::min(size - 1, 3))]
+    } else {
+      self.chr_regs[slot | (size - 1)]
+    };
+    (reg as usize * size + slot % size) * CHR_BANK_SIZE + (addr as usize & (CHR_BANK_SIZE - 1))
+  }
+
+  fn read_chr_bank(&self, addr: Address, sprite: bool) -> Byte {
+    match &self.character_ram {
+      Some(ram) => ram[addr as usize],
+      None => {
+        let vrom = self.cart.get_vrom();
+        vrom[self.chr_offset(addr, sprite) % vrom.len()]
+      }
+    }
+  }
+}
+
+impl Mapper for ExRom {
+  fn write_prg(&mut self, addr: Address, value: Byte) {
+    let (rom, offset) = self.prg_offset(addr);
+    if !rom && self.prg_ram_writable() {
+      let len = self.prg_ram.len();
+      self.prg_ram[offset % len] = value;
+    }
+  }
+
+  fn read_prg(&self, addr: Address) -> Byte {
+    if addr < 0x6000 {
+      return 0;
+    }
+    match self.prg_offset(addr) {
+      (true, offset) => {
+        let rom = self.cart.get_rom();
+        rom[offset % rom.len()]
+      }
+      (false, offset) => self.prg_ram[offset % self.prg_ram.len()],
+    }
+  }
+
+  fn write_chr(&mut self, addr: Address, value: Byte) {
+    match &mut self.character_ram {
+      Some(ram) => ram[addr as usize] = value,
+      None => warn!("Attempting to write read-only CHR memory on {:#x}", addr),
+    }
+  }
+
+  fn read_chr(&self, addr: Address) -> Byte {
+    self.read_chr_bank(addr, false)
+  }
+
+  fn read_sprite_chr(&self, addr: Address) -> Byte {
+    self.read_chr_bank(addr, true)
+  }
+
+  fn maps_nametables(&self) -> bool {
+    true
+  }
+
+  fn read_nametable(&self, addr: Address, ciram: &[Byte]) -> Byte {
+    let offset = (addr & 0x3FF) as usize;
+    let attribute = offset >= 0x3C0;
+    match (self.name_table_mapping >> (((addr >> 10) & 3) * 2)) & 3 {
+      0 => ciram[offset],
+      1 => ciram[0x400 + offset],
+      2 if self.ex_ram_mode <= EX_RAM_ATTRIBUTE => self.ex_ram[offset],
+      2 => 0,
+      _ if attribute => self.fill_color * 0x55,
+      _ => self.fill_tile,
+    }
+  }
+
+  fn fetch_nametable(&self, addr: Address, ciram: &[Byte]) -> Byte {
+    let offset = (addr & 0x3FF) as usize;
+    if offset < 0x3C0 {
+      self.last_tile.set(offset);
+    } else if self.ex_ram_mode == EX_RAM_ATTRIBUTE {
+      // the same palette for all four quadrants
+      return (self.ex_ram[self.last_tile.get()] >> 6) * 0x55;
+    }
+    self.read_nametable(addr, ciram)
+  }
+
+  fn write_nametable(&mut self, addr: Address, value: Byte, ciram: &mut [Byte]) {
+    let offset = (addr & 0x3FF) as usize;
+    match (self.name_table_mapping >> (((addr >> 10) & 3) * 2)) & 3 {
+      0 => ciram[offset] = value,
+      1 => ciram[0x400 + offset] = value,
+      2 if self.ex_ram_mode <= EX_RAM_ATTRIBUTE => self.ex_ram[offset] = value,
+      _ => {}
+    }
+  }
+
+  fn read_register(&mut self, addr: Address) -> Option<Byte> {
+    match addr {
+      0x5010 => Some(self.audio.read_pcm_status()),
+      0x5015 => Some(self.audio.read_status()),
+      0x5204 => {
+        let in_frame = self.in_frame && self.idle_cycles < IDLE_CYCLES;
+        let status = (self.irq_pending as Byte) << 7 | (in_frame as Byte) << 6;
+        self.irq_pending = false;
+        Some(status)
+      }
+      0x5205 => Some((self.multiplicand as Address * self.multiplier as Address) as Byte),
+      0x5206 => Some(((self.multiplicand as Address * self.multiplier as Address) >> 8) as Byte),
+      0x5C00..=0x5FFF if self.ex_ram_mode >= EX_RAM_READ_WRITE => {
+        Some(self.ex_ram[(addr - 0x5C00) as usize])
+      }
+      _ => None,
+    }
+  }
+
+  fn write_register(&mut self, addr: Address, value: Byte) {
+    match addr {
+      PPU_CTRL => self.sprite_8x16 = bit_eq(value, 0x20),
+      0x5000..=0x5015 => self.audio.write(addr, value),
+      0x5100 => self.prg_mode = value & 3,
+      0x5101 => self.chr_mode = value & 3,
+      0x5102 => self.ram_protect[0] = value & 3,
+      0x5103 => self.ram_protect[1] = value & 3,
+      0x5104 => self.ex_ram_mode = value & 3,
+      0x5105 => self.name_table_mapping = value,
+      0x5106 => self.fill_tile = value,
+      0x5107 => self.fill_color = value & 3,
+      0x5113..=0x5117 => self.prg_regs[(addr - 0x5113) as usize] = value,
+      0x5120..=0x512B => {
+        let index = (addr - 0x5120) as usize;
+        self.chr_regs[index] = value as Address | (self.chr_upper as Address) << 8;
+        self.last_chr_set_b = index >= 8;
+      }
+      0x5130 => self.chr_upper = value & 3,
+      0x5203 => self.irq_target = value,
+      0x5204 => self.irq_enabled = bit_eq(value, 0x80),
+      0x5205 => self.multiplicand = value,
+      0x5206 => self.multiplier = value,
+      0x5C00..=0x5FFF if self.ex_ram_mode != EX_RAM_READ_ONLY => {
+        self.ex_ram[(addr - 0x5C00) as usize] = value
+      }
+      _ => {}
+    }
+  }
+
+  fn watch_cpu_reads(&self) -> bool {
+    true
+  }
+
+  fn notify_cpu_read(&mut self, addr: Address, value: Byte) {
+    if addr < 0xC000 {
+      self.audio.read_sample(value);
+    }
+  }
+
+  fn prg_ram(&self) -> Option<&[Byte]> {
+    Some(&self.prg_ram)
+  }
+
+  fn prg_ram_mut(&mut self) -> Option<&mut [Byte]> {
+    Some(&mut self.prg_ram)
+  }
+
+  fn has_expansion_audio(&self) -> bool {
+    true
+  }
+
+  fn step_audio(&mut self) {
+    self.audio.step();
+  }
+
+  fn audio_output(&self) -> f32 {
+    self.audio.output()
+  }
+
+  fn watch_ppu_fetches(&self) -> bool {
+    true
+  }
+
+  fn notify_ppu_fetch(&mut self, addr: Address, dot: u64) {
+    // `cpu_clock` runs ahead of the PPU for a whole instruction, so the gap
+    // is measured in dots here
+    let idle = dot.wrapping_sub(self.last_fetch_dot) > IDLE_DOTS;
+    if idle {
+      self.in_frame = false;
+    }
+    self.last_fetch_dot = dot;
+    self.idle_cycles = 0;
+    if !idle && addr & 0xF000 == 0x2000 && addr == self.last_fetch {
+      self.fetch_matches += 1;
+      if self.fetch_matches == 2 {
+        self.clock_scanline();
+      }
+    } else {
+      self.fetch_matches = 0;
+    }
+    self.last_fetch = addr;
+  }
+
+  fn cpu_clock(&mut self) {
+    self.idle_cycles = std::cmp::min(self.idle_cycles + 1, IDLE_CYCLES);
+  }
+
+  // reading $5204 acknowledges the scanline IRQ, $5010 the PCM one
+  fn irq_pending(&self) -> bool {
+    self.irq_pending && self.irq_enabled || self.audio.irq_pending()
+  }
+
+  fn has_extended_ram(&self) -> bool {
+    // PRG-RAM lives in the mapper, see `prg_ram`
+    false
+  }
+
+  fn has_battery(&self) -> bool {
+    self.cart.has_battery()
+  }
+
+  fn get_name_table_mirroring(&self) -> u8 {
+    self.cart.get_name_table_mirroring()
+  }
+
+  fn save(&self) -> Vec<u8> {
+    save(self)
+  }
+
+  fn cartridge(&self) -> &Cartridge {
+    &self.cart
+  }
+
+  fn cartridge_mut(&mut self) -> &mut Cartridge {
+    &mut self.cart
+  }
+
+  fn mapper_type(&self) -> MapperType {
+    EXROM
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::mapper::test::{load, test_rom};
+
+  fn mmc5_rom() -> Cartridge {
+    let mut rom = test_rom(5, 128, 128, PRG_BANK_SIZE, CHR_BANK_SIZE);
+    // NES 2.0 with 8KB of PRG-RAM
+    rom[7] |= 0x08;
+    rom[10] = 0x07;
+    load(&rom)
+  }
+
+  #[test]
+  fn prg_test() {
+    let mut mapper = ExRom::new(mmc5_rom());
+    assert_eq!(mapper.prg_ram.len(), PRG_BANK_SIZE);
+    assert_eq!(mapper.read_prg(0xFFFC), 15);
+    mapper.write_register(0x5100, 0);
+    mapper.write_register(0x5117, 0x85);
+    assert_eq!(mapper.read_prg(0x8000), 4);
+    assert_eq!(mapper.read_prg(0xE000), 7);
+
+    mapper.write_register(0x5100, 2);
+    mapper.write_register(0x5115, 0x83);
+    mapper.write_register(0x5116, 0x89);
+    assert_eq!(mapper.read_prg(0x8000), 2);
+    assert_eq!(mapper.read_prg(0xA000), 3);
+    assert_eq!(mapper.read_prg(0xC000), 9);
+    assert_eq!(mapper.read_prg(0xE000), 5);
+
+    // RAM at $C000 is write protected until $5102/$5103 unlock it
+    mapper.write_register(0x5116, 0x00);
+    mapper.write_prg(0xC000, 0x42);
+    assert_eq!(mapper.read_prg(0xC000), 0);
+    mapper.write_register(0x5102, 2);
+    mapper.write_register(0x5103, 1);
+    mapper.write_prg(0xC000, 0x42);
+    assert_eq!(mapper.read_prg(0xC000), 0x42);
+    assert_eq!(mapper.read_prg(0x6000), 0x42);
+    assert_eq!(mapper.prg_ram().unwrap()[0], 0x42);
+  }
+
+  #[test]
+  fn chr_test() {
+    let mut mapper = ExRom::new(mmc5_rom());
+    mapper.write_register(0x5101, 3);
+    for i in 0..8 {
+      mapper.write_register(0x5120 + i, 10 + i as Byte);
+    }
+    assert_eq!(mapper.read_chr(0x0C00), 13);
+    for i in 0..4 {
+      mapper.write_register(0x5128 + i, 20 + i as Byte);
+    }
+    // 8x8 sprites use the set written last
+    assert_eq!(mapper.read_sprite_chr(0x1400), 21);
+
+    mapper.write_register(PPU_CTRL, 0x20);
+    assert_eq!(mapper.read_sprite_chr(0x1400), 15);
+    assert_eq!(mapper.read_chr(0x1400), 21);
+
+    mapper.write_register(0x5101, 1);
+    assert_eq!(mapper.read_sprite_chr(0x0000), 13 * 4);
+    assert_eq!(mapper.read_sprite_chr(0x1C00), 17 * 4 + 3);
+    assert_eq!(mapper.read_chr(0x1800), 23 * 4 + 2);
+  }
+
+  #[test]
+  fn name_table_test() {
+    let mut mapper = ExRom::new(mmc5_rom());
+    let mut ciram = vec![0; 0x800];
+    // CIRAM A, CIRAM B, ExRAM, fill
+    mapper.write_register(0x5105, 0b11_10_01_00);
+    mapper.write_register(0x5106, 0x33);
+    mapper.write_register(0x5107, 2);
+    mapper.write_nametable(0x2001, 1, &mut ciram);
+    mapper.write_nametable(0x2401, 2, &mut ciram);
+    mapper.write_nametable(0x2801, 3, &mut ciram);
+    assert_eq!(ciram[0x001], 1);
+    assert_eq!(ciram[0x401], 2);
+    assert_eq!(mapper.ex_ram[1], 3);
+    assert_eq!(mapper.read_nametable(0x2C01, &ciram), 0x33);
+    assert_eq!(mapper.read_nametable(0x2FC0, &ciram), 0xAA);
+
+    // extended attributes: palette 1 and 4KB CHR bank 5 for tile 1, whatever
+    // CHR set was written last with 8x8 sprites
+    mapper.write_register(0x5104, EX_RAM_ATTRIBUTE);
+    mapper.write_register(0x5C01, 0x45);
+    mapper.write_register(0x5C02, 0x86);
+    mapper.write_register(0x5101, 3);
+    mapper.write_register(0x5128, 0);
+    mapper.write_register(0x5121, 9);
+    mapper.fetch_nametable(0x2001, &ciram);
+    assert_eq!(mapper.fetch_nametable(0x23C0, &ciram), 0x55);
+    assert_eq!(mapper.read_chr(0x0400), 5 * 4 + 1);
+    assert_eq!(mapper.read_sprite_chr(0x0400), 9);
+
+    // $2007 reads don't move the tile being drawn
+    assert_eq!(mapper.read_nametable(0x2002, &ciram), 0);
+    assert_eq!(mapper.fetch_nametable(0x23C0, &ciram), 0x55);
+    assert_eq!(mapper.read_chr(0x0400), 5 * 4 + 1);
+  }
+
+  #[test]
+  fn irq_test() {
+    let mut mapper = ExRom::new(mmc5_rom());
+    mapper.write_register(0x5205, 200);
+    mapper.write_register(0x5206, 100);
+    assert_eq!(mapper.read_register(0x5205), Some((20000 & 0xFF) as Byte));
+    assert_eq!(mapper.read_register(0x5206), Some((20000 >> 8) as Byte));
+
+    mapper.write_register(0x5203, 10);
+    mapper.write_register(0x5204, 0x80);
+    // pre-render line, then scanlines 0 to 9
+    for line in 0..11 {
+      render_line(&mut mapper, line);
+    }
+    assert!(!mapper.irq_pending());
+    assert_eq!(mapper.read_register(0x5204), Some(0x40));
+    render_line(&mut mapper, 11);
+    assert!(mapper.irq_pending());
+    assert_eq!(mapper.read_register(0x5204), Some(0xC0));
+    assert!(!mapper.irq_pending());
+    // rendering stops
+    mapper.cpu_clock();
+    mapper.cpu_clock();
+    assert_eq!(mapper.read_register(0x5204), Some(0x40));
+    mapper.cpu_clock();
+    assert_eq!(mapper.read_register(0x5204), Some(0x00));
+    // and the next frame counts from its first scanline again
+    for line in 100..111 {
+      render_line(&mut mapper, line);
+    }
+    assert!(!mapper.irq_pending());
+    render_line(&mut mapper, 111);
+    assert!(mapper.irq_pending());
+  }
+
+  // The fetches `Ppu::report_fetch` makes from dot 1 to dot 339 of `line`,
+  // the first nametable fetch of the next line makes three in a row.
+  fn render_line(mapper: &mut ExRom, line: u64) {
+    let dot = line * 341;
+    for tile in 0..42 {
+      mapper.notify_ppu_fetch(0x2000 + tile, dot + tile as u64 * 8 + 1);
+      mapper.notify_ppu_fetch(0x1000 + tile * 16, dot + tile as u64 * 8 + 5);
+    }
+    mapper.notify_ppu_fetch(0x2000, dot + 337);
+    mapper.notify_ppu_fetch(0x2000, dot + 339);
+  }
+
+  #[test]
+  fn pcm_read_mode_test() {
+    let mut mapper = ExRom::new(mmc5_rom());
+    // read mode with the IRQ enabled
+    mapper.write_register(0x5010, 0x81);
+    mapper.write_register(0x5011, 0x40);
+    assert_eq!(mapper.audio_output(), 0.0);
+    mapper.notify_cpu_read(0x8000, 0xFF);
+    assert!(mapper.audio_output() > 0.0);
+    // $C000-$FFFF is not sampled
+    mapper.notify_cpu_read(0xC000, 0);
+    assert!(!mapper.irq_pending());
+    mapper.notify_cpu_read(0xBFFF, 0);
+    assert!(mapper.irq_pending());
+    assert_eq!(mapper.read_register(0x5010), Some(0x80));
+    assert!(!mapper.irq_pending());
+  }
+}
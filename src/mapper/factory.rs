@@ -1,20 +1,36 @@
 use num_enum::{FromPrimitive, IntoPrimitive};
 use serde::{Deserialize, Serialize};
 
-use crate::cartridge::Cartridge;
-use crate::common::Byte;
+use crate::cartridge::{Cartridge, LoadError};
+use crate::mapper::ax_rom::AxRom;
+use crate::mapper::bn_rom::BnRom;
+use crate::mapper::camerica::Camerica;
 use crate::mapper::cn_rom::CnRom;
+use crate::mapper::color_dreams::ColorDreams;
+use crate::mapper::cp_rom::CpRom;
+use crate::mapper::ex_rom::ExRom;
+use crate::mapper::fme7::Fme7;
+use crate::mapper::gx_rom::GxRom;
 use crate::mapper::n_rom::NRom;
+use crate::mapper::namco163::Namco163;
+use crate::mapper::px_rom::PxRom;
 use crate::mapper::ux_rom::UxRom;
+use crate::mapper::vrc4::Vrc4;
+use crate::mapper::vrc6::Vrc6;
+use crate::mapper::vrc7::Vrc7;
 use crate::mapper::sx_rom::SxRom;
 use crate::mapper::tx_rom::TxRom;
-use crate::mapper::Mapper;
+use crate::mapper::{restore, Mapper};
+use crate::NesResult;
 use std::{cell::RefCell, rc::Rc};
 
-use super::{NROM, SXROM, UXROM, CNROM, TXROM};
+use super::{
+  MapperType, AXROM, BNROM, CAMERICA, CNROM, COLOR_DREAMS, CPROM, EXROM, FME7, FXROM, GXROM,
+  NAMCO163, NROM, PXROM, SXROM, TQROM, TXROM, TXSROM, UXROM, VRC2A, VRC2B, VRC4A, VRC4B, VRC6A,
+  VRC6B, VRC7,
+};
 
-pub type MirrorCallback = Box<dyn FnMut(u8) -> ()>;
-pub type IRQCallback = Box<dyn FnMut() -> ()>;
+pub type MirrorCallback = Box<dyn FnMut(u8)>;
 
 #[derive(
   Default, Debug, Clone, Copy, IntoPrimitive, FromPrimitive, PartialEq, Serialize, Deserialize,
@@ -32,54 +48,152 @@ pub enum NameTableMirroring {
 pub fn create_mapper<'a>(
   cartridge: Cartridge,
   mirror_cb: MirrorCallback,
-  irq_cb: IRQCallback,
-) -> Rc<RefCell<dyn Mapper + 'a>> {
+) -> NesResult<Rc<RefCell<dyn Mapper + 'a>>> {
   let mapper_type = cartridge.get_mapper();
-  match mapper_type {
+  log::info!(
+    "Create mapper {} (submapper {})",
+    mapper_type,
+    cartridge.header().submapper
+  );
+  let mapper: Rc<RefCell<dyn Mapper + 'a>> = match mapper_type {
     NROM => Rc::new(RefCell::new(NRom::new(cartridge))),
     SXROM => Rc::new(RefCell::new(SxRom::new(cartridge, mirror_cb))),
     UXROM => Rc::new(RefCell::new(UxRom::new(cartridge))),
     CNROM => Rc::new(RefCell::new(CnRom::new(cartridge))),
-    TXROM => Rc::new(RefCell::new(TxRom::new(cartridge, mirror_cb, irq_cb))),
+    TXROM | TXSROM | TQROM => Rc::new(RefCell::new(TxRom::new(cartridge, mirror_cb))),
+    EXROM => Rc::new(RefCell::new(ExRom::new(cartridge))),
+    AXROM => Rc::new(RefCell::new(AxRom::new(cartridge, mirror_cb))),
+    PXROM => Rc::new(RefCell::new(PxRom::new(cartridge, false, mirror_cb))),
+    FXROM => Rc::new(RefCell::new(PxRom::new(cartridge, true, mirror_cb))),
+    VRC4A | VRC2A | VRC2B | VRC4B => Rc::new(RefCell::new(Vrc4::new(cartridge, mirror_cb))),
+    VRC6A | VRC6B => Rc::new(RefCell::new(Vrc6::new(cartridge, mapper_type == VRC6B, mirror_cb))),
+    VRC7 => Rc::new(RefCell::new(Vrc7::new(cartridge, mirror_cb))),
+    NAMCO163 => Rc::new(RefCell::new(Namco163::new(cartridge))),
+    FME7 => Rc::new(RefCell::new(Fme7::new(cartridge, mirror_cb))),
+    COLOR_DREAMS => Rc::new(RefCell::new(ColorDreams::new(cartridge))),
+    CPROM => Rc::new(RefCell::new(CpRom::new(cartridge))),
+    BNROM => Rc::new(RefCell::new(BnRom::new(cartridge))),
+    GXROM => Rc::new(RefCell::new(GxRom::new(cartridge))),
+    CAMERICA => Rc::new(RefCell::new(Camerica::new(cartridge, mirror_cb))),
     _ => {
-      panic!("invalid mapper type received {}", mapper_type);
+      log::error!("invalid mapper type received {}", mapper_type);
+      return Err(LoadError::UnsupportedMapper(mapper_type).into());
     }
-  }
+  };
+  Ok(mapper)
 }
 
 pub fn load_mapper<'a>(
-  mapper_type: Byte,
-  serialized: &str,
+  mapper_type: MapperType,
+  state: &[u8],
+  rom: &Cartridge,
   mirror_cb: MirrorCallback,
-  irq_cb: IRQCallback,
-) -> Rc<RefCell<dyn Mapper + 'a>> {
-  match mapper_type {
+) -> NesResult<Rc<RefCell<dyn Mapper + 'a>>> {
+  let mapper: Rc<RefCell<dyn Mapper + 'a>> = match mapper_type {
     NROM => {
-      let mapper_typed: NRom = serde_json::from_str(serialized).unwrap();
+      let mapper_typed: NRom = restore(state, rom)?;
       Rc::new(RefCell::new(mapper_typed))
     }
     SXROM => {
-      let mut mapper_typed: SxRom = serde_json::from_str(serialized).unwrap();
+      let mut mapper_typed: SxRom = restore(state, rom)?;
       mapper_typed.set_mirror_cb(mirror_cb);
       Rc::new(RefCell::new(mapper_typed))
     }
     UXROM => {
-      let mapper_typed: UxRom = serde_json::from_str(serialized).unwrap();
+      let mapper_typed: UxRom = restore(state, rom)?;
       Rc::new(RefCell::new(mapper_typed))
     }
     CNROM => {
-      let mapper_typed: CnRom = serde_json::from_str(serialized).unwrap();
+      let mapper_typed: CnRom = restore(state, rom)?;
+      Rc::new(RefCell::new(mapper_typed))
+    }
+    TXROM | TXSROM | TQROM => {
+      let mut mapper_typed: TxRom = restore(state, rom)?;
+      mapper_typed.set_mirror_cb(mirror_cb);
+      Rc::new(RefCell::new(mapper_typed))
+    }
+    EXROM => {
+      let mapper_typed: ExRom = restore(state, rom)?;
       Rc::new(RefCell::new(mapper_typed))
     }
-    // TODO TxRom
-    TXROM => {
-      let mut mapper_typed: TxRom = serde_json::from_str(serialized).unwrap();
+    AXROM => {
+      let mut mapper_typed: AxRom = restore(state, rom)?;
+      mapper_typed.set_mirror_cb(mirror_cb);
+      Rc::new(RefCell::new(mapper_typed))
+    }
+    PXROM | FXROM => {
+      let mut mapper_typed: PxRom = restore(state, rom)?;
+      mapper_typed.set_mirror_cb(mirror_cb);
+      Rc::new(RefCell::new(mapper_typed))
+    }
+    VRC4A | VRC2A | VRC2B | VRC4B => {
+      let mut mapper_typed: Vrc4 = restore(state, rom)?;
+      mapper_typed.set_mirror_cb(mirror_cb);
+      Rc::new(RefCell::new(mapper_typed))
+    }
+    VRC6A | VRC6B => {
+      let mut mapper_typed: Vrc6 = restore(state, rom)?;
+      mapper_typed.set_mirror_cb(mirror_cb);
+      Rc::new(RefCell::new(mapper_typed))
+    }
+    VRC7 => {
+      let mut mapper_typed: Vrc7 = restore(state, rom)?;
+      mapper_typed.set_mirror_cb(mirror_cb);
+      Rc::new(RefCell::new(mapper_typed))
+    }
+    NAMCO163 => {
+      let mapper_typed: Namco163 = restore(state, rom)?;
+      Rc::new(RefCell::new(mapper_typed))
+    }
+    FME7 => {
+      let mut mapper_typed: Fme7 = restore(state, rom)?;
+      mapper_typed.set_mirror_cb(mirror_cb);
+      Rc::new(RefCell::new(mapper_typed))
+    }
+    COLOR_DREAMS => {
+      let mapper_typed: ColorDreams = restore(state, rom)?;
+      Rc::new(RefCell::new(mapper_typed))
+    }
+    CPROM => {
+      let mapper_typed: CpRom = restore(state, rom)?;
+      Rc::new(RefCell::new(mapper_typed))
+    }
+    BNROM => {
+      let mapper_typed: BnRom = restore(state, rom)?;
+      Rc::new(RefCell::new(mapper_typed))
+    }
+    GXROM => {
+      let mapper_typed: GxRom = restore(state, rom)?;
+      Rc::new(RefCell::new(mapper_typed))
+    }
+    CAMERICA => {
+      let mut mapper_typed: Camerica = restore(state, rom)?;
       mapper_typed.set_mirror_cb(mirror_cb);
-      mapper_typed.set_irq_cb(irq_cb);
       Rc::new(RefCell::new(mapper_typed))
     }
     _ => {
-      panic!("invalid mapper type received {}", mapper_type);
+      return Err(LoadError::UnsupportedMapper(mapper_type).into());
     }
+  };
+  Ok(mapper)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn unsupported_mapper_test() {
+    // 16KB PRG, mapper 255
+    let mut rom = b"NES\x1A\x01\x00\xF0\xF0\x00\x00\x00\x00\x00\x00\x00\x00".to_vec();
+    rom.resize(16 + 0x4000, 0);
+    let mut cart = Cartridge::new();
+    cart.load_from_data(&rom).unwrap();
+    let err = create_mapper(cart, Box::new(|_| {})).err().unwrap();
+    assert_eq!(
+      err.downcast_ref::<LoadError>(),
+      Some(&LoadError::UnsupportedMapper(255))
+    );
+    assert_eq!(err.to_string(), "unsupported mapper 255");
   }
 }
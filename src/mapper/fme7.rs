@@ -0,0 +1,329 @@
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::apu::Sunsoft5bAudio;
+use crate::cartridge::Cartridge;
+use crate::common::*;
+
+use super::{
+  factory::{MirrorCallback, NameTableMirroring},
+  save, Mapper, MapperType, FME7,
+};
+
+const PRG_BANK_SIZE: usize = 0x2000;
+const CHR_BANK_SIZE: usize = 0x400;
+
+/**
+ * Sunsoft FME-7 and 5B (mapper 69)
+ *
+ * A command register at $8000 and its parameter at $A000 select eight 1KB CHR
+ * banks, four 8KB PRG banks with the one at $6000 either ROM or RAM, the
+ * mirroring and a 16-bit IRQ counter decremented by every CPU cycle. The 5B
+ * adds a sound chip at $C000 and $E000.
+ *
+ * Reference https://www.nesdev.org/wiki/Sunsoft_FME-7
+ */
+#[derive(Serialize, Deserialize)]
+pub struct Fme7 {
+  #[serde(with = "serde_bytes")]
+  character_ram: Option<Vec<Byte>>,
+  #[serde(with = "serde_bytes")]
+  prg_ram: Vec<Byte>,
+  cart: Cartridge,
+  #[serde(skip)]
+  mirror_cb: Option<MirrorCallback>,
+  mirroring: NameTableMirroring,
+
+  command: Byte,
+  chr_banks: [usize; 8], // offsets of vrom
+  prg_banks: [usize; 4], // offsets of rom, the first one of RAM if `ram_selected`
+  ram_selected: bool,
+  ram_enabled: bool,
+
+  irq_enabled: bool,
+  irq_counter_enabled: bool,
+  irq_counter: Address,
+  irq_pending: bool,
+
+  audio: Sunsoft5bAudio,
+}
+
+impl Fme7 {
+  pub fn new(cart: Cartridge, mirror_cb: MirrorCallback) -> Self {
+    let ram = if cart.get_vrom().is_empty() {
+      Some(vec![0; cart.chr_ram_size()])
+    } else {
+      None
+    };
+    Self {
+      character_ram: ram,
+      prg_ram: vec![0; PRG_BANK_SIZE],
+      mirroring: NameTableMirroring::from(cart.get_name_table_mirroring()),
+      cart,
+      mirror_cb: Some(mirror_cb),
+      command: 0,
+      chr_banks: [0; 8],
+      prg_banks: [0; 4],
+      ram_selected: false,
+      ram_enabled: false,
+      irq_enabled: false,
+      irq_counter_enabled: false,
+      irq_counter: 0,
+      irq_pending: false,
+      audio: Sunsoft5bAudio::new(),
+    }
+  }
+
+  pub fn set_mirror_cb(&mut self, mirror_cb: MirrorCallback) {
+    self.mirror_cb = Some(mirror_cb);
+  }
+
+  fn prg_offset(&self, value: Byte) -> usize {
+    let banks = std::cmp::max(self.cart.get_rom().len() / PRG_BANK_SIZE, 1);
+    ((value & 0x3F) as usize % banks) * PRG_BANK_SIZE
+  }
+
+  fn write_parameter(&mut self, value: Byte) {
+    match self.command {
+      0..=7 => {
+        let banks = std::cmp::max(self.cart.get_vrom().len() / CHR_BANK_SIZE, 1);
+        self.chr_banks[self.command as usize] = (value as usize % banks) * CHR_BANK_SIZE;
+      }
+      8 => {
+        self.ram_selected = bit_eq(value, 0x40);
+        self.ram_enabled = bit_eq(value, 0x80);
+        self.prg_banks[0] = if self.ram_selected {
+          0
+        } else {
+          self.prg_offset(value)
+        };
+      }
+      9..=0x0B => self.prg_banks[(self.command - 8) as usize] = self.prg_offset(value),
+      0x0C => {
+        let mirroring = match value & 0x03 {
+          0 => NameTableMirroring::Vertical,
+          1 => NameTableMirroring::Horizontal,
+          2 => NameTableMirroring::OneScreenLower,
+          _ => NameTableMirroring::OneScreenHigher,
+        };
+        if mirroring != self.mirroring {
+          self.mirroring = mirroring;
+          if let Some(cb) = self.mirror_cb.as_mut() {
+            cb(mirroring.into());
+          }
+        }
+      }
+      // writing the IRQ control acknowledges the IRQ
+      0x0D => {
+        self.irq_enabled = bit_eq(value, 0x01);
+        self.irq_counter_enabled = bit_eq(value, 0x80);
+        self.irq_pending = false;
+      }
+      0x0E => self.irq_counter = (self.irq_counter & 0xFF00) | value as Address,
+      _ => self.irq_counter = (self.irq_counter & 0x00FF) | (value as Address) << 8,
+    }
+  }
+}
+
+impl Mapper for Fme7 {
+  fn write_prg(&mut self, addr: Address, value: Byte) {
+    match addr {
+      0x6000..=0x7FFF => {
+        if self.ram_selected && self.ram_enabled {
+          self.prg_ram[(addr & 0x1FFF) as usize] = value;
+        }
+      }
+      0x8000..=0x9FFF => self.command = value & 0x0F,
+      0xA000..=0xBFFF => self.write_parameter(value),
+      0xC000..=0xDFFF => self.audio.write_address(value),
+      _ => self.audio.write_data(value),
+    }
+  }
+
+  fn read_prg(&self, addr: Address) -> Byte {
+    let rom = self.cart.get_rom();
+    match addr {
+      0x6000..=0x7FFF if self.ram_selected && self.ram_enabled => {
+        self.prg_ram[(addr & 0x1FFF) as usize]
+      }
+      // open bus
+      0x6000..=0x7FFF if self.ram_selected => 0,
+      0x6000..=0xDFFF => {
+        let bank = self.prg_banks[((addr - 0x6000) >> 13) as usize];
+        rom[bank + (addr & 0x1FFF) as usize]
+      }
+      0xE000..=0xFFFF => rom[rom.len() - PRG_BANK_SIZE + (addr & 0x1FFF) as usize],
+      _ => 0,
+    }
+  }
+
+  fn write_chr(&mut self, addr: Address, value: Byte) {
+    match &mut self.character_ram {
+      Some(ram) => ram[addr as usize] = value,
+      None => warn!("Attempting to write read-only CHR memory on {:#x}", addr),
+    }
+  }
+
+  fn read_chr(&self, addr: Address) -> Byte {
+    match &self.character_ram {
+      Some(ram) => ram[addr as usize],
+      None => {
+        let bank = self.chr_banks[(addr >> 10) as usize & 7];
+        self.cart.get_vrom()[bank + (addr as usize & (CHR_BANK_SIZE - 1))]
+      }
+    }
+  }
+
+  fn prg_ram(&self) -> Option<&[Byte]> {
+    Some(&self.prg_ram)
+  }
+
+  fn prg_ram_mut(&mut self) -> Option<&mut [Byte]> {
+    Some(&mut self.prg_ram)
+  }
+
+  fn cpu_clock(&mut self) {
+    if !self.irq_counter_enabled {
+      return;
+    }
+    self.irq_counter = self.irq_counter.wrapping_sub(1);
+    if self.irq_counter == 0xFFFF && self.irq_enabled {
+      self.irq_pending = true;
+    }
+  }
+
+  fn irq_pending(&self) -> bool {
+    self.irq_pending
+  }
+
+  fn has_expansion_audio(&self) -> bool {
+    true
+  }
+
+  fn step_audio(&mut self) {
+    self.audio.step();
+  }
+
+  fn audio_output(&self) -> f32 {
+    self.audio.output()
+  }
+
+  fn has_extended_ram(&self) -> bool {
+    true
+  }
+
+  fn has_battery(&self) -> bool {
+    self.cart.has_battery()
+  }
+
+  fn get_name_table_mirroring(&self) -> u8 {
+    self.mirroring.into()
+  }
+
+  fn save(&self) -> Vec<u8> {
+    save(self)
+  }
+
+  fn cartridge(&self) -> &Cartridge {
+    &self.cart
+  }
+
+  fn cartridge_mut(&mut self) -> &mut Cartridge {
+    &mut self.cart
+  }
+
+  fn mapper_type(&self) -> MapperType {
+    FME7
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::mapper::test::test_cart;
+
+  fn fme7_rom() -> Cartridge {
+    test_cart(69, 128, 64, PRG_BANK_SIZE, CHR_BANK_SIZE)
+  }
+
+  fn command(mapper: &mut Fme7, command: Byte, value: Byte) {
+    mapper.write_prg(0x8000, command);
+    mapper.write_prg(0xA000, value);
+  }
+
+  #[test]
+  fn banking_test() {
+    let mut mapper = Fme7::new(fme7_rom(), Box::new(|_| {}));
+    assert_eq!(mapper.mapper_type(), FME7);
+    assert_eq!(mapper.read_prg(0xE000), 15);
+    command(&mut mapper, 9, 3);
+    command(&mut mapper, 0x0A, 4);
+    command(&mut mapper, 0x0B, 5);
+    assert_eq!(mapper.read_prg(0x8000), 3);
+    assert_eq!(mapper.read_prg(0xA000), 4);
+    assert_eq!(mapper.read_prg(0xC000), 5);
+
+    command(&mut mapper, 0, 10);
+    command(&mut mapper, 7, 40);
+    assert_eq!(mapper.read_chr(0x0000), 10);
+    assert_eq!(mapper.read_chr(0x1C00), 40);
+
+    // ROM at $6000, then RAM
+    command(&mut mapper, 8, 6);
+    assert_eq!(mapper.read_prg(0x6000), 6);
+    mapper.write_prg(0x6000, 0x42);
+    assert_eq!(mapper.read_prg(0x6000), 6);
+    command(&mut mapper, 8, 0xC0);
+    mapper.write_prg(0x6000, 0x42);
+    assert_eq!(mapper.read_prg(0x6000), 0x42);
+  }
+
+  #[test]
+  fn irq_test() {
+    let mut mapper = Fme7::new(fme7_rom(), Box::new(|_| {}));
+    command(&mut mapper, 0x0E, 0x10);
+    command(&mut mapper, 0x0F, 0x00);
+    command(&mut mapper, 0x0D, 0x81);
+    for _ in 0..0x10 {
+      mapper.cpu_clock();
+    }
+    assert!(!mapper.irq_pending());
+    mapper.cpu_clock();
+    assert!(mapper.irq_pending());
+
+    // the counter keeps running without firing while the IRQ is disabled
+    command(&mut mapper, 0x0D, 0x80);
+    assert!(!mapper.irq_pending());
+    for _ in 0..0x10000 {
+      mapper.cpu_clock();
+    }
+    assert!(!mapper.irq_pending());
+  }
+
+  #[test]
+  fn audio_test() {
+    let mut mapper = Fme7::new(fme7_rom(), Box::new(|_| {}));
+    // channel A alone, period 1, full volume
+    for (reg, value) in [(0, 1), (1, 0), (7, 0x3E), (8, 0x0F)] {
+      mapper.write_prg(0xC000, reg);
+      mapper.write_prg(0xE000, value);
+    }
+    let mut levels = vec![];
+    for _ in 0..64 {
+      mapper.step_audio();
+      levels.push(mapper.audio_output());
+    }
+    assert!(levels.iter().any(|&level| level == 0.0));
+    assert!(levels.iter().any(|&level| level > 0.1));
+
+    // envelope decaying once and holding silence
+    for (reg, value) in [(8, 0x10), (0x0B, 1), (0x0C, 0), (0x0D, 0x00)] {
+      mapper.write_prg(0xC000, reg);
+      mapper.write_prg(0xE000, value);
+    }
+    for _ in 0..(16 * 64) {
+      mapper.step_audio();
+    }
+    assert_eq!(mapper.audio_output(), 0.0);
+  }
+}
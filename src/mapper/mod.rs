@@ -1,24 +1,56 @@
+pub mod ax_rom;
+pub mod bn_rom;
+pub mod camerica;
 pub mod cn_rom;
+pub mod color_dreams;
+pub mod cp_rom;
+pub mod ex_rom;
 pub mod factory;
+pub mod fme7;
+pub mod gx_rom;
 pub mod n_rom;
+pub mod namco163;
+pub mod px_rom;
 pub mod ux_rom;
+pub mod vrc4;
+pub mod vrc6;
+pub mod vrc7;
 pub mod sx_rom;
 pub mod tx_rom;
 
-use serde::Serialize;
+use ciborium::{de::from_reader, ser::into_writer};
+use serde::{de::DeserializeOwned, Serialize};
 
+use crate::cartridge::Cartridge;
 use crate::common::*;
+use crate::NesResult;
 
-type MapperType = u8;
+pub type MapperType = u16;
 pub(crate) const NROM: MapperType = 0;
 pub(crate) const SXROM: MapperType = 1;
 pub(crate) const UXROM: MapperType = 2;
 pub(crate) const CNROM: MapperType = 3;
 pub(crate) const TXROM: MapperType = 4;
-// TODO
 pub(crate) const EXROM: MapperType = 5;
 pub(crate) const AXROM: MapperType = 7;
 pub(crate) const PXROM: MapperType = 9;
+pub(crate) const FXROM: MapperType = 10;
+pub(crate) const COLOR_DREAMS: MapperType = 11;
+pub(crate) const CPROM: MapperType = 13;
+pub(crate) const NAMCO163: MapperType = 19;
+pub(crate) const VRC4A: MapperType = 21;
+pub(crate) const VRC2A: MapperType = 22;
+pub(crate) const VRC2B: MapperType = 23;
+pub(crate) const VRC6A: MapperType = 24;
+pub(crate) const VRC4B: MapperType = 25;
+pub(crate) const VRC6B: MapperType = 26;
+pub(crate) const BNROM: MapperType = 34;
+pub(crate) const GXROM: MapperType = 66;
+pub(crate) const FME7: MapperType = 69;
+pub(crate) const CAMERICA: MapperType = 71;
+pub(crate) const VRC7: MapperType = 85;
+pub(crate) const TXSROM: MapperType = 118;
+pub(crate) const TQROM: MapperType = 119;
 
 pub trait Mapper {
   fn write_prg(&mut self, addr: Address, value: Byte);
@@ -26,20 +58,190 @@ pub trait Mapper {
   fn write_chr(&mut self, addr: Address, value: Byte);
   fn read_chr(&self, addr: Address) -> Byte;
 
+  /// Whether the PPU should report its reads with `notify_ppu_read`.
+  fn watch_ppu_reads(&self) -> bool {
+    false
+  }
+
+  /// Called after the PPU read `addr`, for mappers switching banks on PPU
+  /// fetches like MMC2 and MMC4.
+  fn notify_ppu_read(&mut self, _addr: Address) {}
+
+  /// Whether the PPU should report its rendering fetches with `notify_ppu_fetch`.
+  fn watch_ppu_fetches(&self) -> bool {
+    false
+  }
+
+  /// Called with the address of each nametable and pattern fetch at the dot
+  /// the 2C02 makes it, and with the VRAM address of $2006/$2007 accesses.
+  /// `dot` counts PPU cycles since power on, for mappers which time PPU A12
+  /// like MMC3.
+  fn notify_ppu_fetch(&mut self, _addr: Address, _dot: u64) {}
+
+  /// Sprite pattern fetch, MMC5 banks sprites apart from the background.
+  fn read_sprite_chr(&self, addr: Address) -> Byte {
+    self.read_chr(addr)
+  }
+
+  /// Whether nametables go through `read_nametable` and `write_nametable`
+  /// instead of the fixed mirroring.
+  fn maps_nametables(&self) -> bool {
+    false
+  }
+
+  /// Whether the CPU should report its reads of $8000-$FFFF with
+  /// `notify_cpu_read`.
+  fn watch_cpu_reads(&self) -> bool {
+    false
+  }
+
+  /// Called after the CPU read `value` at `addr`, MMC5 takes PCM samples from
+  /// these reads.
+  fn notify_cpu_read(&mut self, _addr: Address, _value: Byte) {}
+
+  /// Nametable read at $2000-$3EFF, `ciram` is the 2KB VRAM of the console.
+  fn read_nametable(&self, addr: Address, ciram: &[Byte]) -> Byte {
+    ciram[(addr & 0x7FF) as usize]
+  }
+
+  /// Nametable or attribute fetch of the background renderer, as opposed to
+  /// a $2007 read. MMC5 follows the tile being drawn with these.
+  fn fetch_nametable(&self, addr: Address, ciram: &[Byte]) -> Byte {
+    self.read_nametable(addr, ciram)
+  }
+
+  fn write_nametable(&mut self, addr: Address, value: Byte, ciram: &mut [Byte]) {
+    ciram[(addr & 0x7FF) as usize] = value;
+  }
+
+  /// CPU read at $4020-$5FFF, `None` leaves it to `read_prg`.
+  fn read_register(&mut self, _addr: Address) -> Option<Byte> {
+    None
+  }
+
+  /// CPU write at $4020-$5FFF, also gets PPUCTRL writes for mappers which
+  /// snoop them.
+  fn write_register(&mut self, _addr: Address, _value: Byte) {}
+
+  /// PRG-RAM banked by the mapper itself, $6000-$7FFF then goes through
+  /// `read_prg` and `write_prg`.
+  fn prg_ram(&self) -> Option<&[Byte]> {
+    None
+  }
+
+  fn prg_ram_mut(&mut self) -> Option<&mut [Byte]> {
+    None
+  }
+
+  fn has_expansion_audio(&self) -> bool {
+    false
+  }
+
+  /// Clock the sound chip of the cartridge, once per CPU cycle.
+  fn step_audio(&mut self) {}
+
+  /// Level of the sound chip, on the scale of the APU mixer.
+  fn audio_output(&self) -> f32 {
+    0.0
+  }
+
   fn has_extended_ram(&self) -> bool;
 
-  fn scanline_irq(&mut self) {}
+  /// PRG-RAM at $6000-$7FFF is battery backed and should be persisted.
+  fn has_battery(&self) -> bool;
+
+  /// Called once per CPU cycle, for IRQ counters clocked by M2.
+  fn cpu_clock(&mut self) {}
+
+  /// Level of the cartridge IRQ line, polled by the CPU after each
+  /// instruction. It stays asserted until the game acknowledges the IRQ
+  /// through a mapper register.
+  fn irq_pending(&self) -> bool {
+    false
+  }
 
   fn get_name_table_mirroring(&self) -> u8;
 
-  fn save(&self) -> String;
+  /// CBOR encoded state, without the ROM contents of the cartridge.
+  fn save(&self) -> Vec<u8>;
 
-  fn mapper_type(&self) -> u8;
+  fn cartridge(&self) -> &Cartridge;
+
+  fn cartridge_mut(&mut self) -> &mut Cartridge;
+
+  fn mapper_type(&self) -> MapperType;
 }
 
-fn save<T>(t: &T) -> String
+fn save<T>(t: &T) -> Vec<u8>
 where
   T: Serialize + Mapper,
 {
-  serde_json::to_string(&t).unwrap()
+  let mut state = vec![];
+  into_writer(t, &mut state).unwrap();
+  state
+}
+
+/**
+ * Decode a state made by `save` and attach the ROM of `rom`, the cartridge
+ * the running mapper was created from. States of version 1 savestates are
+ * JSON which still carries the ROM, it is replaced all the same.
+ */
+pub(crate) fn restore<T>(state: &[u8], rom: &Cartridge) -> NesResult<T>
+where
+  T: DeserializeOwned + Mapper,
+{
+  let mut mapper: T = if state.first() == Some(&b'{') {
+    serde_json::from_slice(state)?
+  } else {
+    from_reader(state)?
+  };
+  mapper.cartridge_mut().attach_rom(rom);
+  Ok(mapper)
+}
+
+/// Cartridges for the mapper tests.
+#[cfg(test)]
+pub(crate) mod test {
+  use crate::cartridge::Cartridge;
+
+  /**
+   * An iNES image of `mapper` with `prg_kb` KB of PRG-ROM and `chr_kb` KB of
+   * CHR-ROM, CHR-RAM when there is none. Every byte of a PRG bank of
+   * `prg_bank` bytes and of a CHR bank of `chr_bank` bytes holds the number
+   * of its bank.
+   */
+  pub fn test_rom(
+    mapper: u8,
+    prg_kb: usize,
+    chr_kb: usize,
+    prg_bank: usize,
+    chr_bank: usize,
+  ) -> Vec<u8> {
+    let mut rom = b"NES\x1A\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00".to_vec();
+    rom[4] = (prg_kb / 16) as u8;
+    rom[5] = (chr_kb / 8) as u8;
+    rom[6] = mapper << 4;
+    rom[7] = mapper & 0xF0;
+    rom.extend((0..prg_kb * 1024).map(|i| (i / prg_bank) as u8));
+    rom.extend((0..chr_kb * 1024).map(|i| (i / chr_bank) as u8));
+    rom
+  }
+
+  /// `test_rom` loaded into a cartridge.
+  pub fn test_cart(
+    mapper: u8,
+    prg_kb: usize,
+    chr_kb: usize,
+    prg_bank: usize,
+    chr_bank: usize,
+  ) -> Cartridge {
+    load(&test_rom(mapper, prg_kb, chr_kb, prg_bank, chr_bank))
+  }
+
+  /// A cartridge from a `test_rom` with a patched header.
+  pub fn load(rom: &[u8]) -> Cartridge {
+    let mut cart = Cartridge::new();
+    cart.load_from_data(rom).unwrap();
+    cart
+  }
 }
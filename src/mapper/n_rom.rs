@@ -5,19 +5,20 @@ use crate::cartridge::{Cartridge, BANK_SIZE};
 use crate::common::*;
 use crate::mapper::Mapper;
 
-use super::{NROM, save};
+use super::{save, MapperType, NROM};
 
 #[derive(Serialize, Deserialize)]
 pub struct NRom {
   one_bank: bool,
+  #[serde(with = "serde_bytes")]
   character_ram: Option<Vec<Byte>>,
   cart: Cartridge,
 }
 
 impl NRom {
   pub fn new(cart: Cartridge) -> Self {
-    let ram = if cart.get_vrom().len() == 0 {
-      Some(vec![0; 0x2000])
+    let ram = if cart.get_vrom().is_empty() {
+      Some(vec![0; cart.chr_ram_size()])
     } else {
       None
     };
@@ -65,16 +66,28 @@ impl Mapper for NRom {
     self.cart.has_extended_ram()
   }
 
+  fn has_battery(&self) -> bool {
+    self.cart.has_battery()
+  }
+
   #[inline]
   fn get_name_table_mirroring(&self) -> u8 {
     self.cart.get_name_table_mirroring()
   }
 
-  fn save(&self) -> String {
+  fn save(&self) -> Vec<u8> {
     save(self)
   }
 
-  fn mapper_type(&self) -> u8 {
+  fn cartridge(&self) -> &Cartridge {
+    &self.cart
+  }
+
+  fn cartridge_mut(&mut self) -> &mut Cartridge {
+    &mut self.cart
+  }
+
+  fn mapper_type(&self) -> MapperType {
     NROM
   }
 }
@@ -0,0 +1,347 @@
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::apu::Namco163Audio;
+use crate::cartridge::Cartridge;
+use crate::common::*;
+
+use super::{save, Mapper, MapperType, NAMCO163};
+
+const PRG_BANK_SIZE: usize = 0x2000;
+const CHR_BANK_SIZE: usize = 0x400;
+/// CHR and nametable banks from $E0 select the console VRAM.
+const CIRAM_BANK: Byte = 0xE0;
+const IRQ_MAX: Address = 0x7FFF;
+
+/**
+ * Namco 129/163 (mapper 19)
+ *
+ * Three switchable 8KB PRG banks followed by the fixed last 8KB, eight 1KB
+ * CHR banks, four nametable banks from CHR-ROM or VRAM, a 15-bit IRQ counter
+ * clocked by the CPU, 8KB of PRG-RAM and a wavetable sound chip.
+ *
+ * CHR banks pointing at VRAM are not supported as pattern tables, they read
+ * CHR-ROM.
+ *
+ * Reference https://www.nesdev.org/wiki/INES_Mapper_019
+ */
+#[derive(Serialize, Deserialize)]
+pub struct Namco163 {
+  #[serde(with = "serde_bytes")]
+  character_ram: Option<Vec<Byte>>,
+  #[serde(with = "serde_bytes")]
+  prg_ram: Vec<Byte>,
+  cart: Cartridge,
+
+  prg_banks: [usize; 3], // offsets of rom
+  chr_regs: [Byte; 8],
+  name_table_regs: [Byte; 4],
+  // $F800, PRG-RAM write protection
+  ram_protect: Byte,
+
+  irq_counter: Address,
+  irq_enabled: bool,
+  irq_pending: bool,
+
+  audio: Namco163Audio,
+}
+
+impl Namco163 {
+  pub fn new(cart: Cartridge) -> Self {
+    let ram = if cart.get_vrom().is_empty() {
+      Some(vec![0; cart.chr_ram_size()])
+    } else {
+      None
+    };
+    Self {
+      character_ram: ram,
+      prg_ram: vec![0; PRG_BANK_SIZE],
+      cart,
+      prg_banks: [0; 3],
+      chr_regs: [0; 8],
+      name_table_regs: [CIRAM_BANK; 4],
+      ram_protect: 0,
+      irq_counter: 0,
+      irq_enabled: false,
+      irq_pending: false,
+      audio: Namco163Audio::new(),
+    }
+  }
+
+  fn write_prg_bank(&mut self, index: usize, value: Byte) {
+    let banks = std::cmp::max(self.cart.get_rom().len() / PRG_BANK_SIZE, 1);
+    self.prg_banks[index] = ((value & 0x3F) as usize % banks) * PRG_BANK_SIZE;
+  }
+
+  /// Each 2KB of PRG-RAM is writable only with $4x in $F800 and its bit clear.
+  fn prg_ram_writable(&self, addr: Address) -> bool {
+    let window = (addr - 0x6000) >> 11;
+    self.ram_protect & 0xF0 == 0x40 && !bit_eq(self.ram_protect, 1 << window)
+  }
+
+  fn vrom_offset(&self, bank: Byte, addr: Address) -> usize {
+    let vrom = self.cart.get_vrom();
+    (bank as usize * CHR_BANK_SIZE + (addr as usize & (CHR_BANK_SIZE - 1))) % vrom.len()
+  }
+}
+
+impl Mapper for Namco163 {
+  fn write_prg(&mut self, addr: Address, value: Byte) {
+    match addr {
+      0x6000..=0x7FFF if self.prg_ram_writable(addr) => {
+        self.prg_ram[(addr & 0x1FFF) as usize] = value
+      }
+      0x8000..=0xBFFF => self.chr_regs[((addr - 0x8000) >> 11) as usize] = value,
+      0xC000..=0xDFFF => self.name_table_regs[((addr - 0xC000) >> 11) as usize] = value,
+      0xE000..=0xE7FF => {
+        self.write_prg_bank(0, value);
+        self.audio.set_disabled(bit_eq(value, 0x40));
+      }
+      0xE800..=0xEFFF => self.write_prg_bank(1, value),
+      0xF000..=0xF7FF => self.write_prg_bank(2, value),
+      0xF800..=0xFFFF => {
+        self.ram_protect = value;
+        self.audio.write_address(value);
+      }
+      _ => {}
+    }
+  }
+
+  fn read_prg(&self, addr: Address) -> Byte {
+    let rom = self.cart.get_rom();
+    match addr {
+      0x6000..=0x7FFF => self.prg_ram[(addr & 0x1FFF) as usize],
+      0x8000..=0xDFFF => {
+        let bank = self.prg_banks[((addr - 0x8000) >> 13) as usize];
+        rom[bank + (addr & 0x1FFF) as usize]
+      }
+      0xE000..=0xFFFF => rom[rom.len() - PRG_BANK_SIZE + (addr & 0x1FFF) as usize],
+      _ => 0,
+    }
+  }
+
+  fn write_chr(&mut self, addr: Address, value: Byte) {
+    match &mut self.character_ram {
+      Some(ram) => ram[addr as usize] = value,
+      None => warn!("Attempting to write read-only CHR memory on {:#x}", addr),
+    }
+  }
+
+  fn read_chr(&self, addr: Address) -> Byte {
+    match &self.character_ram {
+      Some(ram) => ram[addr as usize],
+      None => {
+        let bank = self.chr_regs[(addr >> 10) as usize & 7];
+        self.cart.get_vrom()[self.vrom_offset(bank, addr)]
+      }
+    }
+  }
+
+  fn maps_nametables(&self) -> bool {
+    self.character_ram.is_none()
+  }
+
+  fn read_nametable(&self, addr: Address, ciram: &[Byte]) -> Byte {
+    let bank = self.name_table_regs[((addr >> 10) & 3) as usize];
+    if bank >= CIRAM_BANK {
+      ciram[(bank as usize & 1) * 0x400 + (addr & 0x3FF) as usize]
+    } else {
+      self.cart.get_vrom()[self.vrom_offset(bank, addr)]
+    }
+  }
+
+  fn write_nametable(&mut self, addr: Address, value: Byte, ciram: &mut [Byte]) {
+    let bank = self.name_table_regs[((addr >> 10) & 3) as usize];
+    if bank >= CIRAM_BANK {
+      ciram[(bank as usize & 1) * 0x400 + (addr & 0x3FF) as usize] = value;
+    }
+  }
+
+  fn read_register(&mut self, addr: Address) -> Option<Byte> {
+    match addr {
+      0x4800..=0x4FFF => Some(self.audio.read_data()),
+      0x5000..=0x57FF => Some(self.irq_counter as Byte),
+      0x5800..=0x5FFF => Some((self.irq_counter >> 8) as Byte | (self.irq_enabled as Byte) << 7),
+      _ => None,
+    }
+  }
+
+  fn write_register(&mut self, addr: Address, value: Byte) {
+    match addr {
+      0x4800..=0x4FFF => self.audio.write_data(value),
+      // writing either half of the counter acknowledges the IRQ
+      0x5000..=0x57FF => {
+        self.irq_counter = (self.irq_counter & 0x7F00) | value as Address;
+        self.irq_pending = false;
+      }
+      0x5800..=0x5FFF => {
+        self.irq_counter = (self.irq_counter & 0x00FF) | ((value as Address & 0x7F) << 8);
+        self.irq_enabled = bit_eq(value, 0x80);
+        self.irq_pending = false;
+      }
+      _ => {}
+    }
+  }
+
+  fn prg_ram(&self) -> Option<&[Byte]> {
+    Some(&self.prg_ram)
+  }
+
+  fn prg_ram_mut(&mut self) -> Option<&mut [Byte]> {
+    Some(&mut self.prg_ram)
+  }
+
+  fn cpu_clock(&mut self) {
+    if !self.irq_enabled || self.irq_counter == IRQ_MAX {
+      return;
+    }
+    self.irq_counter += 1;
+    if self.irq_counter == IRQ_MAX {
+      self.irq_pending = true;
+    }
+  }
+
+  fn irq_pending(&self) -> bool {
+    self.irq_pending
+  }
+
+  fn has_expansion_audio(&self) -> bool {
+    true
+  }
+
+  fn step_audio(&mut self) {
+    self.audio.step();
+  }
+
+  fn audio_output(&self) -> f32 {
+    self.audio.output()
+  }
+
+  fn has_extended_ram(&self) -> bool {
+    true
+  }
+
+  fn has_battery(&self) -> bool {
+    self.cart.has_battery()
+  }
+
+  fn get_name_table_mirroring(&self) -> u8 {
+    self.cart.get_name_table_mirroring()
+  }
+
+  fn save(&self) -> Vec<u8> {
+    save(self)
+  }
+
+  fn cartridge(&self) -> &Cartridge {
+    &self.cart
+  }
+
+  fn cartridge_mut(&mut self) -> &mut Cartridge {
+    &mut self.cart
+  }
+
+  fn mapper_type(&self) -> MapperType {
+    NAMCO163
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::mapper::test::{load, test_rom};
+
+  fn namco163_rom() -> Cartridge {
+    let mut rom = test_rom(19, 128, 64, PRG_BANK_SIZE, CHR_BANK_SIZE);
+    // battery
+    rom[6] |= 0x02;
+    load(&rom)
+  }
+
+  #[test]
+  fn banking_test() {
+    let mut mapper = Namco163::new(namco163_rom());
+    assert_eq!(mapper.mapper_type(), NAMCO163);
+    assert!(mapper.has_battery());
+    assert_eq!(mapper.read_prg(0xE000), 15);
+    mapper.write_prg(0xE000, 3);
+    mapper.write_prg(0xE800, 4);
+    mapper.write_prg(0xF000, 5);
+    assert_eq!(mapper.read_prg(0x8000), 3);
+    assert_eq!(mapper.read_prg(0xA000), 4);
+    assert_eq!(mapper.read_prg(0xC000), 5);
+
+    mapper.write_prg(0x8000, 10);
+    mapper.write_prg(0xB800, 40);
+    assert_eq!(mapper.read_chr(0x0000), 10);
+    assert_eq!(mapper.read_chr(0x1C00), 40);
+
+    // nametables from VRAM and CHR-ROM
+    let mut ciram = vec![0; 0x800];
+    mapper.write_prg(0xC000, 0xE1);
+    mapper.write_prg(0xC800, 20);
+    mapper.write_nametable(0x2000, 0x55, &mut ciram);
+    assert_eq!(ciram[0x400], 0x55);
+    assert_eq!(mapper.read_nametable(0x2000, &ciram), 0x55);
+    assert_eq!(mapper.read_nametable(0x2400, &ciram), 20);
+
+    // PRG-RAM is write protected unless $F800 is $4x
+    mapper.write_prg(0x6000, 0x42);
+    assert_eq!(mapper.read_prg(0x6000), 0);
+    mapper.write_prg(0xF800, 0x41);
+    mapper.write_prg(0x6000, 0x42);
+    mapper.write_prg(0x6800, 0x43);
+    assert_eq!(mapper.read_prg(0x6000), 0);
+    assert_eq!(mapper.read_prg(0x6800), 0x43);
+  }
+
+  #[test]
+  fn irq_test() {
+    let mut mapper = Namco163::new(namco163_rom());
+    mapper.write_register(0x5000, 0xF0);
+    mapper.write_register(0x5800, 0xFF);
+    for _ in 0..14 {
+      mapper.cpu_clock();
+    }
+    assert!(!mapper.irq_pending());
+    mapper.cpu_clock();
+    assert!(mapper.irq_pending());
+    // the counter stops at $7FFF
+    mapper.cpu_clock();
+    assert_eq!(mapper.read_register(0x5000), Some(0xFF));
+    assert_eq!(mapper.read_register(0x5800), Some(0xFF));
+    mapper.write_register(0x5800, 0x00);
+    assert!(!mapper.irq_pending());
+  }
+
+  #[test]
+  fn audio_test() {
+    let mut mapper = Namco163::new(namco163_rom());
+    // a square wave at sample 0, 16 samples long, one sample per update on
+    // channel 7 only
+    mapper.write_prg(0xF800, 0x80);
+    for value in [0x00, 0x00, 0x00, 0x00, 0xFF, 0xFF, 0xFF, 0xFF] {
+      mapper.write_register(0x4800, value);
+    }
+    mapper.write_prg(0xF800, 0xF8);
+    for value in [0x00, 0x00, 0x00, 0x00, 0xF1, 0x00, 0x00, 0x0F] {
+      mapper.write_register(0x4800, value);
+    }
+    mapper.write_prg(0xF800, 0xFC);
+    assert_eq!(mapper.read_register(0x4800), Some(0xF1));
+    assert_eq!(mapper.read_register(0x4800), Some(0x00));
+
+    let mut low = false;
+    let mut high = false;
+    for _ in 0..(15 * 64) {
+      mapper.step_audio();
+      let out = mapper.audio_output();
+      low |= out < 0.0;
+      high |= out > 0.0;
+    }
+    assert!(low && high);
+
+    mapper.write_prg(0xE000, 0x40);
+    assert_eq!(mapper.audio_output(), 0.0);
+  }
+}
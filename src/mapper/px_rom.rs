@@ -0,0 +1,248 @@
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::cartridge::Cartridge;
+use crate::common::*;
+
+use super::{
+  factory::{MirrorCallback, NameTableMirroring},
+  save, Mapper, MapperType, FXROM, PXROM,
+};
+
+const CHR_BANK_SIZE: usize = 0x1000;
+const LATCH_FD: Byte = 0xFD;
+const LATCH_FE: Byte = 0xFE;
+
+/**
+ * MMC2 (PxROM, mapper 9) and MMC4 (FxROM, mapper 10).
+ *
+ * Each 4KB CHR half has two banks and a latch picking one of them. The PPU
+ * fetching tile $FD or $FE flips the latch, so games switch CHR in the middle
+ * of a frame without any CPU writes.
+ *
+ * MMC2 switches 8KB of PRG at $8000 and fixes the last three 8KB banks,
+ * MMC4 switches 16KB at $8000 and fixes the last 16KB.
+ */
+#[derive(Serialize, Deserialize)]
+pub struct PxRom {
+  mmc4: bool,
+  #[serde(with = "serde_bytes")]
+  character_ram: Option<Vec<Byte>>,
+  cart: Cartridge,
+  #[serde(skip)]
+  mirror_cb: Option<MirrorCallback>,
+  mirroring: NameTableMirroring,
+
+  prg_bank: usize, // offset of rom
+  // CHR banks of $0000 and $1000, picked by latch $FD and $FE
+  chr_banks: [[usize; 2]; 2],
+  latches: [Byte; 2],
+}
+
+impl PxRom {
+  pub fn new(cart: Cartridge, mmc4: bool, mirror_cb: MirrorCallback) -> Self {
+    let ram = if cart.get_vrom().is_empty() {
+      Some(vec![0; cart.chr_ram_size()])
+    } else {
+      None
+    };
+    Self {
+      mmc4,
+      character_ram: ram,
+      mirroring: NameTableMirroring::from(cart.get_name_table_mirroring()),
+      cart,
+      mirror_cb: Some(mirror_cb),
+      prg_bank: 0,
+      chr_banks: [[0; 2]; 2],
+      latches: [LATCH_FE; 2],
+    }
+  }
+
+  pub fn set_mirror_cb(&mut self, mirror_cb: MirrorCallback) {
+    self.mirror_cb = Some(mirror_cb);
+  }
+
+  fn prg_bank_size(&self) -> usize {
+    if self.mmc4 {
+      0x4000
+    } else {
+      0x2000
+    }
+  }
+
+  fn chr_offset(&self, addr: Address) -> usize {
+    let half = (addr >> 12) as usize & 1;
+    let bank = self.chr_banks[half][(self.latches[half] == LATCH_FE) as usize];
+    bank * CHR_BANK_SIZE + (addr as usize & (CHR_BANK_SIZE - 1))
+  }
+}
+
+impl Mapper for PxRom {
+  fn write_prg(&mut self, addr: Address, value: Byte) {
+    match addr {
+      0xA000..=0xAFFF => {
+        let size = self.prg_bank_size();
+        let banks = std::cmp::max(self.cart.get_rom().len() / size, 1);
+        self.prg_bank = ((value & 0x0F) as usize % banks) * size;
+      }
+      0xB000..=0xEFFF => {
+        let reg = ((addr - 0xB000) >> 12) as usize;
+        let banks = std::cmp::max(self.cart.get_vrom().len() / CHR_BANK_SIZE, 1);
+        self.chr_banks[reg >> 1][reg & 1] = (value & 0x1F) as usize % banks;
+      }
+      0xF000..=0xFFFF => {
+        let mirroring = if bit_eq(value, 0x01) {
+          NameTableMirroring::Horizontal
+        } else {
+          NameTableMirroring::Vertical
+        };
+        if mirroring != self.mirroring {
+          self.mirroring = mirroring;
+          if let Some(cb) = self.mirror_cb.as_mut() {
+            cb(mirroring.into());
+          }
+        }
+      }
+      _ => {}
+    }
+  }
+
+  fn read_prg(&self, addr: Address) -> Byte {
+    let rom = self.cart.get_rom();
+    let size = self.prg_bank_size();
+    let offset = (addr - 0x8000) as usize;
+    if offset < size {
+      rom[(self.prg_bank + offset) % rom.len()]
+    } else {
+      // everything after the switchable bank is fixed to the end of the ROM
+      rom[(rom.len().saturating_sub(0x8000) + offset) % rom.len()]
+    }
+  }
+
+  fn write_chr(&mut self, addr: Address, value: Byte) {
+    match &mut self.character_ram {
+      Some(ram) => ram[addr as usize] = value,
+      None => warn!("Attempting to write read-only CHR memory on {:#x}", addr),
+    }
+  }
+
+  fn read_chr(&self, addr: Address) -> Byte {
+    match &self.character_ram {
+      Some(ram) => ram[addr as usize],
+      None => self.cart.get_vrom()[self.chr_offset(addr)],
+    }
+  }
+
+  fn watch_ppu_reads(&self) -> bool {
+    true
+  }
+
+  fn notify_ppu_read(&mut self, addr: Address) {
+    // MMC2 only latches $0000 on the exact addresses, MMC4 on the whole row.
+    let latch = match addr {
+      0x0FD8 => Some((0, LATCH_FD)),
+      0x0FE8 => Some((0, LATCH_FE)),
+      0x0FD9..=0x0FDF if self.mmc4 => Some((0, LATCH_FD)),
+      0x0FE9..=0x0FEF if self.mmc4 => Some((0, LATCH_FE)),
+      0x1FD8..=0x1FDF => Some((1, LATCH_FD)),
+      0x1FE8..=0x1FEF => Some((1, LATCH_FE)),
+      _ => None,
+    };
+    if let Some((half, value)) = latch {
+      self.latches[half] = value;
+    }
+  }
+
+  fn has_extended_ram(&self) -> bool {
+    self.mmc4 || self.cart.has_extended_ram()
+  }
+
+  fn has_battery(&self) -> bool {
+    self.cart.has_battery()
+  }
+
+  fn get_name_table_mirroring(&self) -> u8 {
+    self.mirroring.into()
+  }
+
+  fn save(&self) -> Vec<u8> {
+    save(self)
+  }
+
+  fn cartridge(&self) -> &Cartridge {
+    &self.cart
+  }
+
+  fn cartridge_mut(&mut self) -> &mut Cartridge {
+    &mut self.cart
+  }
+
+  fn mapper_type(&self) -> MapperType {
+    if self.mmc4 {
+      FXROM
+    } else {
+      PXROM
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::mapper::test::test_cart;
+
+  fn mmc2_rom() -> Cartridge {
+    test_cart(9, 128, 64, 0x2000, CHR_BANK_SIZE)
+  }
+
+  #[test]
+  fn latch_test() {
+    let mut mapper = PxRom::new(mmc2_rom(), false, Box::new(|_| {}));
+    assert_eq!(mapper.mapper_type(), PXROM);
+    assert_eq!(mapper.read_prg(0xE000), 15);
+    mapper.write_prg(0xA000, 3);
+    assert_eq!(mapper.read_prg(0x8000), 3);
+    assert_eq!(mapper.read_prg(0xA000), 13);
+
+    mapper.write_prg(0xB000, 1);
+    mapper.write_prg(0xC000, 2);
+    mapper.write_prg(0xD000, 3);
+    mapper.write_prg(0xE000, 4);
+    assert_eq!(mapper.read_chr(0x0000), 2);
+    assert_eq!(mapper.read_chr(0x1000), 4);
+
+    mapper.notify_ppu_read(0x0FD9);
+    assert_eq!(mapper.read_chr(0x0000), 2);
+    mapper.notify_ppu_read(0x0FD8);
+    assert_eq!(mapper.read_chr(0x0000), 1);
+    mapper.notify_ppu_read(0x1FDC);
+    assert_eq!(mapper.read_chr(0x1000), 3);
+    mapper.notify_ppu_read(0x0FE8);
+    assert_eq!(mapper.read_chr(0x0000), 2);
+    assert_eq!(mapper.read_chr(0x1000), 3);
+  }
+
+  #[test]
+  fn mmc4_test() {
+    let mut mapper = PxRom::new(mmc2_rom(), true, Box::new(|_| {}));
+    assert_eq!(mapper.mapper_type(), FXROM);
+    mapper.write_prg(0xA000, 2);
+    assert_eq!(mapper.read_prg(0x8000), 4);
+    assert_eq!(mapper.read_prg(0xBFFF), 5);
+    assert_eq!(mapper.read_prg(0xC000), 14);
+
+    mapper.write_prg(0xB000, 5);
+    mapper.notify_ppu_read(0x0FDA);
+    assert_eq!(mapper.read_chr(0x0000), 5);
+  }
+
+  #[test]
+  fn small_prg_test() {
+    // 16KB of PRG-ROM mirrors into the fixed banks
+    let mapper = PxRom::new(test_cart(9, 16, 64, 0x2000, CHR_BANK_SIZE), false, Box::new(|_| {}));
+    assert_eq!(mapper.read_prg(0x8000), 0);
+    assert_eq!(mapper.read_prg(0xA000), 1);
+    assert_eq!(mapper.read_prg(0xC000), 0);
+    assert_eq!(mapper.read_prg(0xE000), 1);
+  }
+}
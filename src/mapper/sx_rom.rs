@@ -4,7 +4,7 @@ use serde::{Deserialize, Serialize};
 use crate::common::{bit_eq, Byte};
 use crate::{cartridge::Cartridge, common::Address};
 
-use super::{SXROM, save};
+use super::{save, MapperType, SXROM};
 
 use super::{
   factory::{MirrorCallback, NameTableMirroring},
@@ -12,10 +12,28 @@ use super::{
 };
 
 const BANK_SIZE: usize = 0x1000;
+const PRG_BANK_SIZE: usize = 0x4000;
+const OUTER_PRG_SIZE: usize = 0x40000;
+const PRG_RAM_BANK_SIZE: usize = 0x2000;
 
+/**
+ * MMC1 mapper. The larger boards reuse CHR register 0 lines:
+ *
+ * - SUROM/SXROM: bit 4 selects the 256KB half of a 512KB PRG-ROM
+ * - SOROM: bit 3 selects one of two 8KB PRG-RAM banks
+ * - SXROM: bits 2-3 select one of four 8KB PRG-RAM banks
+ *
+ * The board is picked from the PRG-ROM and PRG-RAM sizes of the header.
+ * Like the real chip, a serial write on the cycle right after another one
+ * is ignored, read-modify-write instructions only load their first write.
+ */
 #[derive(Serialize, Deserialize)]
 pub struct SxRom {
+  #[serde(with = "serde_bytes")]
   character_ram: Option<Vec<Byte>>,
+  // SOROM and SXROM PRG-RAM, plain boards use the RAM of the main bus
+  #[serde(default, with = "serde_bytes")]
+  prg_ram: Option<Vec<Byte>>,
   cart: Cartridge,
   #[serde(skip)]
   mirror_cb: Option<MirrorCallback>,
@@ -36,18 +54,33 @@ pub struct SxRom {
 
   first_bank_chr: usize,  // offset of vrom
   second_bank_chr: usize, // offset of vrom
+
+  #[serde(default)]
+  prg_ram_bank: usize, // offset of prg_ram
+
+  #[serde(default)]
+  cpu_cycles: u64,
+  #[serde(default)]
+  last_write_cycle: Option<u64>,
 }
 
 impl SxRom {
   pub fn new(cart: Cartridge, mirror_cb: MirrorCallback) -> Self {
-    let ram = if cart.get_vrom().len() == 0 {
-      Some(vec![0; 0x2000])
+    let ram = if cart.get_vrom().is_empty() {
+      Some(vec![0; cart.chr_ram_size()])
+    } else {
+      None
+    };
+    let header = cart.header();
+    let prg_ram_size = header.prg_ram_size + header.prg_nvram_size;
+    let prg_ram = if prg_ram_size > PRG_RAM_BANK_SIZE {
+      Some(vec![0; std::cmp::min(prg_ram_size, 4 * PRG_RAM_BANK_SIZE)])
     } else {
       None
     };
-    let rom_len = cart.get_rom().len();
-    Self {
+    let mut ret = Self {
       character_ram: ram,
+      prg_ram,
       cart,
       mirror_cb: Some(mirror_cb),
       mirroring: NameTableMirroring::Horizontal,
@@ -60,28 +93,80 @@ impl SxRom {
       reg_chr1: 0,
 
       first_bank_prg: 0,
-      second_bank_prg: rom_len - 0x4000, // 0x2000 * 0x0e last bank,
+      second_bank_prg: 0,
 
       first_bank_chr: 0,
-      second_bank_chr: 0,
-    }
+      second_bank_chr: BANK_SIZE,
+
+      prg_ram_bank: 0,
+      cpu_cycles: 0,
+      last_write_cycle: None,
+    };
+    ret.calculate_prg_pointers();
+    ret.calculate_chr_pointers();
+    ret
   }
 }
 
 impl SxRom {
   fn calculate_prg_pointers(&mut self) {
+    let rom_len = self.cart.get_rom().len();
+    // 512KB boards switch 256KB halves, fixed banks stay in the selected half
+    let (outer, last_bank) = if rom_len > OUTER_PRG_SIZE {
+      let outer = ((self.reg_chr0 >> 4) & 1) as usize * OUTER_PRG_SIZE;
+      (outer, outer + OUTER_PRG_SIZE - PRG_BANK_SIZE)
+    } else {
+      (0, rom_len - PRG_BANK_SIZE)
+    };
     if self.mode_prg <= 1 {
       // 32KB changeable
-      self.first_bank_prg = 0x8000 * (self.reg_prg >> 1) as usize;
+      self.first_bank_prg = outer + 0x8000 * (self.reg_prg >> 1) as usize;
       self.second_bank_prg = self.first_bank_prg + 0x4000; // add 16KB
     } else if self.mode_prg == 2 {
       // fir first switch second fixed
-      self.first_bank_prg = 0;
-      self.second_bank_prg = 0x4000 * self.reg_prg as usize;
+      self.first_bank_prg = outer;
+      self.second_bank_prg = outer + 0x4000 * self.reg_prg as usize;
     } else {
       // switch first fix second
-      self.first_bank_prg = 0x4000 * self.reg_prg as usize;
-      self.second_bank_prg = self.cart.get_rom().len() - 0x4000;
+      self.first_bank_prg = outer + 0x4000 * self.reg_prg as usize;
+      self.second_bank_prg = last_bank;
+    }
+    self.first_bank_prg %= rom_len;
+    self.second_bank_prg %= rom_len;
+  }
+
+  fn calculate_chr_pointers(&mut self) {
+    let chr_len = match &self.character_ram {
+      Some(ram) => ram.len(),
+      None => self.cart.get_vrom().len(),
+    };
+    if self.mode_chr == 0 {
+      // one 8KB bank
+      self.first_bank_chr = BANK_SIZE * (self.reg_chr0 & 0x1E) as usize;
+      self.second_bank_chr = self.first_bank_chr + BANK_SIZE;
+    } else {
+      // two 4KB banks
+      self.first_bank_chr = BANK_SIZE * self.reg_chr0 as usize;
+      self.second_bank_chr = BANK_SIZE * self.reg_chr1 as usize;
+    }
+    self.first_bank_chr %= chr_len;
+    self.second_bank_chr %= chr_len;
+
+    if let Some(ram) = &self.prg_ram {
+      let bank = if ram.len() > 2 * PRG_RAM_BANK_SIZE {
+        (self.reg_chr0 >> 2) & 0x3
+      } else {
+        (self.reg_chr0 >> 3) & 0x1
+      };
+      self.prg_ram_bank = (bank as usize * PRG_RAM_BANK_SIZE) % ram.len();
+    }
+  }
+
+  fn chr_offset(&self, addr: Address) -> usize {
+    if addr < BANK_SIZE as Address {
+      self.first_bank_chr + addr as usize
+    } else {
+      self.second_bank_chr + (addr & 0xfff) as usize
     }
   }
 
@@ -92,6 +177,22 @@ impl SxRom {
 
 impl Mapper for SxRom {
   fn write_prg(&mut self, addr: Address, value: Byte) {
+    if addr < 0x8000 {
+      if let Some(ram) = &mut self.prg_ram {
+        ram[self.prg_ram_bank + (addr & 0x1FFF) as usize] = value;
+      }
+      return;
+    }
+    // Clocks are counted after each instruction, both writes of a
+    // read-modify-write instruction see the same count.
+    let consecutive = self
+      .last_write_cycle
+      .is_some_and(|cycle| self.cpu_cycles.wrapping_sub(cycle) < 2);
+    self.last_write_cycle = Some(self.cpu_cycles);
+    if consecutive {
+      return;
+    }
+
     if !bit_eq(value, 0x80) {
       // reset bit not set.
       self.temp_register = (self.temp_register >> 1) | ((value & 1) << 4);
@@ -106,36 +207,22 @@ impl Mapper for SxRom {
             3 => self.mirroring = NameTableMirroring::Horizontal,
             _ => unreachable!(),
           }
-          if self.mirror_cb.is_some() {
-            self.mirror_cb.as_mut().unwrap()(self.mirroring.into());
+          if let Some(mirror_cb) = self.mirror_cb.as_mut() {
+            mirror_cb(self.mirroring.into());
           }
 
           self.mode_chr = (self.temp_register & 0x10) >> 4;
           self.mode_prg = (self.temp_register & 0xc) >> 2;
           self.calculate_prg_pointers();
-
-          // Recalculate CHR pointers
-          if self.mode_chr == 0 {
-            // one 8KB bank
-            self.first_bank_chr = BANK_SIZE * (self.reg_chr0 | 1) as usize;
-            self.second_bank_chr = self.first_bank_chr + BANK_SIZE;
-          } else {
-            // two 4KB banks
-            self.first_bank_chr = BANK_SIZE * self.reg_chr0 as usize;
-            self.second_bank_chr = BANK_SIZE * self.reg_chr1 as usize;
-          }
+          self.calculate_chr_pointers();
         } else if addr <= 0xbfff {
-          // CHR Reg 0
+          // CHR Reg 0, also the outer PRG and PRG-RAM bank of large boards
           self.reg_chr0 = self.temp_register;
-          self.first_bank_prg = BANK_SIZE * (self.temp_register | (1 - self.mode_chr)) as usize;
-          if self.mode_chr == 0 {
-            self.second_bank_chr = self.first_bank_chr + BANK_SIZE;
-          }
+          self.calculate_prg_pointers();
+          self.calculate_chr_pointers();
         } else if addr <= 0xdfff {
           self.reg_chr1 = self.temp_register;
-          if self.mode_chr == 1 {
-            self.second_bank_chr = BANK_SIZE * self.temp_register as usize;
-          }
+          self.calculate_chr_pointers();
         } else {
           // TODO PRG-RAM
           if bit_eq(self.temp_register, 0x10) {
@@ -160,6 +247,12 @@ impl Mapper for SxRom {
   }
 
   fn read_prg(&self, addr: Address) -> Byte {
+    if addr < 0x8000 {
+      return match &self.prg_ram {
+        Some(ram) => ram[self.prg_ram_bank + (addr & 0x1FFF) as usize],
+        None => 0,
+      };
+    }
     self.cart.get_rom()[if addr < 0xC000 {
       self.first_bank_prg
     } else {
@@ -168,36 +261,147 @@ impl Mapper for SxRom {
   }
 
   fn write_chr(&mut self, addr: Address, value: Byte) {
+    let offset = self.chr_offset(addr);
     match &mut self.character_ram {
-      Some(ram) => ram[addr as usize] = value,
+      Some(ram) => ram[offset] = value,
       None => warn!("Attempting to write read-only CHR memory on {:#x}", addr),
     }
   }
 
   fn read_chr(&self, addr: Address) -> Byte {
     match &self.character_ram {
-      Some(ram) => ram[addr as usize],
-      None => self.cart.get_vrom()[if addr < BANK_SIZE as Address {
-        self.first_bank_chr + addr as usize
-      } else {
-        self.second_bank_chr + (addr & 0xfff) as usize
-      }],
+      Some(ram) => ram[self.chr_offset(addr)],
+      None => self.cart.get_vrom()[self.chr_offset(addr)],
     }
   }
 
+  fn prg_ram(&self) -> Option<&[Byte]> {
+    self.prg_ram.as_deref()
+  }
+
+  fn prg_ram_mut(&mut self) -> Option<&mut [Byte]> {
+    self.prg_ram.as_deref_mut()
+  }
+
+  fn cpu_clock(&mut self) {
+    self.cpu_cycles = self.cpu_cycles.wrapping_add(1);
+  }
+
   fn has_extended_ram(&self) -> bool {
     self.cart.has_extended_ram()
   }
 
+  fn has_battery(&self) -> bool {
+    self.cart.has_battery()
+  }
+
   fn get_name_table_mirroring(&self) -> u8 {
     self.mirroring.into()
   }
 
-  fn save(&self) -> String {
+  fn save(&self) -> Vec<u8> {
     save(self)
   }
 
-  fn mapper_type(&self) -> u8 {
+  fn cartridge(&self) -> &Cartridge {
+    &self.cart
+  }
+
+  fn cartridge_mut(&mut self) -> &mut Cartridge {
+    &mut self.cart
+  }
+
+  fn mapper_type(&self) -> MapperType {
     SXROM
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::mapper::{
+    restore,
+    test::{load, test_rom},
+  };
+
+  fn rom(prg_kb: usize, prg_ram_shift: u8) -> Cartridge {
+    let mut rom = test_rom(1, prg_kb, 0, PRG_BANK_SIZE, 0);
+    // NES 2.0 with 8KB of CHR-RAM
+    rom[7] |= 0x08;
+    rom[10] = prg_ram_shift;
+    rom[11] = 0x07;
+    load(&rom)
+  }
+
+  fn write_serial(mapper: &mut SxRom, addr: Address, value: Byte) {
+    for i in 0..5 {
+      mapper.write_prg(addr, (value >> i) & 1);
+      mapper.cpu_clock();
+      mapper.cpu_clock();
+    }
+  }
+
+  #[test]
+  fn banking_test() {
+    let mut mapper = SxRom::new(rom(256, 0), Box::new(|_| {}));
+    assert!(mapper.prg_ram().is_none());
+    assert_eq!(mapper.read_prg(0xC000), 15);
+    write_serial(&mut mapper, 0xE000, 5);
+    assert_eq!(mapper.read_prg(0x8000), 5);
+    // 4KB CHR mode, CHR-RAM banks as well
+    write_serial(&mut mapper, 0x8000, 0x1C);
+    write_serial(&mut mapper, 0xA000, 1);
+    write_serial(&mut mapper, 0xC000, 1);
+    mapper.write_chr(0x0000, 0x42);
+    assert_eq!(mapper.read_chr(0x1000), 0x42);
+
+    let loaded: SxRom = restore(&mapper.save(), mapper.cartridge()).unwrap();
+    assert_eq!(loaded.read_prg(0x8000), 5);
+    assert_eq!(loaded.read_chr(0x0000), 0x42);
+  }
+
+  #[test]
+  fn surom_test() {
+    let mut mapper = SxRom::new(rom(512, 0), Box::new(|_| {}));
+    assert_eq!(mapper.read_prg(0xC000), 15);
+    write_serial(&mut mapper, 0xE000, 2);
+    write_serial(&mut mapper, 0xA000, 0x10);
+    assert_eq!(mapper.read_prg(0x8000), 18);
+    assert_eq!(mapper.read_prg(0xC000), 31);
+    write_serial(&mut mapper, 0xA000, 0x00);
+    assert_eq!(mapper.read_prg(0x8000), 2);
+  }
+
+  #[test]
+  fn sxrom_ram_test() {
+    // 32KB PRG-RAM
+    let mut mapper = SxRom::new(rom(512, 9), Box::new(|_| {}));
+    assert_eq!(mapper.prg_ram().unwrap().len(), 0x8000);
+    mapper.write_prg(0x6000, 0x11);
+    write_serial(&mut mapper, 0xA000, 0x0C);
+    assert_eq!(mapper.read_prg(0x6000), 0);
+    mapper.write_prg(0x6000, 0x33);
+    assert_eq!(mapper.prg_ram().unwrap()[0x6000], 0x33);
+    write_serial(&mut mapper, 0xA000, 0x00);
+    assert_eq!(mapper.read_prg(0x6000), 0x11);
+
+    // 16KB selects its bank with bit 3
+    let mut mapper = SxRom::new(rom(256, 8), Box::new(|_| {}));
+    write_serial(&mut mapper, 0xA000, 0x08);
+    mapper.write_prg(0x7FFF, 0x22);
+    assert_eq!(mapper.prg_ram().unwrap()[0x3FFF], 0x22);
+  }
+
+  #[test]
+  fn consecutive_write_test() {
+    let mut mapper = SxRom::new(rom(256, 0), Box::new(|_| {}));
+    // the second write of a read-modify-write is ignored, so a reset
+    // followed by a bit in the same instruction leaves the register empty
+    mapper.write_prg(0xE000, 0x80);
+    mapper.write_prg(0xE000, 0x01);
+    mapper.cpu_clock();
+    mapper.cpu_clock();
+    write_serial(&mut mapper, 0xE000, 3);
+    assert_eq!(mapper.read_prg(0x8000), 3);
+  }
+}
@@ -4,17 +4,49 @@ use crate::{
 };
 
 use super::{
-  factory::{MirrorCallback, NameTableMirroring, IRQCallback},
-  Mapper, TXROM,
+  factory::{MirrorCallback, NameTableMirroring},
+  Mapper, MapperType, TQROM, TXROM, TXSROM,
 };
 use serde::{Deserialize, Serialize};
 
+const CHR_RAM_SIZE: usize = 0x2000;
+const MMC6_RAM_SIZE: usize = 0x400;
+// PPU dots A12 has to stay low before a rise clocks the IRQ counter, filters
+// out the short drops between sprite pattern fetches.
+const A12_FILTER_DOTS: u64 = 16;
+
+/// Boards built around the MMC3 that change how it is wired.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Board {
+  #[default]
+  Mmc3,
+  /// Mapper 4 submapper 1: 1KB of PRG-RAM at $7000 with per half protection.
+  Mmc6,
+  /// Mapper 118: bit 7 of the CHR bank registers drives CIRAM A10.
+  TxSRom,
+  /// Mapper 119: bit 6 of the CHR bank registers selects 8KB of CHR-RAM.
+  TqRom,
+}
+
 /**
- * MMC3 mapper
+ * MMC3 mapper, with its TxSROM, TQROM and MMC6 variants.
+ *
+ * The IRQ counter of MMC3C ("new") fires every clock the counter is 0, the
+ * MMC3A and Acclaim MC-ACC ("old", submappers 4 and 3) only when it is
+ * decremented to 0 or reloaded through $C001. The counter is clocked by
+ * rises of PPU A12 after it was low for a while. MC-ACC counts falling edges
+ * instead, a few dots later, which is not modelled.
+ *
+ * iNES 1.0 images of MMC6 games run as plain MMC3, which works since the
+ * $6000-$7FFF RAM is then always enabled.
  */
 #[derive(Serialize, Deserialize)]
 pub struct TxRom {
   cart: Cartridge,
+  #[serde(default)]
+  board: Board,
+  #[serde(default)]
+  old_irq: bool,
   target_register: usize,
   prg_bank_mode: bool,
   chr_inversion: bool,
@@ -25,8 +57,18 @@ pub struct TxRom {
   irq_counter: u32,
   irq_latch: u8,
   irq_reload_pending: bool,
+  #[serde(default)]
+  irq_pending: bool,
+  #[serde(default)]
+  a12_high: bool,
+  // PPU dot A12 last went low
+  #[serde(default)]
+  a12_low_since: u64,
 
-  prg_ram: Vec<Byte>,
+  // 1KB inside MMC6, MMC3 boards use the PRG-RAM of the console
+  #[serde(with = "serde_bytes")]
+  prg_ram: Option<Vec<Byte>>,
+  #[serde(with = "serde_bytes")]
   mirroring_ram: Vec<Byte>,
 
   prg_bank0: usize,
@@ -35,6 +77,17 @@ pub struct TxRom {
   prg_bank3: usize,
 
   chr_banks: [usize; 8],
+  // slots of chr_banks pointing into character_ram
+  #[serde(default)]
+  chr_ram_slots: u8,
+  #[serde(default, with = "serde_bytes")]
+  character_ram: Option<Vec<Byte>>,
+
+  // MMC6 $8000 bit 5 and $A001 bits 4-7
+  #[serde(default)]
+  prg_ram_enable: bool,
+  #[serde(default)]
+  prg_ram_protect: Byte,
 
   rom_size: usize,
   cart_mirroring: u8,
@@ -43,17 +96,32 @@ pub struct TxRom {
   #[serde(skip)]
   // mirroring callback
   mirror_cb: Option<MirrorCallback>,
-  #[serde(skip)]
-  // interrupt callback
-  interrupt_cb: Option<IRQCallback>,
 }
 
 impl TxRom {
-  pub fn new(cart: Cartridge, mirror_cb: MirrorCallback, interrupt_cb: IRQCallback) -> Self {
+  pub fn new(cart: Cartridge, mirror_cb: MirrorCallback) -> Self {
+    let submapper = cart.header().submapper;
+    let board = match cart.get_mapper() {
+      TXSROM => Board::TxSRom,
+      TQROM => Board::TqRom,
+      _ if submapper == 1 => Board::Mmc6,
+      _ => Board::Mmc3,
+    };
     let rom_len = cart.get_rom().len();
     let vrom_len = std::cmp::max(cart.get_vrom().len(), 0x800);
     let cart_mirroring = cart.get_name_table_mirroring();
+    let character_ram = if cart.get_vrom().is_empty() || board == Board::TqRom {
+      Some(vec![0; std::cmp::max(cart.chr_ram_size(), CHR_RAM_SIZE)])
+    } else {
+      None
+    };
     let mut ret = TxRom {
+      board,
+      old_irq: submapper == 3 || submapper == 4,
+      chr_ram_slots: if cart.get_vrom().is_empty() { 0xFF } else { 0 },
+      character_ram,
+      prg_ram_enable: false,
+      prg_ram_protect: 0,
       cart,
       target_register: 0,
       prg_bank_mode: false,
@@ -63,9 +131,12 @@ impl TxRom {
       irq_counter: 0,
       irq_latch: 0,
       irq_reload_pending: false,
-      prg_ram: vec![0; 32 * 1024],
-      rom_size: rom_len as usize,
-      cart_mirroring: cart_mirroring,
+      irq_pending: false,
+      a12_high: false,
+      a12_low_since: 0,
+      prg_ram: (board == Board::Mmc6).then(|| vec![0; MMC6_RAM_SIZE]),
+      rom_size: rom_len,
+      cart_mirroring,
       mirroring_ram: vec![0; 4 * 1024],
       prg_bank0: rom_len - 0x4000,
       prg_bank1: rom_len - 0x2000,
@@ -74,7 +145,6 @@ impl TxRom {
       chr_banks: [vrom_len - 0x400; 8],
       mirroring: NameTableMirroring::Horizontal,
       mirror_cb: Some(mirror_cb),
-      interrupt_cb: Some(interrupt_cb)
     };
     ret.chr_banks[0] = vrom_len - 0x800;
     ret.chr_banks[3] = vrom_len - 0x800;
@@ -86,77 +156,133 @@ impl TxRom {
     self.mirror_cb = Some(mirror_cb);
   }
 
-  pub fn set_irq_cb(&mut self, irq_cb: IRQCallback) {
-    self.interrupt_cb = Some(irq_cb);
-  }
-
   #[inline]
   fn read_prg_bank(&self, addr: usize) -> Byte {
     self.cart.get_rom()[addr]
   }
 
   fn update_bank_offset(&self, index: Address) -> usize {
-    if self.cart.get_vrom().len() == 0 {
+    if self.cart.get_vrom().is_empty() {
       return 0
     }
     let truncate = (index as usize) % (self.cart.get_vrom().len() / 0x400);
     truncate * 0x400
   }
+
+  // Register values of the eight 1KB CHR slots, R0 and R1 select 2KB banks.
+  fn chr_registers(&self) -> [Address; 8] {
+    let r = &self.bank_register;
+    let low = [r[0] & 0xFE, (r[0] & 0xFE) + 1, r[1] & 0xFE, (r[1] & 0xFE) + 1];
+    let high = [r[2], r[3], r[4], r[5]];
+    if !self.chr_inversion {
+      [low[0], low[1], low[2], low[3], high[0], high[1], high[2], high[3]]
+    } else {
+      [high[0], high[1], high[2], high[3], low[0], low[1], low[2], low[3]]
+    }
+  }
+
+  fn update_banks(&mut self) {
+    let registers = self.chr_registers();
+    for (slot, &index) in registers.iter().enumerate() {
+      let in_ram = match &self.character_ram {
+        Some(_) if self.cart.get_vrom().is_empty() => true,
+        Some(_) => self.board == Board::TqRom && bit_eq(index, 0x40),
+        None => false,
+      };
+      if in_ram {
+        let banks = self.character_ram.as_ref().unwrap().len() / 0x400;
+        self.chr_ram_slots |= 1 << slot;
+        self.chr_banks[slot] = (index as usize % banks) * 0x400;
+      } else {
+        self.chr_ram_slots &= !(1 << slot);
+        self.chr_banks[slot] = self.update_bank_offset(index);
+      }
+    }
+
+    if !self.prg_bank_mode {
+      // ignore top two bits for R6 / R7 using 0x3F
+      self.prg_bank0 = (self.bank_register[6] & 0x3f) as usize * 0x2000;
+      self.prg_bank1 = (self.bank_register[7] & 0x3f) as usize * 0x2000;
+      self.prg_bank2 = self.rom_size - 0x4000;
+      self.prg_bank3 = self.rom_size - 0x2000;
+    } else {
+      self.prg_bank0 = self.rom_size - 0x4000;
+      self.prg_bank1 = (self.bank_register[7] & 0x3f) as usize * 0x2000;
+      self.prg_bank2 = (self.bank_register[6] & 0x3f) as usize * 0x2000;
+      self.prg_bank3 = self.rom_size - 0x2000;
+    }
+  }
+
+  // CIRAM page of a TxSROM nametable, from bit 7 of the CHR register that
+  // would map the same PPU address below $1000.
+  fn ciram_offset(&self, addr: Address) -> usize {
+    let slot = ((addr >> 10) & 3) as usize;
+    let index = if !self.chr_inversion {
+      self.bank_register[slot >> 1]
+    } else {
+      self.bank_register[2 + slot]
+    };
+    ((index >> 7) & 1) as usize * 0x400 + (addr & 0x3FF) as usize
+  }
+
+  fn clock_irq(&mut self) {
+    let reload_pending = self.irq_reload_pending;
+    let decremented = self.irq_counter != 0 && !reload_pending;
+    if decremented {
+      self.irq_counter -= 1;
+    } else {
+      self.irq_counter = self.irq_latch as u32;
+      self.irq_reload_pending = false;
+    }
+    let fire = !self.old_irq || decremented || reload_pending;
+    if self.irq_counter == 0 && fire && self.irq_enable {
+      self.irq_pending = true;
+    }
+  }
+
+  // Offset of MMC6 PRG-RAM and whether the half it is in is enabled for
+  // reading and writing.
+  fn mmc6_ram(&self, addr: Address) -> (usize, bool, bool) {
+    let offset = (addr & 0x3FF) as usize;
+    let (read, write) = if offset >= 0x200 { (0x80, 0x40) } else { (0x20, 0x10) };
+    let readable = addr >= 0x7000 && self.prg_ram_enable && bit_eq(self.prg_ram_protect, read);
+    (offset, readable, readable && bit_eq(self.prg_ram_protect, write))
+  }
 }
 
 impl Mapper for TxRom {
   fn write_prg(&mut self, addr: Address, value: Byte) {
     match addr {
       0x6000..=0x7fff => {
-        self.prg_ram[(addr & 0x1fff) as usize] = value;
+        let (offset, _, writable) = self.mmc6_ram(addr);
+        match &mut self.prg_ram {
+          Some(ram) if writable => ram[offset] = value,
+          _ => {}
+        }
       }
       0x8000..=0x9fff => {
         if !(bit_eq(addr, 0x01)) {
           self.target_register = (value & 0x7) as usize;
           self.prg_bank_mode = bit_eq(value, 0x40);
           self.chr_inversion = bit_eq(value, 0x80);
+          if self.board == Board::Mmc6 {
+            self.prg_ram_enable = bit_eq(value, 0x20);
+            if !self.prg_ram_enable {
+              self.prg_ram_protect = 0;
+            }
+          }
         } else {
           self.bank_register[self.target_register] = value as Address;
-
-          if !self.chr_inversion {
-            // Add 0xfe mask to ignore lowest bit
-            self.chr_banks[0] = self.update_bank_offset(self.bank_register[0] & 0xFE);
-            self.chr_banks[1] = self.update_bank_offset(self.bank_register[0] & 0xFE + 1);
-            self.chr_banks[2] = self.update_bank_offset(self.bank_register[1] & 0xFE);
-            self.chr_banks[3] = self.update_bank_offset(self.bank_register[1] & 0xFE + 1);
-            self.chr_banks[4] = self.update_bank_offset(self.bank_register[2]);
-            self.chr_banks[5] = self.update_bank_offset(self.bank_register[3]);
-            self.chr_banks[6] = self.update_bank_offset(self.bank_register[4]);
-            self.chr_banks[7] = self.update_bank_offset(self.bank_register[5]);
-          } else {
-            self.chr_banks[0] = self.update_bank_offset(self.bank_register[2]);
-            self.chr_banks[1] = self.update_bank_offset(self.bank_register[3]);
-            self.chr_banks[2] = self.update_bank_offset(self.bank_register[4]);
-            self.chr_banks[3] = self.update_bank_offset(self.bank_register[5]);
-            self.chr_banks[4] = self.update_bank_offset(self.bank_register[0] & 0xFE);
-            self.chr_banks[5] = self.update_bank_offset(self.bank_register[0] & 0xFE + 1);
-            self.chr_banks[6] = self.update_bank_offset(self.bank_register[1] & 0xFE);
-            self.chr_banks[7] = self.update_bank_offset(self.bank_register[1] & 0xFE + 1);
-          }
-
-          if !self.prg_bank_mode {
-            // ignore top two bits for R6 / R7 using 0x3F
-            self.prg_bank0 = (self.bank_register[6] & 0x3f) as usize * 0x2000;
-            self.prg_bank1 = (self.bank_register[7] & 0x3f) as usize * 0x2000;
-            self.prg_bank2 = self.rom_size - 0x4000;
-            self.prg_bank3 = self.rom_size - 0x2000;
-          } else {
-            self.prg_bank0 = self.rom_size - 0x4000;
-            self.prg_bank1 = (self.bank_register[7] & 0x3f) as usize * 0x2000;
-            self.prg_bank2 = (self.bank_register[6] & 0x3f) as usize * 0x2000;
-            self.prg_bank3 = self.rom_size - 0x2000;
-          }
         }
+        self.update_banks();
       }
       0xa000..=0xbfff => {
         // self.prg_bank1 = value as usize;
         if !bit_eq(addr, 0x01) {
-          // Mirroring
+          // Mirroring, TxSROM takes it from the CHR registers instead
+          if self.board == Board::TxSRom {
+            return;
+          }
           if bit_eq(self.cart_mirroring, 0x8) {
             self.mirroring = NameTableMirroring::FourScreen
           } else if bit_eq(value, 0x01) {
@@ -165,6 +291,8 @@ impl Mapper for TxRom {
             self.mirroring = NameTableMirroring::Vertical
           }
           self.mirror_cb.as_mut().unwrap()(self.mirroring.into());
+        } else if self.board == Board::Mmc6 && self.prg_ram_enable {
+          self.prg_ram_protect = value & 0xF0;
         } else {
           // PRG Ram Protect
         }
@@ -178,9 +306,11 @@ impl Mapper for TxRom {
         }
       }
       0xe000.. => {
-        // enable if add address
-        self.irq_enable = bit_eq(addr & 0x01, 0x01)
-        // TODO acknowledge any pending interrupts?
+        // enable if odd address, disabling also acknowledges the IRQ
+        self.irq_enable = bit_eq(addr & 0x01, 0x01);
+        if !self.irq_enable {
+          self.irq_pending = false;
+        }
       }
       _ => {}
     }
@@ -188,7 +318,10 @@ impl Mapper for TxRom {
 
   fn read_prg(&self, addr: Address) -> Byte {
     match addr {
-      0x6000..=0x7fff => self.prg_ram[(addr & 0x1fff) as usize],
+      0x6000..=0x7fff => match (&self.prg_ram, self.mmc6_ram(addr)) {
+        (Some(ram), (offset, true, _)) => ram[offset],
+        _ => 0,
+      },
       0x8000..=0x9fff => self.read_prg_bank(self.prg_bank0 + (addr & 0x1fff) as usize),
       0xa000..=0xbfff => self.read_prg_bank(self.prg_bank1 + (addr & 0x1fff) as usize),
       0xc000..=0xdfff => self.read_prg_bank(self.prg_bank2 + (addr & 0x1fff) as usize),
@@ -198,19 +331,25 @@ impl Mapper for TxRom {
   }
 
   fn write_chr(&mut self, addr: Address, value: Byte) {
-    if addr >= 0x2000 && addr <= 0x2fff {
+    if addr <= 0x1fff {
+      let slot = (addr >> 10) as usize;
+      if self.chr_ram_slots & (1 << slot) != 0 {
+        let offset = self.chr_banks[slot] + (addr & 0x3ff) as usize;
+        self.character_ram.as_mut().unwrap()[offset] = value;
+      }
+    } else if addr <= 0x2fff {
       self.mirroring_ram[(addr - 0x2000) as usize] = value;
     }
   }
 
   fn read_chr(&self, addr: Address) -> Byte {
     if addr <= 0x1fff {
-      if self.cart.get_vrom().len() == 0 {
-        return 0
-      }
       let back_select = addr >> 10;
       let base_address = self.chr_banks[back_select as usize];
-      self.cart.get_vrom()[(base_address + (addr & 0x3ff) as usize) as usize]
+      if self.chr_ram_slots & (1 << back_select) != 0 {
+        return self.character_ram.as_ref().unwrap()[base_address + (addr & 0x3ff) as usize];
+      }
+      self.cart.get_vrom()[base_address + (addr & 0x3ff) as usize]
     } else if addr <= 0x2fff {
       self.mirroring_ram[(addr - 0x2000) as usize]
     } else {
@@ -218,31 +357,233 @@ impl Mapper for TxRom {
     }
   }
 
+  fn maps_nametables(&self) -> bool {
+    self.board == Board::TxSRom
+  }
+
+  fn read_nametable(&self, addr: Address, ciram: &[Byte]) -> Byte {
+    ciram[self.ciram_offset(addr)]
+  }
+
+  fn write_nametable(&mut self, addr: Address, value: Byte, ciram: &mut [Byte]) {
+    ciram[self.ciram_offset(addr)] = value;
+  }
+
+  // version 1 savestates carry 32KB of PRG-RAM for every board
+  fn prg_ram(&self) -> Option<&[Byte]> {
+    self.prg_ram.as_deref().filter(|_| self.board == Board::Mmc6)
+  }
+
+  fn prg_ram_mut(&mut self) -> Option<&mut [Byte]> {
+    let mmc6 = self.board == Board::Mmc6;
+    self.prg_ram.as_deref_mut().filter(|_| mmc6)
+  }
+
   fn has_extended_ram(&self) -> bool {
     self.cart.has_extended_ram()
   }
 
+  fn has_battery(&self) -> bool {
+    self.cart.has_battery()
+  }
+
   fn get_name_table_mirroring(&self) -> u8 {
     self.mirroring.into()
   }
 
-  fn save(&self) -> String {
+  fn save(&self) -> Vec<u8> {
     super::save(self)
   }
 
-  fn mapper_type(&self) -> u8 {
-    TXROM
+  fn cartridge(&self) -> &Cartridge {
+    &self.cart
   }
 
-  fn scanline_irq(&mut self) {
-      if self.irq_counter == 0 || self.irq_reload_pending {
-        self.irq_counter = self.irq_latch as u32;
-        self.irq_reload_pending = false;
-      } else {
-        self.irq_counter-=1;
-        if self.irq_counter == 0 && self.irq_enable {
-            self.interrupt_cb.as_mut().unwrap()();
-        }
+  fn cartridge_mut(&mut self) -> &mut Cartridge {
+    &mut self.cart
+  }
+
+  fn mapper_type(&self) -> MapperType {
+    match self.board {
+      Board::TxSRom => TXSROM,
+      Board::TqRom => TQROM,
+      _ => TXROM,
+    }
+  }
+
+  fn watch_ppu_fetches(&self) -> bool {
+    true
+  }
+
+  fn notify_ppu_fetch(&mut self, addr: Address, dot: u64) {
+    if bit_eq(addr, 0x1000) {
+      if !self.a12_high && dot.wrapping_sub(self.a12_low_since) >= A12_FILTER_DOTS {
+        self.clock_irq();
+      }
+      self.a12_high = true;
+    } else if self.a12_high {
+      self.a12_high = false;
+      self.a12_low_since = dot;
+    }
+  }
+
+  fn irq_pending(&self) -> bool {
+    self.irq_pending
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::mapper::{
+    restore,
+    test::{load, test_rom},
+  };
+
+  fn rom(mapper: u8, submapper: u8) -> Cartridge {
+    let mut rom = test_rom(mapper, 128, 64, 0x2000, 0x400);
+    // NES 2.0 for the submapper
+    rom[7] |= 0x08;
+    rom[8] = submapper << 4;
+    load(&rom)
+  }
+
+  fn select(mapper: &mut TxRom, register: Byte, value: Byte) {
+    mapper.write_prg(0x8000, register);
+    mapper.write_prg(0x8001, value);
+  }
+
+  #[test]
+  fn banking_test() {
+    let mut mapper = TxRom::new(rom(4, 0), Box::new(|_| {}));
+    // the PRG-RAM of MMC3 boards is on the console side
+    assert!(mapper.prg_ram().is_none());
+    select(&mut mapper, 0, 5);
+    select(&mut mapper, 6, 3);
+    assert_eq!(mapper.read_chr(0x0000), 4);
+    assert_eq!(mapper.read_chr(0x0400), 5);
+    assert_eq!(mapper.read_prg(0x8000), 3);
+    assert_eq!(mapper.read_prg(0xE000), 15);
+    // switching modes takes effect without another bank write
+    mapper.write_prg(0x8000, 0xC0);
+    assert_eq!(mapper.read_chr(0x1400), 5);
+    assert_eq!(mapper.read_prg(0xC000), 3);
+
+    let loaded: TxRom = restore(&mapper.save(), mapper.cartridge()).unwrap();
+    assert_eq!(loaded.read_chr(0x1000), 4);
+    assert_eq!(loaded.read_prg(0x8000), 14);
+  }
+
+  #[test]
+  fn txsrom_test() {
+    let mut mapper = TxRom::new(rom(118, 0), Box::new(|_| {}));
+    assert_eq!(mapper.mapper_type(), TXSROM);
+    assert!(mapper.maps_nametables());
+    let mut ciram = vec![0; 0x800];
+    select(&mut mapper, 0, 0x80);
+    select(&mut mapper, 1, 0x00);
+    mapper.write_nametable(0x2400, 0x11, &mut ciram);
+    mapper.write_nametable(0x2800, 0x22, &mut ciram);
+    assert_eq!(ciram[0x400], 0x11);
+    assert_eq!(ciram[0x000], 0x22);
+    // with CHR inversion R2-R5 pick a page per nametable
+    select(&mut mapper, 0x85, 0x80);
+    assert_eq!(mapper.read_nametable(0x2C00, &ciram), 0x11);
+    assert_eq!(mapper.read_nametable(0x2000, &ciram), 0x22);
+  }
+
+  #[test]
+  fn tqrom_test() {
+    let mut mapper = TxRom::new(rom(119, 0), Box::new(|_| {}));
+    assert_eq!(mapper.mapper_type(), TQROM);
+    select(&mut mapper, 2, 0x41);
+    select(&mut mapper, 3, 7);
+    mapper.write_chr(0x1000, 0x55);
+    mapper.write_chr(0x1400, 0x66);
+    assert_eq!(mapper.read_chr(0x1000), 0x55);
+    assert_eq!(mapper.read_chr(0x1400), 7);
+    // the same RAM bank seen through another register
+    select(&mut mapper, 4, 0x41);
+    assert_eq!(mapper.read_chr(0x1800), 0x55);
+
+    let loaded: TxRom = restore(&mapper.save(), mapper.cartridge()).unwrap();
+    assert_eq!(loaded.read_chr(0x1000), 0x55);
+  }
+
+  #[test]
+  fn mmc6_test() {
+    let mut mapper = TxRom::new(rom(4, 1), Box::new(|_| {}));
+    assert_eq!(mapper.prg_ram().unwrap().len(), MMC6_RAM_SIZE);
+    // disabled until $8000 bit 5 is set
+    mapper.write_prg(0xA001, 0xF0);
+    mapper.write_prg(0x7000, 0x12);
+    assert_eq!(mapper.read_prg(0x7000), 0);
+    mapper.write_prg(0x8000, 0x20);
+    mapper.write_prg(0xA001, 0xF0);
+    mapper.write_prg(0x7000, 0x12);
+    mapper.write_prg(0x7200, 0x34);
+    // 1KB mirrored through $7000-$7FFF, nothing at $6000
+    assert_eq!(mapper.read_prg(0x7C00), 0x12);
+    assert_eq!(mapper.read_prg(0x6000), 0);
+    // lower half read only, upper half hidden
+    mapper.write_prg(0xA001, 0x20);
+    mapper.write_prg(0x7000, 0x56);
+    assert_eq!(mapper.read_prg(0x7000), 0x12);
+    assert_eq!(mapper.read_prg(0x7200), 0);
+    assert_eq!(mapper.prg_ram().unwrap()[0x200], 0x34);
+  }
+
+  fn count_irqs(submapper: Byte, latch: Byte, scanlines: usize) -> usize {
+    let mut irqs = 0;
+    let mut mapper = TxRom::new(rom(4, submapper), Box::new(|_| {}));
+    mapper.write_prg(0xC000, latch);
+    mapper.write_prg(0xC001, 0);
+    mapper.write_prg(0xE001, 0);
+    // background at $0000 and sprites at $1000, like most games
+    for line in 0..scanlines as u64 {
+      let dot = line * 341;
+      mapper.notify_ppu_fetch(0x2000, dot + 257);
+      for sprite in 0..8 {
+        mapper.notify_ppu_fetch(0x1000, dot + 261 + sprite * 8);
+        mapper.notify_ppu_fetch(0x2000, dot + 265 + sprite * 8);
       }
+      mapper.notify_ppu_fetch(0x0000, dot + 325);
+      // acknowledge and re-enable like an IRQ handler
+      if mapper.irq_pending() {
+        irqs += 1;
+        mapper.write_prg(0xE000, 0);
+        mapper.write_prg(0xE001, 0);
+      }
+    }
+    irqs
+  }
+
+  #[test]
+  fn a12_filter_test() {
+    let mut mapper = TxRom::new(rom(4, 0), Box::new(|_| {}));
+    mapper.write_prg(0xC000, 0);
+    mapper.write_prg(0xE001, 0);
+    mapper.notify_ppu_fetch(0x1000, 100);
+    assert!(mapper.irq_pending());
+    mapper.write_prg(0xE000, 0);
+    mapper.write_prg(0xE001, 0);
+    assert!(!mapper.irq_pending());
+    // a short drop is filtered out
+    mapper.notify_ppu_fetch(0x2000, 104);
+    mapper.notify_ppu_fetch(0x1000, 108);
+    assert!(!mapper.irq_pending());
+    mapper.notify_ppu_fetch(0x0FF0, 120);
+    mapper.notify_ppu_fetch(0x1FF0, 120 + A12_FILTER_DOTS);
+    assert!(mapper.irq_pending());
+  }
+
+  #[test]
+  fn irq_test() {
+    assert_eq!(count_irqs(0, 3, 9), 2);
+    assert_eq!(count_irqs(4, 3, 9), 2);
+    // a latch of 0 fires every scanline on MMC3C, once after $C001 on MMC3A
+    assert_eq!(count_irqs(0, 0, 5), 5);
+    assert_eq!(count_irqs(4, 0, 5), 1);
+    assert_eq!(count_irqs(3, 0, 5), 1);
   }
 }
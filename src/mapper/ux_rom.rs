@@ -6,19 +6,20 @@ use crate::cartridge::Cartridge;
 use crate::common::*;
 use crate::mapper::Mapper;
 
-use super::{save, UXROM};
+use super::{save, MapperType, UXROM};
 
 #[derive(Serialize, Deserialize)]
 pub struct UxRom {
   select_chr: Address,
+  #[serde(with = "serde_bytes")]
   character_ram: Option<Vec<Byte>>,
   cart: Cartridge,
 }
 
 impl UxRom {
   pub fn new(cart: Cartridge) -> Self {
-    let ram = if cart.get_vrom().len() == 0 {
-      Some(vec![0; 0x2000])
+    let ram = if cart.get_vrom().is_empty() {
+      Some(vec![0; cart.chr_ram_size()])
     } else {
       None
     };
@@ -65,15 +66,27 @@ impl Mapper for UxRom {
     self.cart.has_extended_ram()
   }
 
+  fn has_battery(&self) -> bool {
+    self.cart.has_battery()
+  }
+
   fn get_name_table_mirroring(&self) -> u8 {
     self.cart.get_name_table_mirroring()
   }
 
-  fn save(&self) -> String {
+  fn save(&self) -> Vec<u8> {
     save(self)
   }
 
-  fn mapper_type(&self) -> u8 {
+  fn cartridge(&self) -> &Cartridge {
+    &self.cart
+  }
+
+  fn cartridge_mut(&mut self) -> &mut Cartridge {
+    &mut self.cart
+  }
+
+  fn mapper_type(&self) -> MapperType {
     UXROM
   }
 }
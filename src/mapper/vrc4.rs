@@ -0,0 +1,341 @@
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::cartridge::Cartridge;
+use crate::common::*;
+
+use super::{
+  factory::{MirrorCallback, NameTableMirroring},
+  save,
+  vrc6::VrcIrq,
+  Mapper, MapperType, VRC2A, VRC2B, VRC4A,
+};
+
+const PRG_BANK_SIZE: usize = 0x2000;
+const CHR_BANK_SIZE: usize = 0x400;
+
+/**
+ * Konami VRC2 and VRC4 (mappers 21, 22, 23 and 25)
+ *
+ * Two switchable 8KB PRG banks, eight 1KB CHR banks written a nibble at a
+ * time and mirroring control. VRC4 adds a PRG swap mode, one-screen mirroring
+ * and the VRC IRQ counter.
+ *
+ * The boards wire the two register select lines to different CPU address
+ * lines, the NES 2.0 submapper tells them apart. Without one, both wirings of
+ * the mapper number are decoded:
+ *
+ * - 21: VRC4a (A1, A2) and VRC4c (A6, A7)
+ * - 22: VRC2a (A1, A0), CHR banks in 2KB units
+ * - 23: VRC4f and VRC2b (A0, A1), VRC4e (A2, A3)
+ * - 25: VRC4b and VRC2c (A1, A0), VRC4d (A3, A2)
+ *
+ * Reference https://www.nesdev.org/wiki/VRC2_and_VRC4
+ */
+#[derive(Serialize, Deserialize)]
+pub struct Vrc4 {
+  mapper_type: MapperType,
+  vrc2: bool,
+  // CPU address lines wired to the register selects
+  a0_lines: Address,
+  a1_lines: Address,
+
+  #[serde(with = "serde_bytes")]
+  character_ram: Option<Vec<Byte>>,
+  #[serde(with = "serde_bytes")]
+  prg_ram: Vec<Byte>,
+  cart: Cartridge,
+  #[serde(skip)]
+  mirror_cb: Option<MirrorCallback>,
+  mirroring: NameTableMirroring,
+
+  prg_regs: [Byte; 2],
+  prg_swap: bool,
+  ram_enabled: bool,
+  chr_regs: [Address; 8],
+
+  irq: VrcIrq,
+}
+
+impl Vrc4 {
+  pub fn new(cart: Cartridge, mirror_cb: MirrorCallback) -> Self {
+    let mapper_type = cart.get_mapper();
+    let submapper = cart.header().submapper;
+    let vrc2 = mapper_type == VRC2A || (submapper == 3 && mapper_type != VRC4A);
+    let (a0_lines, a1_lines) = match (mapper_type, submapper) {
+      (VRC4A, 1) => (0x02, 0x04),
+      (VRC4A, 2) => (0x40, 0x80),
+      (VRC4A, _) => (0x42, 0x84),
+      (VRC2A, _) => (0x02, 0x01),
+      (VRC2B, 1) | (VRC2B, 3) => (0x01, 0x02),
+      (VRC2B, 2) => (0x04, 0x08),
+      (VRC2B, _) => (0x05, 0x0A),
+      (_, 1) | (_, 3) => (0x02, 0x01),
+      (_, 2) => (0x08, 0x04),
+      _ => (0x0A, 0x05),
+    };
+    let ram = if cart.get_vrom().is_empty() {
+      Some(vec![0; cart.chr_ram_size()])
+    } else {
+      None
+    };
+    Self {
+      mapper_type,
+      vrc2,
+      a0_lines,
+      a1_lines,
+      character_ram: ram,
+      prg_ram: vec![0; PRG_BANK_SIZE],
+      mirroring: NameTableMirroring::from(cart.get_name_table_mirroring()),
+      cart,
+      mirror_cb: Some(mirror_cb),
+      prg_regs: [0; 2],
+      prg_swap: false,
+      // VRC2 has no enable bit
+      ram_enabled: vrc2,
+      chr_regs: [0; 8],
+      irq: VrcIrq::default(),
+    }
+  }
+
+  pub fn set_mirror_cb(&mut self, mirror_cb: MirrorCallback) {
+    self.mirror_cb = Some(mirror_cb);
+  }
+
+  /// Register number 0-3 of a write, from the wired address lines.
+  fn register(&self, addr: Address) -> Address {
+    (addr & self.a0_lines != 0) as Address | ((addr & self.a1_lines != 0) as Address) << 1
+  }
+
+  fn set_mirroring(&mut self, value: Byte) {
+    let value = if self.vrc2 {
+      value & 0x01
+    } else {
+      value & 0x03
+    };
+    let mirroring = match value {
+      0 => NameTableMirroring::Vertical,
+      1 => NameTableMirroring::Horizontal,
+      2 => NameTableMirroring::OneScreenLower,
+      _ => NameTableMirroring::OneScreenHigher,
+    };
+    if mirroring != self.mirroring {
+      self.mirroring = mirroring;
+      if let Some(cb) = self.mirror_cb.as_mut() {
+        cb(mirroring.into());
+      }
+    }
+  }
+
+  fn write_chr_reg(&mut self, index: usize, high: bool, value: Byte) {
+    let reg = self.chr_regs[index];
+    self.chr_regs[index] = if high {
+      let mask = if self.vrc2 { 0x0F } else { 0x1F };
+      (reg & 0x0F) | ((value & mask) as Address) << 4
+    } else {
+      (reg & 0x1F0) | (value & 0x0F) as Address
+    };
+  }
+
+  fn prg_offset(&self, bank: usize) -> usize {
+    let banks = std::cmp::max(self.cart.get_rom().len() / PRG_BANK_SIZE, 1);
+    (bank % banks) * PRG_BANK_SIZE
+  }
+}
+
+impl Mapper for Vrc4 {
+  fn write_prg(&mut self, addr: Address, value: Byte) {
+    if addr < 0x8000 {
+      if self.ram_enabled {
+        self.prg_ram[(addr & 0x1FFF) as usize] = value;
+      }
+      return;
+    }
+    let reg = self.register(addr);
+    match (addr & 0xF000, reg) {
+      (0x8000, _) => self.prg_regs[0] = value & 0x1F,
+      (0x9000, _) if self.vrc2 => self.set_mirroring(value),
+      (0x9000, 0) => self.set_mirroring(value),
+      (0x9000, 2) => {
+        self.ram_enabled = bit_eq(value, 0x01);
+        self.prg_swap = bit_eq(value, 0x02);
+      }
+      (0xA000, _) => self.prg_regs[1] = value & 0x1F,
+      (0xB000..=0xE000, _) => {
+        let index = (((addr - 0xB000) >> 11) | (reg >> 1)) as usize;
+        self.write_chr_reg(index, reg & 1 == 1, value);
+      }
+      (0xF000, _) if self.vrc2 => {}
+      (0xF000, 0) => self.irq.write_latch_nibble(false, value),
+      (0xF000, 1) => self.irq.write_latch_nibble(true, value),
+      (0xF000, 2) => self.irq.write_control(value),
+      (0xF000, _) => self.irq.acknowledge(),
+      _ => {}
+    }
+  }
+
+  fn read_prg(&self, addr: Address) -> Byte {
+    let rom = self.cart.get_rom();
+    let banks = rom.len() / PRG_BANK_SIZE;
+    let bank = match addr {
+      0x6000..=0x7FFF if self.ram_enabled => return self.prg_ram[(addr & 0x1FFF) as usize],
+      0x8000..=0x9FFF if self.prg_swap => banks - 2,
+      0x8000..=0x9FFF => self.prg_regs[0] as usize,
+      0xA000..=0xBFFF => self.prg_regs[1] as usize,
+      0xC000..=0xDFFF if self.prg_swap => self.prg_regs[0] as usize,
+      0xC000..=0xDFFF => banks - 2,
+      0xE000..=0xFFFF => banks - 1,
+      _ => return 0,
+    };
+    rom[self.prg_offset(bank) + (addr & 0x1FFF) as usize]
+  }
+
+  fn write_chr(&mut self, addr: Address, value: Byte) {
+    match &mut self.character_ram {
+      Some(ram) => ram[addr as usize] = value,
+      None => warn!("Attempting to write read-only CHR memory on {:#x}", addr),
+    }
+  }
+
+  fn read_chr(&self, addr: Address) -> Byte {
+    match &self.character_ram {
+      Some(ram) => ram[addr as usize],
+      None => {
+        let mut bank = self.chr_regs[(addr >> 10) as usize & 7] as usize;
+        if self.mapper_type == VRC2A {
+          // the lowest bit of the registers is not connected
+          bank >>= 1;
+        }
+        let vrom = self.cart.get_vrom();
+        vrom[(bank * CHR_BANK_SIZE + (addr as usize & (CHR_BANK_SIZE - 1))) % vrom.len()]
+      }
+    }
+  }
+
+  fn prg_ram(&self) -> Option<&[Byte]> {
+    Some(&self.prg_ram)
+  }
+
+  fn prg_ram_mut(&mut self) -> Option<&mut [Byte]> {
+    Some(&mut self.prg_ram)
+  }
+
+  fn cpu_clock(&mut self) {
+    self.irq.clock();
+  }
+
+  fn irq_pending(&self) -> bool {
+    self.irq.pending()
+  }
+
+  fn has_extended_ram(&self) -> bool {
+    true
+  }
+
+  fn has_battery(&self) -> bool {
+    self.cart.has_battery()
+  }
+
+  fn get_name_table_mirroring(&self) -> u8 {
+    self.mirroring.into()
+  }
+
+  fn save(&self) -> Vec<u8> {
+    save(self)
+  }
+
+  fn cartridge(&self) -> &Cartridge {
+    &self.cart
+  }
+
+  fn cartridge_mut(&mut self) -> &mut Cartridge {
+    &mut self.cart
+  }
+
+  fn mapper_type(&self) -> MapperType {
+    self.mapper_type
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::mapper::test::{load, test_rom};
+
+  fn vrc4_rom(mapper: Byte, submapper: Byte) -> Cartridge {
+    let mut rom = test_rom(mapper, 128, 64, PRG_BANK_SIZE, CHR_BANK_SIZE);
+    // NES 2.0 for the submapper
+    rom[7] |= 0x08;
+    rom[8] = submapper << 4;
+    load(&rom)
+  }
+
+  #[test]
+  fn vrc4_test() {
+    // VRC4c, registers on A6 and A7
+    let mut mapper = Vrc4::new(vrc4_rom(21, 2), Box::new(|_| {}));
+    assert_eq!(mapper.mapper_type(), VRC4A);
+    mapper.write_prg(0x8000, 3);
+    mapper.write_prg(0xA000, 4);
+    assert_eq!(mapper.read_prg(0x8000), 3);
+    assert_eq!(mapper.read_prg(0xA000), 4);
+    assert_eq!(mapper.read_prg(0xC000), 14);
+    assert_eq!(mapper.read_prg(0xE000), 15);
+
+    // swap mode on $9002
+    mapper.write_prg(0x9080, 0x03);
+    assert_eq!(mapper.read_prg(0x8000), 14);
+    assert_eq!(mapper.read_prg(0xC000), 3);
+
+    // CHR 1 is $B002/$B003, CHR 7 is $E002/$E003
+    mapper.write_prg(0xB080, 0x01);
+    mapper.write_prg(0xB0C0, 0x02);
+    mapper.write_prg(0xE0C0, 0x02);
+    assert_eq!(mapper.read_chr(0x0400), 33);
+    assert_eq!(mapper.read_chr(0x1C00), 32);
+
+    // PRG-RAM enabled by the $9002 write above
+    mapper.write_prg(0x6000, 0x42);
+    assert_eq!(mapper.read_prg(0x6000), 0x42);
+  }
+
+  #[test]
+  fn vrc2_test() {
+    // VRC2a, A1 and A0 swapped, CHR banks in 2KB units
+    let mut mapper = Vrc4::new(vrc4_rom(22, 0), Box::new(|_| {}));
+    assert_eq!(mapper.mapper_type(), VRC2A);
+    mapper.write_prg(0xB000, 0x06);
+    mapper.write_prg(0xB002, 0x01);
+    assert_eq!(mapper.read_chr(0x0000), 11);
+    // $9002 is mirroring on VRC2, not swap mode
+    mapper.write_prg(0x8000, 3);
+    mapper.write_prg(0x9002, 0x03);
+    assert_eq!(mapper.read_prg(0x8000), 3);
+    assert_eq!(
+      mapper.get_name_table_mirroring(),
+      NameTableMirroring::Horizontal as u8
+    );
+  }
+
+  #[test]
+  fn irq_test() {
+    // iNES mapper 25, VRC4b and VRC4d decoded together
+    let mut mapper = Vrc4::new(vrc4_rom(25, 0), Box::new(|_| {}));
+    // latch $F0 written as two nibbles, $F000 and $F002 on VRC4b
+    mapper.write_prg(0xF000, 0x00);
+    mapper.write_prg(0xF002, 0x0F);
+    // control on $F001 (VRC4b) or $F004 (VRC4d)
+    mapper.write_prg(0xF004, 0x06);
+    for _ in 0..15 {
+      mapper.cpu_clock();
+    }
+    assert!(!mapper.irq_pending());
+    mapper.cpu_clock();
+    assert!(mapper.irq_pending());
+    // the line stays asserted until acknowledged
+    mapper.cpu_clock();
+    assert!(mapper.irq_pending());
+    mapper.write_prg(0xF00C, 0);
+    assert!(!mapper.irq_pending());
+  }
+}
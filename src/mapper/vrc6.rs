@@ -0,0 +1,352 @@
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::apu::Vrc6Audio;
+use crate::cartridge::Cartridge;
+use crate::common::*;
+
+use super::{
+  factory::{MirrorCallback, NameTableMirroring},
+  save, Mapper, MapperType, VRC6A, VRC6B,
+};
+
+const CHR_BANK_SIZE: usize = 0x400;
+
+/**
+ * IRQ counter of the Konami VRC chips.
+ *
+ * The 8-bit counter counts up to $FF, then reloads from the latch and raises
+ * the IRQ line until it is acknowledged. In scanline mode a prescaler divides
+ * CPU cycles by 113.667, in cycle mode every CPU cycle clocks the counter.
+ */
+#[derive(Default, Serialize, Deserialize)]
+pub(crate) struct VrcIrq {
+  latch: Byte,
+  counter: Byte,
+  prescaler: i16,
+  enabled: bool,
+  enable_after_ack: bool,
+  cycle_mode: bool,
+  pending: bool,
+}
+
+impl VrcIrq {
+  pub fn write_latch(&mut self, value: Byte) {
+    self.latch = value;
+  }
+
+  /// VRC4 writes the latch a nibble at a time.
+  pub fn write_latch_nibble(&mut self, high: bool, value: Byte) {
+    self.latch = if high {
+      (self.latch & 0x0F) | (value << 4)
+    } else {
+      (self.latch & 0xF0) | (value & 0x0F)
+    };
+  }
+
+  pub fn write_control(&mut self, value: Byte) {
+    self.enable_after_ack = bit_eq(value, 0x01);
+    self.enabled = bit_eq(value, 0x02);
+    self.cycle_mode = bit_eq(value, 0x04);
+    self.pending = false;
+    if self.enabled {
+      self.counter = self.latch;
+      self.prescaler = 341;
+    }
+  }
+
+  pub fn acknowledge(&mut self) {
+    self.enabled = self.enable_after_ack;
+    self.pending = false;
+  }
+
+  pub fn pending(&self) -> bool {
+    self.pending
+  }
+
+  /// Clock one CPU cycle.
+  pub fn clock(&mut self) {
+    if !self.enabled {
+      return;
+    }
+    if !self.cycle_mode {
+      self.prescaler -= 3;
+      if self.prescaler > 0 {
+        return;
+      }
+      self.prescaler += 341;
+    }
+    if self.counter == 0xFF {
+      self.counter = self.latch;
+      self.pending = true;
+    } else {
+      self.counter += 1;
+    }
+  }
+}
+
+/**
+ * Konami VRC6 (mappers 24 and 26)
+ *
+ * A switchable 16KB and 8KB PRG bank followed by the fixed last 8KB, eight
+ * CHR registers and a sound chip. VRC6b (mapper 26) swaps the A0 and A1
+ * address lines.
+ *
+ * Only the nametable mirroring of the $B003 modes is supported, nametables
+ * from CHR-ROM are not.
+ *
+ * Reference https://www.nesdev.org/wiki/VRC6
+ */
+#[derive(Serialize, Deserialize)]
+pub struct Vrc6 {
+  vrc6b: bool,
+  #[serde(with = "serde_bytes")]
+  character_ram: Option<Vec<Byte>>,
+  cart: Cartridge,
+  #[serde(skip)]
+  mirror_cb: Option<MirrorCallback>,
+  mirroring: NameTableMirroring,
+
+  prg_16k: usize, // offset of rom
+  prg_8k: usize,  // offset of rom
+  chr_regs: [Byte; 8],
+  banking_mode: Byte,
+
+  irq: VrcIrq,
+  audio: Vrc6Audio,
+}
+
+impl Vrc6 {
+  pub fn new(cart: Cartridge, vrc6b: bool, mirror_cb: MirrorCallback) -> Self {
+    let ram = if cart.get_vrom().is_empty() {
+      Some(vec![0; cart.chr_ram_size()])
+    } else {
+      None
+    };
+    Self {
+      vrc6b,
+      character_ram: ram,
+      mirroring: NameTableMirroring::from(cart.get_name_table_mirroring()),
+      cart,
+      mirror_cb: Some(mirror_cb),
+      prg_16k: 0,
+      prg_8k: 0,
+      chr_regs: [0; 8],
+      banking_mode: 0,
+      irq: VrcIrq::default(),
+      audio: Vrc6Audio::new(),
+    }
+  }
+
+  pub fn set_mirror_cb(&mut self, mirror_cb: MirrorCallback) {
+    self.mirror_cb = Some(mirror_cb);
+  }
+
+  fn write_banking_mode(&mut self, value: Byte) {
+    self.banking_mode = value;
+    let mirroring = match (value >> 2) & 0x03 {
+      0 => NameTableMirroring::Vertical,
+      1 => NameTableMirroring::Horizontal,
+      2 => NameTableMirroring::OneScreenLower,
+      _ => NameTableMirroring::OneScreenHigher,
+    };
+    if mirroring != self.mirroring {
+      self.mirroring = mirroring;
+      if let Some(cb) = self.mirror_cb.as_mut() {
+        cb(mirroring.into());
+      }
+    }
+  }
+
+  /// 1KB CHR bank of a 1KB slot of the pattern tables.
+  fn chr_bank(&self, slot: usize) -> usize {
+    // which register and whether it selects a 2KB bank
+    let (reg, pair) = match self.banking_mode & 0x03 {
+      0 => (slot, false),
+      1 => (slot / 2, true),
+      _ if slot < 4 => (slot, false),
+      _ => (4 + (slot - 4) / 2, true),
+    };
+    let bank = self.chr_regs[reg] as usize;
+    if pair && bit_eq(self.banking_mode, 0x20) {
+      // PPU A10 replaces the lowest bit
+      (bank & !1) | (slot & 1)
+    } else {
+      bank
+    }
+  }
+}
+
+impl Mapper for Vrc6 {
+  fn write_prg(&mut self, addr: Address, value: Byte) {
+    let addr = if self.vrc6b {
+      (addr & 0xFFFC) | ((addr & 0x01) << 1) | ((addr & 0x02) >> 1)
+    } else {
+      addr
+    };
+    match addr & 0xF003 {
+      0x8000..=0x8003 => {
+        let banks = std::cmp::max(self.cart.get_rom().len() / 0x4000, 1);
+        self.prg_16k = ((value & 0x0F) as usize % banks) * 0x4000;
+      }
+      0x9000..=0x9003 | 0xA000..=0xA002 | 0xB000..=0xB002 => self.audio.write(addr, value),
+      0xB003 => self.write_banking_mode(value),
+      0xC000..=0xC003 => {
+        let banks = std::cmp::max(self.cart.get_rom().len() / 0x2000, 1);
+        self.prg_8k = ((value & 0x1F) as usize % banks) * 0x2000;
+      }
+      0xD000..=0xD003 => self.chr_regs[(addr & 0x03) as usize] = value,
+      0xE000..=0xE003 => self.chr_regs[4 + (addr & 0x03) as usize] = value,
+      0xF000 => self.irq.write_latch(value),
+      0xF001 => self.irq.write_control(value),
+      0xF002 => self.irq.acknowledge(),
+      _ => {}
+    }
+  }
+
+  fn read_prg(&self, addr: Address) -> Byte {
+    let rom = self.cart.get_rom();
+    match addr {
+      0x8000..=0xBFFF => rom[self.prg_16k + (addr & 0x3FFF) as usize],
+      0xC000..=0xDFFF => rom[self.prg_8k + (addr & 0x1FFF) as usize],
+      0xE000..=0xFFFF => rom[rom.len() - 0x2000 + (addr & 0x1FFF) as usize],
+      _ => 0,
+    }
+  }
+
+  fn write_chr(&mut self, addr: Address, value: Byte) {
+    match &mut self.character_ram {
+      Some(ram) => ram[addr as usize] = value,
+      None => warn!("Attempting to write read-only CHR memory on {:#x}", addr),
+    }
+  }
+
+  fn read_chr(&self, addr: Address) -> Byte {
+    match &self.character_ram {
+      Some(ram) => ram[addr as usize],
+      None => {
+        let vrom = self.cart.get_vrom();
+        let offset = self.chr_bank((addr >> 10) as usize & 7) * CHR_BANK_SIZE;
+        vrom[(offset + (addr as usize & (CHR_BANK_SIZE - 1))) % vrom.len()]
+      }
+    }
+  }
+
+  fn cpu_clock(&mut self) {
+    self.irq.clock();
+  }
+
+  fn irq_pending(&self) -> bool {
+    self.irq.pending()
+  }
+
+  fn has_expansion_audio(&self) -> bool {
+    true
+  }
+
+  fn step_audio(&mut self) {
+    self.audio.step();
+  }
+
+  fn audio_output(&self) -> f32 {
+    self.audio.output()
+  }
+
+  fn has_extended_ram(&self) -> bool {
+    self.cart.has_extended_ram()
+  }
+
+  fn has_battery(&self) -> bool {
+    self.cart.has_battery()
+  }
+
+  fn get_name_table_mirroring(&self) -> u8 {
+    self.mirroring.into()
+  }
+
+  fn save(&self) -> Vec<u8> {
+    save(self)
+  }
+
+  fn cartridge(&self) -> &Cartridge {
+    &self.cart
+  }
+
+  fn cartridge_mut(&mut self) -> &mut Cartridge {
+    &mut self.cart
+  }
+
+  fn mapper_type(&self) -> MapperType {
+    if self.vrc6b {
+      VRC6B
+    } else {
+      VRC6A
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::mapper::test::test_cart;
+
+  fn vrc6_rom(mapper: Byte) -> Cartridge {
+    test_cart(mapper, 128, 64, 0x2000, CHR_BANK_SIZE)
+  }
+
+  #[test]
+  fn banking_test() {
+    let mut mapper = Vrc6::new(vrc6_rom(26), true, Box::new(|_| {}));
+    assert_eq!(mapper.mapper_type(), VRC6B);
+    assert_eq!(mapper.read_prg(0xE000), 15);
+    mapper.write_prg(0x8000, 2);
+    mapper.write_prg(0xC000, 9);
+    assert_eq!(mapper.read_prg(0x8000), 4);
+    assert_eq!(mapper.read_prg(0xA000), 5);
+    assert_eq!(mapper.read_prg(0xC000), 9);
+
+    // $D001 of VRC6a is $D002 on VRC6b
+    mapper.write_prg(0xD002, 33);
+    assert_eq!(mapper.read_chr(0x0400), 33);
+    mapper.write_prg(0xE003, 40);
+    assert_eq!(mapper.read_chr(0x1C00), 40);
+
+    // 2KB banks with PPU A10 as the lowest bit
+    mapper.write_prg(0xB003, 0x21);
+    mapper.write_prg(0xD000, 20);
+    assert_eq!(mapper.read_chr(0x0000), 20);
+    assert_eq!(mapper.read_chr(0x0400), 21);
+  }
+
+  #[test]
+  fn irq_test() {
+    let mut mapper = Vrc6::new(vrc6_rom(24), false, Box::new(|_| {}));
+    // cycle mode, 16 cycles to overflow
+    mapper.write_prg(0xF000, 0xF0);
+    mapper.write_prg(0xF001, 0x06);
+    for _ in 0..15 {
+      mapper.cpu_clock();
+    }
+    assert!(!mapper.irq_pending());
+    mapper.cpu_clock();
+    assert!(mapper.irq_pending());
+
+    // scanline mode, one scanline is 113.667 CPU cycles; writing the control
+    // acknowledges the IRQ
+    mapper.write_prg(0xF000, 0xFE);
+    mapper.write_prg(0xF001, 0x02);
+    assert!(!mapper.irq_pending());
+    for _ in 0..227 {
+      mapper.cpu_clock();
+    }
+    assert!(!mapper.irq_pending());
+    mapper.cpu_clock();
+    assert!(mapper.irq_pending());
+    mapper.write_prg(0xF002, 0);
+    assert!(!mapper.irq_pending());
+    for _ in 0..1000 {
+      mapper.cpu_clock();
+    }
+    assert!(!mapper.irq_pending());
+  }
+}
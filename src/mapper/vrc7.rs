@@ -0,0 +1,255 @@
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::apu::Opll;
+use crate::cartridge::Cartridge;
+use crate::common::*;
+
+use super::{
+  factory::{MirrorCallback, NameTableMirroring},
+  save,
+  vrc6::VrcIrq,
+  Mapper, MapperType, VRC7,
+};
+
+const PRG_BANK_SIZE: usize = 0x2000;
+const CHR_BANK_SIZE: usize = 0x400;
+
+/**
+ * Konami VRC7 (mapper 85)
+ *
+ * Three switchable 8KB PRG banks followed by the fixed last 8KB, eight 1KB
+ * CHR banks, 8KB of PRG-RAM, the VRC IRQ counter and an FM sound chip.
+ *
+ * VRC7a (Lagrange Point) puts the second register of each pair on A4, VRC7b
+ * (Tiny Toon Adventures 2) on A3, both are decoded.
+ *
+ * Reference https://www.nesdev.org/wiki/VRC7
+ */
+#[derive(Serialize, Deserialize)]
+pub struct Vrc7 {
+  #[serde(with = "serde_bytes")]
+  character_ram: Option<Vec<Byte>>,
+  #[serde(with = "serde_bytes")]
+  prg_ram: Vec<Byte>,
+  cart: Cartridge,
+  #[serde(skip)]
+  mirror_cb: Option<MirrorCallback>,
+  mirroring: NameTableMirroring,
+
+  prg_banks: [usize; 3], // offsets of rom
+  chr_banks: [usize; 8], // offsets of vrom
+  prg_ram_enabled: bool,
+
+  irq: VrcIrq,
+  audio: Opll,
+}
+
+impl Vrc7 {
+  pub fn new(cart: Cartridge, mirror_cb: MirrorCallback) -> Self {
+    let ram = if cart.get_vrom().is_empty() {
+      Some(vec![0; cart.chr_ram_size()])
+    } else {
+      None
+    };
+    Self {
+      character_ram: ram,
+      prg_ram: vec![0; PRG_BANK_SIZE],
+      mirroring: NameTableMirroring::from(cart.get_name_table_mirroring()),
+      cart,
+      mirror_cb: Some(mirror_cb),
+      prg_banks: [0; 3],
+      chr_banks: [0; 8],
+      prg_ram_enabled: false,
+      irq: VrcIrq::default(),
+      audio: Opll::new(),
+    }
+  }
+
+  pub fn set_mirror_cb(&mut self, mirror_cb: MirrorCallback) {
+    self.mirror_cb = Some(mirror_cb);
+  }
+
+  fn write_prg_bank(&mut self, index: usize, value: Byte) {
+    let banks = std::cmp::max(self.cart.get_rom().len() / PRG_BANK_SIZE, 1);
+    self.prg_banks[index] = ((value & 0x3F) as usize % banks) * PRG_BANK_SIZE;
+  }
+
+  fn write_control(&mut self, value: Byte) {
+    let mirroring = match value & 0x03 {
+      0 => NameTableMirroring::Vertical,
+      1 => NameTableMirroring::Horizontal,
+      2 => NameTableMirroring::OneScreenLower,
+      _ => NameTableMirroring::OneScreenHigher,
+    };
+    if mirroring != self.mirroring {
+      self.mirroring = mirroring;
+      if let Some(cb) = self.mirror_cb.as_mut() {
+        cb(mirroring.into());
+      }
+    }
+    if bit_eq(value, 0x40) {
+      self.audio.reset();
+    }
+    self.prg_ram_enabled = bit_eq(value, 0x80);
+  }
+}
+
+impl Mapper for Vrc7 {
+  fn write_prg(&mut self, addr: Address, value: Byte) {
+    if addr < 0x8000 {
+      if self.prg_ram_enabled {
+        self.prg_ram[(addr & 0x1FFF) as usize] = value;
+      }
+      return;
+    }
+    let second = addr & 0x18 != 0;
+    match (addr & 0xF000, second) {
+      (0x8000, _) => self.write_prg_bank(second as usize, value),
+      (0x9000, _) if bit_eq(addr as Byte, 0x20) => self.audio.write_data(value),
+      (0x9000, true) => self.audio.write_address(value),
+      (0x9000, false) => self.write_prg_bank(2, value),
+      (0xA000..=0xD000, _) => {
+        let index = ((addr - 0xA000) >> 11) as usize | second as usize;
+        let banks = std::cmp::max(self.cart.get_vrom().len() / CHR_BANK_SIZE, 1);
+        self.chr_banks[index] = (value as usize % banks) * CHR_BANK_SIZE;
+      }
+      (0xE000, false) => self.write_control(value),
+      (0xE000, true) => self.irq.write_latch(value),
+      (0xF000, false) => self.irq.write_control(value),
+      (0xF000, true) => self.irq.acknowledge(),
+      _ => {}
+    }
+  }
+
+  fn read_prg(&self, addr: Address) -> Byte {
+    let rom = self.cart.get_rom();
+    match addr {
+      0x6000..=0x7FFF if self.prg_ram_enabled => self.prg_ram[(addr & 0x1FFF) as usize],
+      0x8000..=0xDFFF => {
+        let bank = self.prg_banks[((addr - 0x8000) >> 13) as usize];
+        rom[bank + (addr & 0x1FFF) as usize]
+      }
+      0xE000..=0xFFFF => rom[rom.len() - PRG_BANK_SIZE + (addr & 0x1FFF) as usize],
+      _ => 0,
+    }
+  }
+
+  fn write_chr(&mut self, addr: Address, value: Byte) {
+    match &mut self.character_ram {
+      Some(ram) => ram[addr as usize] = value,
+      None => warn!("Attempting to write read-only CHR memory on {:#x}", addr),
+    }
+  }
+
+  fn read_chr(&self, addr: Address) -> Byte {
+    match &self.character_ram {
+      Some(ram) => ram[addr as usize],
+      None => {
+        let bank = self.chr_banks[(addr >> 10) as usize & 7];
+        self.cart.get_vrom()[bank + (addr as usize & (CHR_BANK_SIZE - 1))]
+      }
+    }
+  }
+
+  fn prg_ram(&self) -> Option<&[Byte]> {
+    Some(&self.prg_ram)
+  }
+
+  fn prg_ram_mut(&mut self) -> Option<&mut [Byte]> {
+    Some(&mut self.prg_ram)
+  }
+
+  fn cpu_clock(&mut self) {
+    self.irq.clock();
+  }
+
+  fn irq_pending(&self) -> bool {
+    self.irq.pending()
+  }
+
+  fn has_expansion_audio(&self) -> bool {
+    true
+  }
+
+  fn step_audio(&mut self) {
+    self.audio.step();
+  }
+
+  fn audio_output(&self) -> f32 {
+    self.audio.output()
+  }
+
+  fn has_extended_ram(&self) -> bool {
+    true
+  }
+
+  fn has_battery(&self) -> bool {
+    self.cart.has_battery()
+  }
+
+  fn get_name_table_mirroring(&self) -> u8 {
+    self.mirroring.into()
+  }
+
+  fn save(&self) -> Vec<u8> {
+    save(self)
+  }
+
+  fn cartridge(&self) -> &Cartridge {
+    &self.cart
+  }
+
+  fn cartridge_mut(&mut self) -> &mut Cartridge {
+    &mut self.cart
+  }
+
+  fn mapper_type(&self) -> MapperType {
+    VRC7
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::mapper::test::test_cart;
+
+  fn vrc7_rom() -> Cartridge {
+    test_cart(85, 128, 64, PRG_BANK_SIZE, CHR_BANK_SIZE)
+  }
+
+  #[test]
+  fn banking_test() {
+    let mut mapper = Vrc7::new(vrc7_rom(), Box::new(|_| {}));
+    assert_eq!(mapper.mapper_type(), VRC7);
+    assert_eq!(mapper.read_prg(0xE000), 15);
+    mapper.write_prg(0x8000, 3);
+    // VRC7a on A4, VRC7b on A3
+    mapper.write_prg(0x8010, 4);
+    mapper.write_prg(0x9000, 5);
+    assert_eq!(mapper.read_prg(0x8000), 3);
+    assert_eq!(mapper.read_prg(0xA000), 4);
+    assert_eq!(mapper.read_prg(0xC000), 5);
+    mapper.write_prg(0x8008, 6);
+    assert_eq!(mapper.read_prg(0xA000), 6);
+
+    mapper.write_prg(0xA000, 10);
+    mapper.write_prg(0xA008, 11);
+    mapper.write_prg(0xD010, 40);
+    assert_eq!(mapper.read_chr(0x0000), 10);
+    assert_eq!(mapper.read_chr(0x0400), 11);
+    assert_eq!(mapper.read_chr(0x1C00), 40);
+
+    // PRG-RAM only answers when enabled
+    mapper.write_prg(0x6000, 0x42);
+    assert_eq!(mapper.read_prg(0x6000), 0);
+    mapper.write_prg(0xE000, 0x80);
+    mapper.write_prg(0x6000, 0x42);
+    assert_eq!(mapper.read_prg(0x6000), 0x42);
+
+    // sound registers do not touch the PRG bank at $C000
+    mapper.write_prg(0x9010, 0x30);
+    mapper.write_prg(0x9030, 0x10);
+    assert_eq!(mapper.read_prg(0xC000), 5);
+  }
+}
@@ -0,0 +1,226 @@
+//! Input movies in FCEUX's `.fm2` text format
+//!
+//! Reference https://fceux.com/web/help/fm2.html
+use std::fmt::Write;
+
+use anyhow::{anyhow, bail};
+
+use crate::NesResult;
+
+/// Soft reset command of an input frame.
+pub const COMMAND_RESET: u8 = 0x01;
+/// Power cycle command of an input frame.
+pub const COMMAND_POWER: u8 = 0x02;
+
+/// FM2 writes gamepad buttons from bit 7 down to bit 0.
+const BUTTONS: &[u8; 8] = b"RLDUTSBA";
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct MovieFrame {
+  pub commands: u8,
+  /// Buttons of both ports, A, B, Select, Start, Up, Down, Left, Right from
+  /// bit 0 to bit 7.
+  pub ports: [u8; 2],
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Movie {
+  pub rom_filename: String,
+  /// MD5 of the ROM without header.
+  pub rom_checksum: Option<[u8; 16]>,
+  pub guid: String,
+  pub pal: bool,
+  pub rerecord_count: u32,
+  pub comments: Vec<String>,
+  /// Savestate the movie starts from, power-on if `None`. This is our own
+  /// savestate container, FCEUX can't read it.
+  pub savestate: Option<Vec<u8>>,
+  pub frames: Vec<MovieFrame>,
+}
+
+impl Movie {
+  pub fn new(rom_filename: &str, rom_checksum: [u8; 16], pal: bool) -> Self {
+    Self {
+      rom_filename: rom_filename.to_string(),
+      rom_checksum: Some(rom_checksum),
+      guid: random_guid(),
+      pal,
+      ..Self::default()
+    }
+  }
+
+  pub fn parse_fm2(text: &str) -> NesResult<Self> {
+    let mut movie = Movie::default();
+    let mut version = None;
+    for (no, line) in text.lines().enumerate() {
+      let line = line.trim_end_matches('\r');
+      if line.is_empty() {
+        continue;
+      }
+      if line.starts_with('|') {
+        let frame = parse_frame(line).map_err(|e| anyhow!("line {}: {}", no + 1, e))?;
+        movie.frames.push(frame);
+        continue;
+      }
+      let (key, value) = match line.find(' ') {
+        Some(i) => (&line[..i], &line[i + 1..]),
+        None => (line, ""),
+      };
+      match key {
+        "version" => version = Some(value.to_string()),
+        "romFilename" => movie.rom_filename = value.to_string(),
+        "romChecksum" => {
+          let checksum = decode_base64(value)?;
+          if checksum.len() != 16 {
+            bail!("invalid romChecksum {}", value);
+          }
+          let mut md5 = [0; 16];
+          md5.copy_from_slice(&checksum);
+          movie.rom_checksum = Some(md5);
+        }
+        "guid" => movie.guid = value.to_string(),
+        "palFlag" => movie.pal = value == "1",
+        "rerecordCount" => movie.rerecord_count = value.parse().unwrap_or(0),
+        "comment" => movie.comments.push(value.to_string()),
+        "savestate" => movie.savestate = Some(decode_base64(value)?),
+        "fourscore" | "microphone" | "port2" | "FDS" if value != "0" => {
+          bail!("{} {} is not supported", key, value)
+        }
+        "port0" | "port1" if value != "1" && value != "0" => {
+          bail!("{} {} is not supported, only gamepads are", key, value)
+        }
+        _ => {}
+      }
+    }
+    if version.as_deref() != Some("3") {
+      bail!("unsupported fm2 version {:?}", version);
+    }
+    Ok(movie)
+  }
+
+  pub fn to_fm2(&self) -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "version 3");
+    let _ = writeln!(out, "emuVersion 22020");
+    let _ = writeln!(out, "rerecordCount {}", self.rerecord_count);
+    let _ = writeln!(out, "palFlag {}", self.pal as u8);
+    let _ = writeln!(out, "romFilename {}", self.rom_filename);
+    if let Some(checksum) = self.rom_checksum {
+      let _ = writeln!(out, "romChecksum base64:{}", base64::encode(checksum));
+    }
+    let _ = writeln!(out, "guid {}", self.guid);
+    let _ = writeln!(out, "fourscore 0");
+    let _ = writeln!(out, "microphone 0");
+    let _ = writeln!(out, "port0 1");
+    let _ = writeln!(out, "port1 1");
+    let _ = writeln!(out, "port2 0");
+    let _ = writeln!(out, "FDS 0");
+    let _ = writeln!(out, "NewPPU 0");
+    for comment in &self.comments {
+      let _ = writeln!(out, "comment {}", comment);
+    }
+    if let Some(savestate) = &self.savestate {
+      let _ = writeln!(out, "savestate base64:{}", base64::encode(savestate));
+    }
+    for frame in &self.frames {
+      let _ = writeln!(
+        out,
+        "|{}|{}|{}||",
+        frame.commands,
+        format_buttons(frame.ports[0]),
+        format_buttons(frame.ports[1])
+      );
+    }
+    out
+  }
+}
+
+fn parse_frame(line: &str) -> NesResult<MovieFrame> {
+  let fields: Vec<&str> = line.split('|').collect();
+  if fields.len() < 4 {
+    bail!("invalid input line {}", line);
+  }
+  let commands = fields[1]
+    .parse()
+    .map_err(|_| anyhow!("invalid commands {}", fields[1]))?;
+  Ok(MovieFrame {
+    commands,
+    ports: [parse_buttons(fields[2])?, parse_buttons(fields[3])?],
+  })
+}
+
+fn parse_buttons(field: &str) -> NesResult<u8> {
+  if field.is_empty() {
+    return Ok(0);
+  }
+  if field.len() != BUTTONS.len() {
+    bail!("invalid gamepad input {}", field);
+  }
+  Ok(
+    field
+      .bytes()
+      .enumerate()
+      .filter(|(_, c)| *c != b'.' && *c != b' ')
+      .fold(0, |buttons, (i, _)| buttons | 0x80 >> i),
+  )
+}
+
+fn format_buttons(buttons: u8) -> String {
+  BUTTONS
+    .iter()
+    .enumerate()
+    .map(|(i, c)| {
+      if buttons & (0x80 >> i) != 0 {
+        *c as char
+      } else {
+        '.'
+      }
+    })
+    .collect()
+}
+
+fn decode_base64(value: &str) -> NesResult<Vec<u8>> {
+  match value.strip_prefix("base64:") {
+    Some(encoded) => Ok(base64::decode(encoded)?),
+    None => bail!("only base64 encoded binaries are supported, got {}", value),
+  }
+}
+
+fn random_guid() -> String {
+  let bytes: [u8; 16] = rand::random();
+  let hex: String = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+  format!(
+    "{}-{}-{}-{}-{}",
+    &hex[0..8],
+    &hex[8..12],
+    &hex[12..16],
+    &hex[16..20],
+    &hex[20..32]
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn fm2_test() {
+    let text = "version 3\nemuVersion 22020\nrerecordCount 2\npalFlag 0\n\
+      romFilename mario\nromChecksum base64:AAECAwQFBgcICQoLDA0ODw==\n\
+      guid 452DE2C3-EF43-2FA9-77AC-0677FC51543B\nport0 1\nport1 1\nport2 0\n\
+      comment author someone\n|1|........|........||\n|0|R..UT..A|.L....B.||\n";
+    let movie = Movie::parse_fm2(text).unwrap();
+    assert_eq!(movie.rom_filename, "mario");
+    assert_eq!(movie.rom_checksum.unwrap()[15], 15);
+    assert_eq!(movie.rerecord_count, 2);
+    assert_eq!(movie.frames.len(), 2);
+    assert_eq!(movie.frames[0].commands, COMMAND_RESET);
+    // Right, Up, Start, A
+    assert_eq!(movie.frames[1].ports[0], 0x80 | 0x10 | 0x08 | 0x01);
+    // Left, B
+    assert_eq!(movie.frames[1].ports[1], 0x40 | 0x02);
+
+    assert_eq!(Movie::parse_fm2(&movie.to_fm2()).unwrap(), movie);
+    assert!(Movie::parse_fm2("version 2\n").is_err());
+  }
+}
@@ -44,7 +44,13 @@ pub extern "C" fn android_main(argc: std::os::raw::c_int, argv: *const *const u8
   };
   // info!("{:?}", args);
   // info!("rom_path: {}\n rom_data: {}", rom_path, rom_data);
-  let instance = emulator.create_instance_from_data(mario);
+  let instance = match emulator.create_instance_from_data(mario) {
+    Ok(instance) => instance,
+    Err(e) => {
+      emulator.show_error("Failed to load ROM", &format!("{:#}", e));
+      return;
+    }
+  };
   emulator.run(instance);
   info!("emulator exit");
 }
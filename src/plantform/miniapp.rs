@@ -4,7 +4,7 @@ use wasm_bindgen::prelude::*;
 use crate::controller::key_binding_parser;
 use crate::emulator::Emulator;
 
-use super::wasm::WebNes;
+use super::wasm::{load_error, load_promise, WebNes};
 
 #[wasm_bindgen]
 impl WebNes {
@@ -17,7 +17,9 @@ impl WebNes {
     let (p1_key, p2_key) = key_binding_parser::default_key_binding();
     let emulator = Emulator::new(2.0, ".".into(), p1_key, p2_key);
 
-    let instance = emulator.create_instance_from_data(data.as_slice());
+    let instance = emulator
+      .create_instance_from_data(data.as_slice())
+      .map_err(load_error)?;
     // Create gl environment.
     Ok(WebNes {
       emulator,
@@ -26,6 +28,11 @@ impl WebNes {
     })
   }
 
+  /// Like `new`, but a ROM that can't be loaded rejects the promise.
+  pub fn load(data: js_sys::Uint8Array, audio_sample_rate: f32) -> js_sys::Promise {
+    load_promise(WebNes::new(data, audio_sample_rate))
+  }
+
   pub fn do_frame_and_pull_data(&mut self) -> Result<Uint8Array, JsValue> {
     if let Some(frame) = self.emulator.frame(&mut self.instance) {
       unsafe {
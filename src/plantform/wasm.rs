@@ -3,6 +3,7 @@ use wasm_bindgen::prelude::*;
 use crate::controller::web_key;
 use crate::emulator::Emulator;
 use crate::instance::Instance;
+use crate::NesError;
 #[cfg(not(feature = "wasm-miniapp"))]
 use crate::render::webgl::GlWrapper;
 
@@ -19,6 +20,20 @@ pub fn wasm_main() -> Result<(), JsValue> {
   Ok(())
 }
 
+/// A ROM `WebNes::new` can't load. The JS `Error` is thrown from
+/// `new WebNes(...)` and rejects the promise of `WebNes.load(...)`.
+pub(crate) fn load_error(e: NesError) -> js_sys::Error {
+  js_sys::Error::new(&format!("Failed to load ROM: {:#}", e))
+}
+
+/// Settle a promise with the result of `WebNes::new`.
+pub(crate) fn load_promise(result: Result<WebNes, JsValue>) -> js_sys::Promise {
+  match result {
+    Ok(nes) => js_sys::Promise::resolve(&nes.into()),
+    Err(e) => js_sys::Promise::reject(&e),
+  }
+}
+
 #[wasm_bindgen]
 pub struct WebNes {
   #[cfg(not(feature = "wasm-miniapp"))]
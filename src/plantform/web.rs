@@ -5,7 +5,7 @@ use crate::controller::key_binding_parser;
 use crate::emulator::Emulator;
 use crate::render::webgl::GlWrapper;
 
-use super::wasm::WebNes;
+use super::wasm::{load_error, load_promise, WebNes};
 
 
 #[wasm_bindgen]
@@ -20,7 +20,9 @@ impl WebNes {
     let (p1_key, p2_key) = key_binding_parser::default_key_binding();
     let emulator = Emulator::new(2.0, ".".into(), p1_key, p2_key);
 
-    let instance = emulator.create_instance_from_data(data.as_slice());
+    let instance = emulator
+      .create_instance_from_data(data.as_slice())
+      .map_err(load_error)?;
     // Create gl environment.
     let ctx = ele.dyn_into::<web_sys::WebGl2RenderingContext>()?;
     let gl_wrapper = GlWrapper::init_webgl(&ctx)?;
@@ -31,6 +33,12 @@ impl WebNes {
       instance,
     })
   }
+
+  /// Like `new`, but a ROM that can't be loaded rejects the promise.
+  pub fn load(data: js_sys::Uint8Array, ele: JsValue, audio_sample_rate: f32) -> js_sys::Promise {
+    load_promise(WebNes::new(data, ele, audio_sample_rate))
+  }
+
   pub fn do_frame_and_draw(&mut self) {
     let frame = self.emulator.frame(&mut self.instance);
     if frame.is_some() {
@@ -13,7 +13,7 @@ use crate::bus::main_bus::{
 };
 use crate::bus::message_bus::Message;
 use crate::bus::picture_bus::PictureBus;
-use crate::common::*;
+use crate::common::{region::Region, *};
 use crate::cpu::InterruptType;
 use crate::mapper::Mapper;
 
@@ -35,7 +35,6 @@ pub const SCANLINE_END_CYCLE_LENGTH: u32 = 341;
 const SCANLINE_END_CYCLE: usize = 340;
 pub const VISIBLE_SCANLINES: usize = 240;
 pub const SCANLINE_VISIBLE_DOTS: usize = 256;
-const FRAME_END_SCANLINE: usize = 261;
 
 // const ATTRIBUTE_OFFSET: u32 = 0x3C0;
 
@@ -47,8 +46,13 @@ pub struct Ppu {
   scanline_sprites: Vec<Byte>,
 
   pipeline_state: PipelineState,
+  #[serde(default)]
+  region: Region,
 
   cycle: usize,
+  // PPU cycles since power on, timestamps the fetches reported to the mapper
+  #[serde(default)]
+  dot_count: u64,
   scanline: usize,
   event_frame: bool,
 
@@ -85,15 +89,17 @@ pub struct Ppu {
 }
 
 impl Ppu {
-  pub fn new(message_sx: mpsc::Sender<Message>) -> Self {
+  pub fn new(message_sx: mpsc::Sender<Message>, region: Region) -> Self {
     Self {
       bus: PictureBus::new(),
       sprite_memory: vec![0; 64 * 4],
       scanline_sprites: vec![],
 
       pipeline_state: PipelineState::PreRender,
+      region,
 
       cycle: 0,
+      dot_count: 0,
       scanline: 0,
       event_frame: false,
       vblank: false,
@@ -126,6 +132,10 @@ impl Ppu {
     }
   }
 
+  pub fn region(&self) -> Region {
+    self.region
+  }
+
   pub fn set_message_bus(&mut self, message_sx: mpsc::Sender<Message>) {
     self.message_sx = Some(message_sx);
   }
@@ -163,6 +173,9 @@ impl Ppu {
   }
 
   pub fn step(&mut self) {
+    if self.bus.watch_fetches() && (self.show_background || self.show_sprites) {
+      self.report_fetch();
+    }
     match self.pipeline_state {
       PipelineState::PreRender => self.pre_render(),
       PipelineState::Render => self.render(),
@@ -170,6 +183,59 @@ impl Ppu {
       PipelineState::VerticalBlank => self.vertical_blank(),
     }
     self.cycle += 1;
+    self.dot_count += 1;
+  }
+
+  /**
+   * Tell the mapper about the fetch the 2C02 starts at this dot. Pixels are
+   * rendered from direct reads, so this only models the address bus: each
+   * 8 dot group fetches a nametable byte at its first dot and a pattern at
+   * its fifth, background tiles at 1-256 and 321-336, sprites of the next
+   * line at 257-320 and two more nametable bytes at 337 and 339.
+   */
+  fn report_fetch(&self) {
+    let rendering = match self.pipeline_state {
+      PipelineState::PreRender => true,
+      PipelineState::Render => self.scanline < VISIBLE_SCANLINES,
+      _ => false,
+    };
+    if !rendering || self.cycle == 0 {
+      return;
+    }
+    let name_table = 0x2000 | (self.data_address & 0x0FFF);
+    let addr = match (self.cycle, (self.cycle - 1) % 8) {
+      (1..=336, 0) | (337, _) | (339, _) => name_table,
+      (257..=320, 4) => self.sprite_fetch_address((self.cycle - 257) / 8),
+      (1..=256, 4) | (321..=336, 4) => {
+        let tile = self.bus.fetch(name_table) as Address;
+        let fine_y = (self.data_address >> 12) & 0x7;
+        ((self.background_page as Address) << 12) | (tile * 16) | fine_y
+      }
+      _ => return,
+    };
+    self.bus.notify_fetch(addr, self.dot_count);
+  }
+
+  // Pattern address of sprite `slot` on the next line, unused slots fetch
+  // tile $FF.
+  fn sprite_fetch_address(&self, slot: usize) -> Address {
+    let (tile, y_offset) = match self.scanline_sprites.get(slot) {
+      Some(&i) if matches!(self.pipeline_state, PipelineState::Render) => {
+        let idx = i as usize * 4;
+        let length = if self.long_sprites { 16 } else { 8 };
+        let mut y_offset = (self.scanline as i32 - self.sprite_memory[idx] as i32) % length;
+        if bit_eq(self.sprite_memory[idx + 2], 0x80) {
+          y_offset ^= length - 1;
+        }
+        (self.sprite_memory[idx + 1] as Address, y_offset as Address)
+      }
+      _ => (0xFF, 0),
+    };
+    if self.long_sprites {
+      ((tile & 1) << 12) | ((tile >> 1) * 32) | (y_offset & 7) | ((y_offset & 8) << 1)
+    } else {
+      ((self.sprite_page as Address) << 12) | (tile * 16) | y_offset
+    }
   }
 
   fn pre_render(&mut self) {
@@ -195,16 +261,13 @@ impl Ppu {
         return;
       }
     }
-    // If rendering is on, every other frame is one cycle shorter
-    if self.cycle >= SCANLINE_END_CYCLE - (!self.event_frame && enable_render) as usize {
+    // If rendering is on, every other frame is one cycle shorter (NTSC only)
+    let skip_dot = !self.event_frame && enable_render && self.region.skip_odd_frame_dot();
+    if self.cycle >= SCANLINE_END_CYCLE - skip_dot as usize {
       self.pipeline_state = PipelineState::Render;
       self.cycle = 0;
       self.scanline = 0;
     }
-
-    if self.cycle == 260 && self.show_background && self.show_sprites {
-      self.bus.scanline_irq();
-    }
   }
 
   fn render(&mut self) {
@@ -214,6 +277,7 @@ impl Ppu {
       if self.show_background {
         self.render_step2();
       }
+      self.evaluate_sprites();
     } else if self.cycle == SCANLINE_VISIBLE_DOTS + 2 {
       if self.show_background && self.show_sprites {
         // Copy bits related to horizontal position
@@ -222,29 +286,7 @@ impl Ppu {
       }
     }
 
-    if self.cycle == 260 && self.show_background && self.show_sprites {
-      self.bus.scanline_irq();
-    }
-
     if self.cycle >= SCANLINE_END_CYCLE {
-      // Find and index sprites that are on the next Scanline
-      // This isn't where/when this indexing, actually copying in 2C02 is done
-      // but it shouldn't hurt any games if this is done here
-      self.scanline_sprites.resize(0, 0);
-      let range = if self.long_sprites { 16 } else { 8 };
-
-      for i in self.sprite_data_address / 4..64 {
-        let diff = self
-          .scanline
-          .overflowing_sub(self.sprite_memory[i * 4] as usize);
-        if !diff.1 && diff.0 < range {
-          self.scanline_sprites.push(i as Byte);
-          if self.scanline_sprites.len() >= 8 {
-            // 0..7
-            break;
-          }
-        }
-      }
       self.scanline += 1;
       self.cycle = 0;
     }
@@ -254,6 +296,27 @@ impl Ppu {
     }
   }
 
+  fn evaluate_sprites(&mut self) {
+    // Find and index sprites that are on the next Scanline
+    // The 2C02 does it over dots 65-256, doing it at once before the sprite
+    // fetches of dots 257-320 shouldn't hurt any games
+    self.scanline_sprites.clear();
+    let range = if self.long_sprites { 16 } else { 8 };
+
+    for i in self.sprite_data_address / 4..64 {
+      let diff = self
+        .scanline
+        .overflowing_sub(self.sprite_memory[i * 4] as usize);
+      if !diff.1 && diff.0 < range {
+        self.scanline_sprites.push(i as Byte);
+        if self.scanline_sprites.len() >= 8 {
+          // 0..7
+          break;
+        }
+      }
+    }
+  }
+
   fn render_step1(&mut self) {
     let mut bg_color = 0;
     let mut spr_color = 0;
@@ -271,7 +334,7 @@ impl Ppu {
         // Fetch tile
         // Mask off fine y
         let mut addr = 0x2000 | (self.data_address & 0x0FFF);
-        let tile = bus.read(addr) as Address;
+        let tile = bus.fetch(addr) as Address;
 
         // Fetch pattern
         // Each pattern occupies 16 bytes, so multiply by 16
@@ -291,7 +354,7 @@ impl Ppu {
           | (self.data_address & 0x0C00)
           | ((self.data_address >> 4) & 0x38)
           | ((self.data_address >> 2) & 0x07);
-        let attribute = bus.read(addr);
+        let attribute = bus.fetch(addr);
         let shift = (self.data_address >> 4) & 4 | (self.data_address & 2);
         // Extract and set the upper two bits for the color
         bg_color |= ((attribute >> shift) & 0x3) << 2;
@@ -354,7 +417,7 @@ impl Ppu {
           addr |= ((tile & 1) as Address) << 12;
         }
 
-        spr_color |= bus.batch_read(addr, addr + 8, x_shift);
+        spr_color |= bus.batch_read_sprite(addr, addr + 8, x_shift);
 
         spr_opaque = spr_color != 0;
         if !spr_opaque {
@@ -432,7 +495,7 @@ impl Ppu {
   }
 
   fn vertical_blank(&mut self) {
-    if self.cycle == 1 && self.scanline == (VISIBLE_SCANLINES + 1) {
+    if self.cycle == 1 && self.scanline == self.region.vblank_scanline() {
       self.vblank = true;
       if self.generate_interrupt {
         if let Err(e) = self
@@ -452,7 +515,7 @@ impl Ppu {
       self.cycle = 0;
     }
 
-    if self.scanline >= FRAME_END_SCANLINE {
+    if self.scanline >= self.region.frame_end_scanline() {
       self.pipeline_state = PipelineState::PreRender;
       self.scanline = 0;
       self.event_frame = !self.event_frame;
@@ -469,6 +532,9 @@ impl Ppu {
 
   #[inline]
   pub fn get_data(&mut self) -> Byte {
+    if self.bus.watch_fetches() {
+      self.bus.notify_fetch(self.data_address, self.dot_count);
+    }
     let mut data = self.bus.read(self.data_address);
     self.data_address += self.data_address_increment;
     // Reads are delayed by one byte/read when address is in the range
@@ -495,6 +561,9 @@ impl Ppu {
       // Unset the lower byte
       self.temp_address = (self.temp_address & 0xFF00) | addr;
       self.data_address = self.temp_address;
+      if self.bus.watch_fetches() {
+        self.bus.notify_fetch(self.data_address, self.dot_count);
+      }
     }
     self.first_write = !self.first_write;
   }
@@ -513,6 +582,9 @@ impl Ppu {
   }
 
   pub fn set_data(&mut self, value: Byte) {
+    if self.bus.watch_fetches() {
+      self.bus.notify_fetch(self.data_address, self.dot_count);
+    }
     self.bus.write(self.data_address, value);
     self.data_address += self.data_address_increment;
   }
@@ -623,3 +695,56 @@ impl RegisterHandler for Ppu {
     true
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::cartridge::Cartridge;
+  use crate::mapper::tx_rom::TxRom;
+
+  // MMC3 IRQs over one frame with a latch of 0, so every A12 clock fires.
+  fn mmc3_irqs(ctrl: Byte, mask: Byte) -> usize {
+    let mut rom = b"NES\x1A\x02\x01\x40\x00\x00\x00\x00\x00\x00\x00\x00\x00".to_vec();
+    rom.resize(16 + 0x8000 + 0x2000, 0);
+    let mut cart = Cartridge::new();
+    cart.load_from_data(&rom).unwrap();
+    let mapper = Rc::new(RefCell::new(TxRom::new(cart, Box::new(|_| {}))));
+    mapper.borrow_mut().write_prg(0xC000, 0);
+    mapper.borrow_mut().write_prg(0xE001, 0);
+
+    let (message_sx, _message_rx) = mpsc::channel();
+    let mut ppu = Ppu::new(message_sx, Region::Ntsc);
+    ppu.set_mapper_for_bus(mapper.clone());
+    // no sprites on screen
+    for _ in 0..0x100 {
+      ppu.set_oam_data(0xFF);
+    }
+    ppu.control(ctrl);
+    ppu.set_mask(mask);
+    // a line takes SCANLINE_END_CYCLE steps
+    let mut irqs = 0;
+    for _ in 0..SCANLINE_END_CYCLE * 262 {
+      ppu.step();
+      let mut mapper = mapper.borrow_mut();
+      if mapper.irq_pending() {
+        irqs += 1;
+        mapper.write_prg(0xE000, 0);
+        mapper.write_prg(0xE001, 0);
+      }
+    }
+    irqs
+  }
+
+  #[test]
+  fn a12_irq_test() {
+    // pre-render line and 240 visible lines
+    assert_eq!(mmc3_irqs(0x08, 0x18), 241);
+    assert_eq!(mmc3_irqs(0x10, 0x18), 241);
+    // one layer is enough, 8x16 sprites fetch tile $FF from $1000
+    assert_eq!(mmc3_irqs(0x08, 0x08), 241);
+    assert_eq!(mmc3_irqs(0x20, 0x10), 241);
+    // no rendering, no fetches
+    assert_eq!(mmc3_irqs(0x08, 0x00), 0);
+    assert_eq!(mmc3_irqs(0x00, 0x18), 0);
+  }
+}
@@ -0,0 +1,199 @@
+use std::collections::VecDeque;
+
+/// Snapshots are taken every `interval` frames, at most `capacity` are kept.
+pub const DEFAULT_REWIND_INTERVAL: u32 = 2;
+pub const DEFAULT_REWIND_CAPACITY: usize = 600;
+
+/**
+ * Ring buffer of savestates for rewinding.
+ *
+ * Only the newest snapshot is kept in full, every older one is stored as a
+ * delta against the snapshot taken after it. Rewinding walks the chain
+ * backwards and dropping the oldest snapshot never breaks it.
+ */
+pub struct Rewind {
+  interval: u32,
+  capacity: usize,
+  frames: u32,
+  latest: Option<Vec<u8>>,
+  // deltas from the next newer snapshot back to this one, oldest first
+  deltas: VecDeque<Vec<u8>>,
+}
+
+impl Default for Rewind {
+  fn default() -> Self {
+    Self::new(DEFAULT_REWIND_INTERVAL, DEFAULT_REWIND_CAPACITY)
+  }
+}
+
+impl Rewind {
+  pub fn new(interval: u32, capacity: usize) -> Self {
+    Self {
+      interval: interval.max(1),
+      capacity,
+      frames: 0,
+      latest: None,
+      deltas: VecDeque::new(),
+    }
+  }
+
+  pub fn is_enabled(&self) -> bool {
+    self.capacity > 0
+  }
+
+  pub fn len(&self) -> usize {
+    self.latest.is_some() as usize + self.deltas.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.latest.is_none()
+  }
+
+  pub fn clear(&mut self) {
+    self.frames = 0;
+    self.latest = None;
+    self.deltas.clear();
+  }
+
+  /// Count a frame, true when a snapshot is due.
+  pub fn tick(&mut self) -> bool {
+    if !self.is_enabled() {
+      return false;
+    }
+    self.frames += 1;
+    if self.frames >= self.interval {
+      self.frames = 0;
+      true
+    } else {
+      false
+    }
+  }
+
+  pub fn push(&mut self, snapshot: Vec<u8>) {
+    if !self.is_enabled() {
+      return;
+    }
+    if let Some(latest) = self.latest.take() {
+      self.deltas.push_back(encode_delta(&snapshot, &latest));
+    }
+    self.latest = Some(snapshot);
+    while self.len() > self.capacity {
+      self.deltas.pop_front();
+    }
+  }
+
+  /// Take the newest snapshot out of the buffer.
+  pub fn pop(&mut self) -> Option<Vec<u8>> {
+    let latest = self.latest.take()?;
+    self.latest = self.deltas.pop_back().map(|delta| apply_delta(&latest, &delta));
+    self.frames = 0;
+    Some(latest)
+  }
+}
+
+/**
+ * XOR `new` against `base` and run-length encode the zero runs.
+ *
+ * Layout: `len` then pairs of `zero run`, `literal count`, literal bytes, all
+ * numbers are LEB128 varints.
+ */
+pub(crate) fn encode_delta(base: &[u8], new: &[u8]) -> Vec<u8> {
+  let xor = |i: usize| new[i] ^ base.get(i).copied().unwrap_or(0);
+  let mut out = vec![];
+  write_varint(&mut out, new.len());
+  let mut i = 0;
+  while i < new.len() {
+    let start = i;
+    while i < new.len() && xor(i) == 0 {
+      i += 1;
+    }
+    let zeros = i - start;
+    let start = i;
+    // a literal ends at the first run of 3 zeros, shorter runs are cheaper inline
+    while i < new.len() && !(i + 2 < new.len() && xor(i) == 0 && xor(i + 1) == 0 && xor(i + 2) == 0)
+    {
+      i += 1;
+    }
+    write_varint(&mut out, zeros);
+    write_varint(&mut out, i - start);
+    out.extend((start..i).map(xor));
+  }
+  out
+}
+
+pub(crate) fn apply_delta(base: &[u8], delta: &[u8]) -> Vec<u8> {
+  let mut pos = 0;
+  let len = read_varint(delta, &mut pos);
+  let mut out: Vec<u8> = (0..len).map(|i| base.get(i).copied().unwrap_or(0)).collect();
+  let mut i = 0;
+  while pos < delta.len() {
+    i += read_varint(delta, &mut pos);
+    let literals = read_varint(delta, &mut pos);
+    for b in &delta[pos..pos + literals] {
+      out[i] ^= b;
+      i += 1;
+    }
+    pos += literals;
+  }
+  out
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: usize) {
+  loop {
+    let byte = (value & 0x7F) as u8;
+    value >>= 7;
+    if value == 0 {
+      out.push(byte);
+      return;
+    }
+    out.push(byte | 0x80);
+  }
+}
+
+fn read_varint(data: &[u8], pos: &mut usize) -> usize {
+  let mut value = 0;
+  let mut shift = 0;
+  loop {
+    let byte = data[*pos];
+    *pos += 1;
+    value |= ((byte & 0x7F) as usize) << shift;
+    if byte & 0x80 == 0 {
+      return value;
+    }
+    shift += 7;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn delta_test() {
+    let base: Vec<u8> = (0..1000).map(|i| (i % 251) as u8).collect();
+    let mut new = base.clone();
+    new[10] = 0;
+    new[500] ^= 0xFF;
+    new.extend_from_slice(&[1, 2, 3]);
+    let delta = encode_delta(&base, &new);
+    assert!(delta.len() < 32);
+    assert_eq!(apply_delta(&base, &delta), new);
+    // shrinking
+    assert_eq!(apply_delta(&new, &encode_delta(&new, &base)), base);
+  }
+
+  #[test]
+  fn ring_test() {
+    let mut rewind = Rewind::new(1, 3);
+    for i in 0..5u8 {
+      assert!(rewind.tick());
+      rewind.push(vec![i; 16]);
+    }
+    assert_eq!(rewind.len(), 3);
+    assert_eq!(rewind.pop(), Some(vec![4; 16]));
+    assert_eq!(rewind.pop(), Some(vec![3; 16]));
+    assert_eq!(rewind.pop(), Some(vec![2; 16]));
+    assert_eq!(rewind.pop(), None);
+    assert!(rewind.is_empty());
+  }
+}
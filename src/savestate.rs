@@ -0,0 +1,230 @@
+//! Savestate container
+//!
+//! ```text
+//! magic "NESS" | section* ; section = tag (4 bytes) | length (u32 LE) | payload
+//! ```
+//!
+//! The first section is always `HEAD`, a CBOR encoded [`SaveStateHeader`].
+//! Unknown sections are skipped so newer versions can append data.
+use std::convert::TryFrom;
+
+use anyhow::{anyhow, bail};
+use ciborium::{de::from_reader, ser::into_writer};
+use image::{codecs::png::PngEncoder, imageops, ColorType};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{common::region::Region, instance::FrameBuffer, NesResult};
+
+const MAGIC: &[u8; 4] = b"NESS";
+pub const SAVE_STATE_VERSION: u16 = 2;
+
+pub(crate) type SectionTag = [u8; 4];
+pub(crate) const HEAD: SectionTag = *b"HEAD";
+pub(crate) const CPU: SectionTag = *b"CPU ";
+pub(crate) const APU: SectionTag = *b"APU ";
+pub(crate) const PPU: SectionTag = *b"PPU ";
+pub(crate) const BUS: SectionTag = *b"BUS ";
+pub(crate) const THUMBNAIL: SectionTag = *b"THMB";
+
+/// Thumbnails are the framebuffer downscaled by 2.
+const THUMBNAIL_WIDTH: u32 = 128;
+const THUMBNAIL_HEIGHT: u32 = 120;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SaveStateHeader {
+  pub version: u16,
+  /// SHA-1 of PRG-ROM followed by CHR-ROM.
+  pub rom_sha1: [u8; 20],
+  pub region: Region,
+  /// Seconds since the unix epoch.
+  pub timestamp: u64,
+}
+
+impl SaveStateHeader {
+  pub fn new(rom_sha1: [u8; 20], region: Region) -> Self {
+    Self {
+      version: SAVE_STATE_VERSION,
+      rom_sha1,
+      region,
+      timestamp: now_timestamp(),
+    }
+  }
+}
+
+fn now_timestamp() -> u64 {
+  #[cfg(target_arch = "wasm32")]
+  return (js_sys::Date::now() / 1000.0) as u64;
+
+  #[cfg(not(target_arch = "wasm32"))]
+  return std::time::SystemTime::now()
+    .duration_since(std::time::UNIX_EPOCH)
+    .map(|d| d.as_secs())
+    .unwrap_or(0);
+}
+
+/// What a slot picker needs to show a savestate without loading it.
+pub struct SaveStatePreview {
+  pub header: SaveStateHeader,
+  /// PNG encoded thumbnail of the screen when the state was saved.
+  pub thumbnail: Option<Vec<u8>>,
+}
+
+pub(crate) fn encode_thumbnail(frame: &FrameBuffer) -> NesResult<Vec<u8>> {
+  let small = imageops::resize(
+    frame,
+    THUMBNAIL_WIDTH,
+    THUMBNAIL_HEIGHT,
+    imageops::FilterType::Triangle,
+  );
+  let mut png = vec![];
+  PngEncoder::new(&mut png).encode(
+    &small,
+    THUMBNAIL_WIDTH,
+    THUMBNAIL_HEIGHT,
+    ColorType::Rgba8,
+  )?;
+  Ok(png)
+}
+
+pub(crate) struct SaveStateWriter {
+  buf: Vec<u8>,
+}
+
+impl SaveStateWriter {
+  pub fn new(header: &SaveStateHeader) -> NesResult<Self> {
+    let mut writer = Self {
+      buf: MAGIC.to_vec(),
+    };
+    writer.cbor_section(HEAD, header)?;
+    Ok(writer)
+  }
+
+  pub fn section(&mut self, tag: SectionTag, data: &[u8]) -> NesResult<()> {
+    let len = u32::try_from(data.len()).map_err(|_| anyhow!("section too large"))?;
+    self.buf.extend_from_slice(&tag);
+    self.buf.extend_from_slice(&len.to_le_bytes());
+    self.buf.extend_from_slice(data);
+    Ok(())
+  }
+
+  pub fn cbor_section<T: Serialize>(&mut self, tag: SectionTag, value: &T) -> NesResult<()> {
+    let mut data = vec![];
+    into_writer(value, &mut data)?;
+    self.section(tag, &data)
+  }
+
+  pub fn finish(self) -> Vec<u8> {
+    self.buf
+  }
+}
+
+pub(crate) struct SaveStateReader<'a> {
+  pub header: SaveStateHeader,
+  sections: Vec<(SectionTag, &'a [u8])>,
+}
+
+impl<'a> SaveStateReader<'a> {
+  pub fn parse(data: &'a [u8]) -> NesResult<Self> {
+    if data.len() < MAGIC.len() || &data[..MAGIC.len()] != MAGIC {
+      bail!("not a savestate, bad magic number");
+    }
+    let mut sections = vec![];
+    let mut rest = &data[MAGIC.len()..];
+    while !rest.is_empty() {
+      if rest.len() < 8 {
+        bail!("truncated savestate section header");
+      }
+      let mut tag = [0; 4];
+      tag.copy_from_slice(&rest[..4]);
+      let len = u32::from_le_bytes([rest[4], rest[5], rest[6], rest[7]]) as usize;
+      rest = &rest[8..];
+      if rest.len() < len {
+        bail!(
+          "truncated savestate section {}, expect {} bytes but {} left",
+          String::from_utf8_lossy(&tag),
+          len,
+          rest.len()
+        );
+      }
+      sections.push((tag, &rest[..len]));
+      rest = &rest[len..];
+    }
+    match sections.first() {
+      Some((tag, _)) if *tag == HEAD => {}
+      _ => bail!("savestate header is missing"),
+    }
+    let header: SaveStateHeader = from_reader(sections[0].1)
+      .map_err(|e| anyhow!("invalid savestate header: {}", e))?;
+    if header.version > SAVE_STATE_VERSION {
+      bail!(
+        "savestate version {} is newer than supported version {}",
+        header.version,
+        SAVE_STATE_VERSION
+      );
+    }
+    Ok(Self { header, sections })
+  }
+
+  pub fn section(&self, tag: SectionTag) -> NesResult<&'a [u8]> {
+    self
+      .sections
+      .iter()
+      .find(|(t, _)| *t == tag)
+      .map(|(_, data)| *data)
+      .ok_or_else(|| anyhow!("savestate section {} is missing", String::from_utf8_lossy(&tag)))
+  }
+
+  pub fn cbor_section<T: DeserializeOwned>(&self, tag: SectionTag) -> NesResult<T> {
+    from_reader(self.section(tag)?).map_err(|e| {
+      anyhow!(
+        "invalid savestate section {}: {}",
+        String::from_utf8_lossy(&tag),
+        e
+      )
+    })
+  }
+}
+
+/// Read only the header, e.g. to list savestates without loading them.
+pub fn read_header(data: &[u8]) -> NesResult<SaveStateHeader> {
+  Ok(SaveStateReader::parse(data)?.header)
+}
+
+pub fn read_preview(data: &[u8]) -> NesResult<SaveStatePreview> {
+  let reader = SaveStateReader::parse(data)?;
+  let thumbnail = reader.section(THUMBNAIL).ok().map(|png| png.to_vec());
+  Ok(SaveStatePreview {
+    header: reader.header,
+    thumbnail,
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn container_test() {
+    let header = SaveStateHeader::new([7; 20], Region::Pal);
+    let mut writer = SaveStateWriter::new(&header).unwrap();
+    writer.section(BUS, &[1, 2, 3]).unwrap();
+    let data = writer.finish();
+
+    let reader = SaveStateReader::parse(&data).unwrap();
+    assert_eq!(reader.header, header);
+    assert_eq!(reader.section(BUS).unwrap(), &[1, 2, 3]);
+    assert!(reader.section(CPU).is_err());
+
+    assert!(SaveStateReader::parse(&data[..data.len() - 1]).is_err());
+    assert!(SaveStateReader::parse(b"CBOR").is_err());
+  }
+
+  #[test]
+  fn thumbnail_test() {
+    let frame = FrameBuffer::from_pixel(256, 240, image::Rgba([255, 0, 0, 255]));
+    let png = encode_thumbnail(&frame).unwrap();
+    let thumbnail = image::load_from_memory(&png).unwrap().to_rgba8();
+    assert_eq!(thumbnail.dimensions(), (THUMBNAIL_WIDTH, THUMBNAIL_HEIGHT));
+    assert_eq!(thumbnail.get_pixel(10, 10), &image::Rgba([255, 0, 0, 255]));
+  }
+}